@@ -0,0 +1,839 @@
+//! A small Intel-syntax x86_64 assembler for writing patches, on top of iced-x86's encoder.
+//!
+//! Instead of a hand-written instruction table, operands are matched against iced's opcode tables,
+//! so any legacy or VEX encoded instruction works as long as its operands are registers, memory,
+//! immediates or branch targets. Where several encodings fit, the shortest one wins.
+
+use std::{collections::HashMap, sync::OnceLock};
+
+use anyhow::{anyhow, bail, Result};
+use iced_x86::{
+    BlockEncoder, BlockEncoderOptions, Code, Encoder, EncodingKind, Instruction, InstructionBlock, MemorySize,
+    OpCodeOperandKind, OpKind, Register, RepPrefixKind,
+};
+
+/// Label addresses depend on instruction lengths, which can depend on label addresses again
+/// (`mov rax, label`), so encoding repeats until the layout stops changing.
+const MAX_PASSES: usize = 8;
+
+/// Spacing of the made-up addresses of the first pass, far enough apart to never collide
+const FIRST_PASS_SPACING: u64 = 0x1000;
+
+/// Integer constants plus or minus label and symbol names
+#[derive(Debug, Clone, Default)]
+struct Expression {
+    constant: i64,
+    /// Names, and whether they are subtracted
+    names: Vec<(String, bool)>,
+}
+
+#[derive(Debug)]
+struct Memory {
+    /// In bytes, from `byte ptr`, `qword ptr`, ...
+    size: Option<usize>,
+    segment: Register,
+    base: Register,
+    index: Register,
+    scale: u32,
+    displacement: Expression,
+}
+
+#[derive(Debug)]
+enum Operand {
+    Register(Register),
+    Memory(Memory),
+    Value(Expression),
+}
+
+#[derive(Debug)]
+enum Statement {
+    Instruction {
+        lock: bool,
+        rep: RepPrefixKind,
+        mnemonic: String,
+        operands: Vec<Operand>,
+    },
+    /// `db`, `dw`, `dd` and `dq`
+    Data { width: usize, values: Vec<Expression> },
+}
+
+/// An operand with all names resolved, for one encoding pass
+enum Resolved {
+    Register(Register),
+    Memory {
+        size: Option<usize>,
+        segment: Register,
+        base: Register,
+        index: Register,
+        scale: u32,
+        displacement: u64,
+    },
+    Value(i64),
+}
+
+fn registers() -> &'static HashMap<String, Register> {
+    static REGISTERS: OnceLock<HashMap<String, Register>> = OnceLock::new();
+    REGISTERS.get_or_init(|| {
+        Register::values()
+            .filter(|register| *register != Register::None)
+            .map(|register| (format!("{:?}", register).to_lowercase(), register))
+            .collect()
+    })
+}
+
+fn register(name: &str) -> Option<Register> {
+    // x87 registers are usually written st(1)
+    let name = name.trim().to_lowercase().replace(['(', ')'], "");
+    registers().get(&name).copied()
+}
+
+/// Encodings usable in 64-bit mode, by mnemonic
+fn codes(mnemonic: &str) -> Option<&'static [Code]> {
+    static CODES: OnceLock<HashMap<String, Vec<Code>>> = OnceLock::new();
+    CODES
+        .get_or_init(|| {
+            let mut codes = HashMap::<String, Vec<Code>>::new();
+            for code in Code::values() {
+                let op_code = code.op_code();
+                if !op_code.is_instruction()
+                    || !op_code.mode64()
+                    || !matches!(op_code.encoding(), EncodingKind::Legacy | EncodingKind::VEX)
+                {
+                    continue;
+                }
+                // Far pointers in memory are all but unused, and would make `jmp [rax]` ambiguous
+                if matches!(
+                    op_code.memory_size(),
+                    MemorySize::SegPtr16 | MemorySize::SegPtr32 | MemorySize::SegPtr64
+                ) {
+                    continue;
+                }
+                codes.entry(format!("{:?}", code.mnemonic()).to_lowercase()).or_default().push(code);
+            }
+            codes
+        })
+        .get(mnemonic)
+        .map(Vec::as_slice)
+}
+
+/// Map alternative spellings (`jz`, `cmovnae`, `sal`, ...) to the names iced uses
+fn canonical_mnemonic(mnemonic: &str) -> String {
+    const CONDITIONS: &[(&str, &str)] = &[
+        ("z", "e"),
+        ("nz", "ne"),
+        ("c", "b"),
+        ("nae", "b"),
+        ("nc", "ae"),
+        ("nb", "ae"),
+        ("na", "be"),
+        ("nbe", "a"),
+        ("pe", "p"),
+        ("po", "np"),
+        ("nge", "l"),
+        ("nl", "ge"),
+        ("ng", "le"),
+        ("nle", "g"),
+    ];
+    let mnemonic = mnemonic.to_lowercase();
+    for prefix in ["j", "cmov", "set"] {
+        if let Some(condition) = mnemonic.strip_prefix(prefix) {
+            if let Some((_, canonical)) = CONDITIONS.iter().find(|(alias, _)| *alias == condition) {
+                return format!("{}{}", prefix, canonical);
+            }
+        }
+    }
+    match mnemonic.as_str() {
+        "sal" => "shl".into(),
+        "movabs" => "mov".into(),
+        _ => mnemonic,
+    }
+}
+
+/// String instructions without operands, which iced has dedicated constructors for
+fn string_instruction(mnemonic: &str, rep: RepPrefixKind) -> Option<Instruction> {
+    let seg = Register::None;
+    let instruction = match mnemonic {
+        "movsb" => Instruction::with_movsb(64, seg, rep),
+        "movsw" => Instruction::with_movsw(64, seg, rep),
+        "movsd" => Instruction::with_movsd(64, seg, rep),
+        "movsq" => Instruction::with_movsq(64, seg, rep),
+        "cmpsb" => Instruction::with_cmpsb(64, seg, rep),
+        "cmpsw" => Instruction::with_cmpsw(64, seg, rep),
+        "cmpsd" => Instruction::with_cmpsd(64, seg, rep),
+        "cmpsq" => Instruction::with_cmpsq(64, seg, rep),
+        "lodsb" => Instruction::with_lodsb(64, seg, rep),
+        "lodsw" => Instruction::with_lodsw(64, seg, rep),
+        "lodsd" => Instruction::with_lodsd(64, seg, rep),
+        "lodsq" => Instruction::with_lodsq(64, seg, rep),
+        "stosb" => Instruction::with_stosb(64, rep),
+        "stosw" => Instruction::with_stosw(64, rep),
+        "stosd" => Instruction::with_stosd(64, rep),
+        "stosq" => Instruction::with_stosq(64, rep),
+        "scasb" => Instruction::with_scasb(64, rep),
+        "scasw" => Instruction::with_scasw(64, rep),
+        "scasd" => Instruction::with_scasd(64, rep),
+        "scasq" => Instruction::with_scasq(64, rep),
+        _ => return None,
+    };
+    instruction.ok()
+}
+
+fn memory_size(keyword: &str) -> Option<usize> {
+    Some(match keyword {
+        "byte" => 1,
+        "word" => 2,
+        "dword" => 4,
+        "fword" => 6,
+        "qword" | "mmword" => 8,
+        "tbyte" | "tword" => 10,
+        "xmmword" | "oword" => 16,
+        "ymmword" => 32,
+        _ => return None,
+    })
+}
+
+fn is_identifier(text: &str) -> bool {
+    !text.is_empty()
+        && !text.starts_with(|c: char| c.is_ascii_digit())
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || "_.$@!-".contains(c))
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let text = text.trim().to_lowercase().replace('_', "");
+    let value = if let Some(hex) = text.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = text.strip_prefix("0b") {
+        u64::from_str_radix(binary, 2).ok()?
+    } else {
+        text.parse::<u64>().ok()?
+    };
+    Some(value as i64)
+}
+
+/// Split `a + b - c` into terms and whether they are subtracted. Module names can contain dashes
+/// (`ld-linux-x86-64.so.2!_dl_debug_state`), so a dash before a `!` doesn't start a new term.
+fn split_terms(text: &str) -> Vec<(&str, bool)> {
+    let mut terms = Vec::new();
+    let mut start = 0;
+    let mut negative = false;
+    for (i, c) in text.char_indices() {
+        if c != '+' && c != '-' {
+            continue;
+        }
+        if c == '-' && text[i..].split('+').next().is_some_and(|rest| rest.contains('!')) {
+            continue;
+        }
+        let term = text[start..i].trim();
+        if !term.is_empty() {
+            terms.push((term, negative));
+        }
+        negative = c == '-';
+        start = i + 1;
+    }
+    let term = text[start..].trim();
+    if !term.is_empty() {
+        terms.push((term, negative));
+    }
+    terms
+}
+
+fn parse_term(expression: &mut Expression, term: &str, negative: bool) -> Result<()> {
+    if let Some(value) = parse_number(term) {
+        expression.constant = if negative {
+            expression.constant.wrapping_sub(value)
+        } else {
+            expression.constant.wrapping_add(value)
+        };
+    } else if is_identifier(term) && register(term).is_none() {
+        expression.names.push((term.to_string(), negative));
+    } else {
+        bail!("Invalid value: {}", term);
+    }
+    Ok(())
+}
+
+fn parse_expression(text: &str) -> Result<Expression> {
+    let mut expression = Expression::default();
+    let terms = split_terms(text);
+    if terms.is_empty() {
+        bail!("Missing value");
+    }
+    for (term, negative) in terms {
+        parse_term(&mut expression, term, negative)?;
+    }
+    Ok(expression)
+}
+
+/// `qword ptr fs:[rbx + rcx*8 - 0x10]`
+fn parse_memory(text: &str) -> Result<Memory> {
+    let (prefix, rest) = text.split_once('[').unwrap();
+    let inner = rest
+        .strip_suffix(']')
+        .ok_or_else(|| anyhow!("Missing ] in {}", text))?;
+    let mut memory = Memory {
+        size: None,
+        segment: Register::None,
+        base: Register::None,
+        index: Register::None,
+        scale: 1,
+        displacement: Expression::default(),
+    };
+    let mut words = prefix.replace(':', " : ");
+    // The segment may also be inside the brackets: [fs:0x28]
+    let inner = match inner.split_once(':') {
+        Some((segment, inner)) if register(segment).is_some_and(Register::is_segment_register) => {
+            words.push(' ');
+            words.push_str(segment);
+            inner
+        }
+        _ => inner,
+    };
+    for word in words.split_whitespace().map(str::to_lowercase) {
+        if let Some(size) = memory_size(&word) {
+            memory.size = Some(size);
+        } else if let Some(segment) = register(&word).filter(|register| register.is_segment_register()) {
+            memory.segment = segment;
+        } else if word != "ptr" && word != ":" && word != "rel" {
+            bail!("Unexpected {} in {}", word, text);
+        }
+    }
+    let inner = inner.trim().strip_prefix("rel ").unwrap_or(inner);
+    for (term, negative) in split_terms(inner) {
+        let scaled = term.split_once('*').map(|(a, b)| match register(a) {
+            Some(register) => (Some(register), b),
+            None => (register(b), a),
+        });
+        let (register, scale) = match scaled {
+            Some((register, scale)) => (register, Some(scale)),
+            None => (register(term), None),
+        };
+        let Some(register) = register else {
+            parse_term(&mut memory.displacement, term, negative)?;
+            continue;
+        };
+        if negative {
+            bail!("Registers can't be subtracted: {}", text);
+        }
+        match scale {
+            Some(scale) => {
+                let scale = parse_number(scale)
+                    .filter(|scale| matches!(scale, 1 | 2 | 4 | 8))
+                    .ok_or_else(|| anyhow!("Invalid scale in {}", text))?;
+                if memory.index != Register::None {
+                    bail!("Too many index registers in {}", text);
+                }
+                memory.index = register;
+                memory.scale = scale as u32;
+            }
+            None if memory.base == Register::None => memory.base = register,
+            None if memory.index == Register::None => memory.index = register,
+            None => bail!("Too many registers in {}", text),
+        }
+    }
+    Ok(memory)
+}
+
+fn parse_operand(text: &str) -> Result<Operand> {
+    if text.contains('[') {
+        Ok(Operand::Memory(parse_memory(text)?))
+    } else if let Some(register) = register(text) {
+        Ok(Operand::Register(register))
+    } else {
+        Ok(Operand::Value(parse_expression(text)?))
+    }
+}
+
+fn parse_statement(text: &str) -> Result<Statement> {
+    let mut words = text.splitn(2, char::is_whitespace);
+    let mut mnemonic = words.next().unwrap_or_default().to_lowercase();
+    let mut rest = words.next().unwrap_or_default().trim();
+    let mut lock = false;
+    let mut rep = RepPrefixKind::None;
+    loop {
+        match mnemonic.as_str() {
+            "lock" => lock = true,
+            "rep" | "repe" | "repz" => rep = RepPrefixKind::Repe,
+            "repne" | "repnz" => rep = RepPrefixKind::Repne,
+            _ => break,
+        }
+        let mut words = rest.splitn(2, char::is_whitespace);
+        mnemonic = words.next().unwrap_or_default().to_lowercase();
+        rest = words.next().unwrap_or_default().trim();
+    }
+    if mnemonic.is_empty() {
+        bail!("Missing instruction after prefix");
+    }
+    let operands = if rest.is_empty() { Vec::new() } else { rest.split(',').map(str::trim).collect() };
+    let width = match mnemonic.as_str() {
+        "db" => 1,
+        "dw" => 2,
+        "dd" => 4,
+        "dq" => 8,
+        _ => {
+            return Ok(Statement::Instruction {
+                lock,
+                rep,
+                mnemonic: canonical_mnemonic(&mnemonic),
+                operands: operands.into_iter().map(parse_operand).collect::<Result<_>>()?,
+            })
+        }
+    };
+    if operands.is_empty() {
+        bail!("{} needs at least one value", mnemonic);
+    }
+    Ok(Statement::Data {
+        width,
+        values: operands.into_iter().map(parse_expression).collect::<Result<_>>()?,
+    })
+}
+
+/// Whether an operand kind of an encoding accepts a register
+fn register_fits(register: Register, kind: OpCodeOperandKind) -> bool {
+    let kind = format!("{:?}", kind);
+    // Encodings with a fixed register, like `al` in `add al, imm8`
+    if kind == format!("{:?}", register).to_lowercase() {
+        return true;
+    }
+    let class = match register {
+        r if r.is_gpr8() => "r8",
+        r if r.is_gpr16() => "r16",
+        r if r.is_gpr32() => "r32",
+        r if r.is_gpr64() => "r64",
+        r if r.is_xmm() => "xmm",
+        r if r.is_ymm() => "ymm",
+        r if r.is_mm() => "mm",
+        r if r.is_k() => "k",
+        r if r.is_bnd() => "bnd",
+        r if r.is_tmm() => "tmm",
+        r if r.is_cr() => "cr",
+        r if r.is_dr() => "dr",
+        r if r.is_tr() => "tr",
+        r if r.is_st() => "sti",
+        r if r.is_segment_register() => "seg",
+        _ => return false,
+    };
+    kind.split_once('_').is_some_and(|(kind_class, form)| {
+        kind_class == class
+            && matches!(form, "or_mem" | "or_mem_mpx" | "reg" | "reg_mem" | "rm" | "opcode" | "vvvv" | "is4" | "is5")
+    })
+}
+
+fn memory_fits(kind: OpCodeOperandKind) -> bool {
+    let kind = format!("{:?}", kind);
+    kind == "mem" || kind.ends_with("_or_mem") || kind.ends_with("_or_mem_mpx")
+}
+
+/// The operand kind for a value, if it fits an encoding's operand
+fn value_kind(value: i64, kind: OpCodeOperandKind, second_immediate: bool) -> Option<OpKind> {
+    let fits = |min: i64, max: i64| (min..=max).contains(&value);
+    Some(match kind {
+        OpCodeOperandKind::br64_1 | OpCodeOperandKind::br64_4 | OpCodeOperandKind::xbegin_4 => OpKind::NearBranch64,
+        OpCodeOperandKind::imm8_const_1 if value == 1 => OpKind::Immediate8,
+        OpCodeOperandKind::imm8 if fits(i8::MIN.into(), u8::MAX.into()) => match second_immediate {
+            true => OpKind::Immediate8_2nd,
+            false => OpKind::Immediate8,
+        },
+        OpCodeOperandKind::imm8sex16 if fits(i8::MIN.into(), i8::MAX.into()) => OpKind::Immediate8to16,
+        OpCodeOperandKind::imm8sex32 if fits(i8::MIN.into(), i8::MAX.into()) => OpKind::Immediate8to32,
+        OpCodeOperandKind::imm8sex64 if fits(i8::MIN.into(), i8::MAX.into()) => OpKind::Immediate8to64,
+        OpCodeOperandKind::imm16 if fits(i16::MIN.into(), u16::MAX.into()) => OpKind::Immediate16,
+        OpCodeOperandKind::imm32 if fits(i32::MIN.into(), u32::MAX.into()) => OpKind::Immediate32,
+        OpCodeOperandKind::imm32sex64 if fits(i32::MIN.into(), i32::MAX.into()) => OpKind::Immediate32to64,
+        OpCodeOperandKind::imm64 => OpKind::Immediate64,
+        _ => return None,
+    })
+}
+
+/// Build an instruction with a specific encoding, if the operands fit it
+fn build(code: Code, operands: &[Resolved]) -> Option<Instruction> {
+    let op_code = code.op_code();
+    if op_code.op_count() as usize != operands.len() {
+        return None;
+    }
+    let mut instruction = Instruction::default();
+    instruction.set_code(code);
+    let mut immediates = 0;
+    for (i, (operand, &kind)) in operands.iter().zip(op_code.op_kinds()).enumerate() {
+        let i = i as u32;
+        match *operand {
+            Resolved::Register(register) => {
+                if !register_fits(register, kind) {
+                    return None;
+                }
+                instruction.try_set_op_kind(i, OpKind::Register).ok()?;
+                instruction.try_set_op_register(i, register).ok()?;
+            }
+            Resolved::Memory {
+                size,
+                segment,
+                base,
+                index,
+                scale,
+                displacement,
+            } => {
+                if !memory_fits(kind) {
+                    return None;
+                }
+                instruction.try_set_op_kind(i, OpKind::Memory).ok()?;
+                instruction.set_segment_prefix(segment);
+                instruction.set_memory_base(base);
+                instruction.set_memory_index(index);
+                instruction.set_memory_index_scale(scale);
+                instruction.set_memory_displacement64(displacement);
+                // Same as iced's own code assembler: a full-size displacement when it's the whole
+                // address, otherwise the encoder picks the smallest that fits
+                let displ_size = match base == Register::None && index == Register::None {
+                    true => 8,
+                    false => (displacement != 0) as u32,
+                };
+                instruction.set_memory_displ_size(displ_size);
+                if size.is_some_and(|size| size != instruction.memory_size().size()) {
+                    return None;
+                }
+            }
+            Resolved::Value(value) => match value_kind(value, kind, immediates > 0)? {
+                OpKind::NearBranch64 => {
+                    instruction.try_set_op_kind(i, OpKind::NearBranch64).ok()?;
+                    instruction.set_near_branch64(value as u64);
+                }
+                op_kind => {
+                    instruction.try_set_op_kind(i, op_kind).ok()?;
+                    instruction.try_set_immediate_i64(i, value).ok()?;
+                    immediates += 1;
+                }
+            },
+        }
+    }
+    Some(instruction)
+}
+
+struct Program {
+    /// Statements along with their source text, for errors
+    statements: Vec<(String, Statement)>,
+    /// Label names, and the statement they point at (or the end of the code)
+    labels: HashMap<String, usize>,
+}
+
+impl Program {
+    fn parse(source: &str) -> Result<Self> {
+        let mut program = Self {
+            statements: Vec::new(),
+            labels: HashMap::new(),
+        };
+        for line in source.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            for text in line.split(';') {
+                let mut text = text.trim();
+                while let Some((label, rest)) = text.split_once(':').filter(|(label, _)| is_identifier(label.trim())) {
+                    if program.labels.insert(label.trim().to_string(), program.statements.len()).is_some() {
+                        bail!("Label {} is defined twice", label.trim());
+                    }
+                    text = rest.trim();
+                }
+                if text.is_empty() {
+                    continue;
+                }
+                let statement = parse_statement(text).map_err(|e| anyhow!("{}: {}", text, e))?;
+                program.statements.push((text.to_string(), statement));
+            }
+        }
+        Ok(program)
+    }
+
+    fn names(&self) -> impl Iterator<Item = &str> {
+        self.statements
+            .iter()
+            .flat_map(|(_, statement)| match statement {
+                Statement::Instruction { operands, .. } => operands
+                    .iter()
+                    .filter_map(|operand| match operand {
+                        Operand::Memory(memory) => Some(&memory.displacement),
+                        Operand::Value(value) => Some(value),
+                        Operand::Register(_) => None,
+                    })
+                    .collect::<Vec<_>>(),
+                Statement::Data { values, .. } => values.iter().collect(),
+            })
+            .flat_map(|expression| expression.names.iter().map(|(name, _)| name.as_str()))
+    }
+}
+
+/// Where everything is during one encoding pass
+struct Layout<'a> {
+    program: &'a Program,
+    /// Address of every statement, followed by the end of the code
+    addresses: &'a [u64],
+    symbols: &'a HashMap<String, u64>,
+}
+
+impl Layout<'_> {
+    fn evaluate(&self, expression: &Expression) -> i64 {
+        expression.names.iter().fold(expression.constant, |value, (name, negative)| {
+            let address = match self.program.labels.get(name) {
+                Some(&statement) => self.addresses[statement],
+                None => self.symbols[name],
+            } as i64;
+            match negative {
+                true => value.wrapping_sub(address),
+                false => value.wrapping_add(address),
+            }
+        })
+    }
+
+    fn resolve(&self, statement: usize, operand: &Operand) -> Resolved {
+        let memory = match operand {
+            Operand::Register(register) => return Resolved::Register(*register),
+            Operand::Value(value) => return Resolved::Value(self.evaluate(value)),
+            Operand::Memory(memory) => memory,
+        };
+        let displacement = self.evaluate(&memory.displacement) as u64;
+        let (base, displacement) = match memory.base {
+            // Numbers are relative to the next instruction, the way disassemblers show them, while
+            // labels and symbols are the address itself: `[rip + 0x2ed0]`, `[rip + counter]`
+            Register::RIP if memory.displacement.names.is_empty() => {
+                (Register::RIP, self.addresses[statement + 1].wrapping_add(displacement))
+            }
+            Register::RIP => (Register::RIP, displacement),
+            // Plain addresses are RIP-relative, except for thread-local ones like `fs:[0x28]`
+            Register::None
+                if memory.index == Register::None && !matches!(memory.segment, Register::FS | Register::GS) =>
+            {
+                (Register::RIP, displacement)
+            }
+            base => (base, displacement),
+        };
+        Resolved::Memory {
+            size: memory.size,
+            segment: memory.segment,
+            base,
+            index: memory.index,
+            scale: memory.scale,
+            displacement,
+        }
+    }
+
+    fn instruction(
+        &self,
+        statement: usize,
+        lock: bool,
+        rep: RepPrefixKind,
+        mnemonic: &str,
+        operands: &[Operand],
+    ) -> Result<Instruction> {
+        let ip = self.addresses[statement];
+        if operands.is_empty() {
+            if let Some(instruction) = string_instruction(mnemonic, rep) {
+                return Ok(instruction);
+            }
+        }
+        let codes = codes(mnemonic).ok_or_else(|| anyhow!("Unknown instruction {}", mnemonic))?;
+        let operands = operands
+            .iter()
+            .map(|operand| self.resolve(statement, operand))
+            .collect::<Vec<_>>();
+        let mut candidates = codes
+            .iter()
+            .filter_map(|&code| build(code, &operands))
+            .collect::<Vec<_>>();
+        // Branches are encoded near and left to the block encoder to shorten
+        if candidates.iter().any(|instruction| instruction.op_code().op_kinds().contains(&OpCodeOperandKind::br64_4)) {
+            candidates.retain(|instruction| !instruction.op_code().op_kinds().contains(&OpCodeOperandKind::br64_1));
+        }
+        // 16-bit operand sizes only when asked for with 16-bit operands, so `push 0x1000` stays a qword
+        let size_16 = |instruction: &Instruction| instruction.op_code().operand_size() == 16;
+        if candidates.iter().any(|instruction| !size_16(instruction)) {
+            candidates.retain(|instruction| !size_16(instruction));
+        }
+        let unsized_memory = operands
+            .iter()
+            .any(|operand| matches!(operand, Resolved::Memory { size: None, .. }));
+        if unsized_memory {
+            let mut sizes = candidates
+                .iter()
+                .map(|instruction| instruction.memory_size().size())
+                .collect::<Vec<_>>();
+            sizes.sort();
+            sizes.dedup();
+            if sizes.len() > 1 {
+                bail!("Ambiguous operand size, use byte/word/dword/qword ptr");
+            }
+        }
+        if candidates.is_empty() {
+            bail!("Invalid operands for {}", mnemonic);
+        }
+        let mut encoder = Encoder::new(64);
+        let mut error = None;
+        let mut best: Option<(usize, Instruction)> = None;
+        for mut instruction in candidates {
+            instruction.set_has_lock_prefix(lock);
+            match rep {
+                RepPrefixKind::Repe => instruction.set_has_rep_prefix(true),
+                RepPrefixKind::Repne => instruction.set_has_repne_prefix(true),
+                _ => {}
+            }
+            match encoder.encode(&instruction, ip) {
+                Ok(length) if best.as_ref().is_none_or(|(best, _)| length < *best) => {
+                    best = Some((length, instruction));
+                }
+                Ok(_) => {}
+                Err(e) => error = Some(e),
+            }
+        }
+        match (best, error) {
+            (Some((_, instruction)), _) => Ok(instruction),
+            (None, Some(e)) => bail!("{}", e),
+            (None, None) => bail!("Invalid operands for {}", mnemonic),
+        }
+    }
+
+    fn data(&self, width: usize, values: &[Expression]) -> Result<Vec<Instruction>> {
+        let mut bytes = Vec::new();
+        for value in values {
+            let value = self.evaluate(value);
+            let bits = width as u32 * 8;
+            if bits < 64 && !(-(1i64 << (bits - 1))..(1i64 << bits)).contains(&value) {
+                bail!("{:#x} doesn't fit in {} bytes", value, width);
+            }
+            bytes.extend_from_slice(&value.to_le_bytes()[..width]);
+        }
+        bytes
+            .chunks(16)
+            .map(|chunk| Instruction::with_declare_byte(chunk).map_err(|e| anyhow!("{}", e)))
+            .collect()
+    }
+}
+
+/// Assemble Intel-syntax instructions, separated by newlines or `;`, to run at `base`.
+///
+/// Labels are defined with `name:`. Any other name, such as `libc.so.6!malloc`, is passed to
+/// `resolve` for its address.
+pub fn assemble(source: &str, base: u64, resolve: impl Fn(&str) -> Option<u64>) -> Result<Vec<u8>> {
+    let program = Program::parse(source)?;
+    let mut symbols = HashMap::new();
+    for name in program.names() {
+        if program.labels.contains_key(name) || symbols.contains_key(name) {
+            continue;
+        }
+        let address = resolve(name).ok_or_else(|| anyhow!("Unknown label or symbol: {}", name))?;
+        symbols.insert(name.to_string(), address);
+    }
+
+    let count = program.statements.len();
+    let mut addresses = (0..=count as u64)
+        .map(|statement| base.wrapping_add(statement * FIRST_PASS_SPACING))
+        .collect::<Vec<_>>();
+    for _ in 0..MAX_PASSES {
+        let layout = Layout {
+            program: &program,
+            addresses: &addresses,
+            symbols: &symbols,
+        };
+        let mut instructions = Vec::new();
+        let mut first_instruction = Vec::with_capacity(count);
+        for (statement, (text, parsed)) in program.statements.iter().enumerate() {
+            let encoded = match parsed {
+                Statement::Instruction {
+                    lock,
+                    rep,
+                    mnemonic,
+                    operands,
+                } => layout
+                    .instruction(statement, *lock, *rep, mnemonic, operands)
+                    .map(|instruction| vec![instruction]),
+                Statement::Data { width, values } => layout.data(*width, values),
+            }
+            .map_err(|e| anyhow!("{}: {}", text, e))?;
+            first_instruction.push(instructions.len());
+            // The block encoder finds branch targets inside the block by these addresses
+            for (chunk, mut instruction) in encoded.into_iter().enumerate() {
+                instruction.set_ip(addresses[statement] + chunk as u64 * 16);
+                instructions.push(instruction);
+            }
+        }
+        let encoded = BlockEncoder::encode(
+            64,
+            InstructionBlock::new(&instructions, base),
+            BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS,
+        )
+        .map_err(|e| anyhow!("{}", e))?;
+        // Statements that encode to nothing at the very end have no offset of their own
+        let offset = |instruction: usize| {
+            encoded.new_instruction_offsets.get(instruction).map_or(encoded.code_buffer.len() as u64, |&offset| offset as u64)
+        };
+        let new_addresses = first_instruction
+            .iter()
+            .map(|&instruction| base.wrapping_add(offset(instruction)))
+            .chain([base.wrapping_add(encoded.code_buffer.len() as u64)])
+            .collect::<Vec<_>>();
+        if new_addresses == addresses {
+            return Ok(encoded.code_buffer);
+        }
+        addresses = new_addresses;
+    }
+    bail!("Code layout doesn't settle, labels keep moving")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn asm(source: &str) -> Result<Vec<u8>> {
+        assemble(source, 0x1000, |name| (name == "malloc").then_some(0x2000))
+    }
+
+    #[test]
+    fn operand_sizes() {
+        assert_eq!(asm("nop").unwrap(), [0x90]);
+        assert_eq!(asm("mov eax, 1").unwrap(), [0xb8, 0x01, 0x00, 0x00, 0x00]);
+        assert_eq!(asm("add rsp, 8").unwrap(), [0x48, 0x83, 0xc4, 0x08]);
+        assert_eq!(asm("mov byte ptr [rax], 1").unwrap(), [0xc6, 0x00, 0x01]);
+        assert_eq!(asm("inc word ptr [rbx]").unwrap(), [0x66, 0xff, 0x03]);
+        // Immediates don't pick 16-bit encodings unless asked for
+        assert_eq!(asm("push 0x1000").unwrap(), [0x68, 0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(asm("rep movsb").unwrap(), [0xf3, 0xa4]);
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(asm("again: dec ecx; jnz again").unwrap(), [0xff, 0xc9, 0x75, 0xfc]);
+        assert_eq!(asm("jmp done\nnop\ndone: ret").unwrap(), [0xeb, 0x01, 0x90, 0xc3]);
+        // A label after the last statement is the end of the code
+        assert_eq!(asm("jmp done; done:").unwrap(), [0xeb, 0x00]);
+        assert_eq!(asm("call malloc").unwrap(), [0xe8, 0xfb, 0x0f, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn rip_relative() {
+        // Numbers are relative to the next instruction, addresses and labels are absolute
+        assert_eq!(asm("mov rax, [rip + 0x10]").unwrap(), [0x48, 0x8b, 0x05, 0x10, 0x00, 0x00, 0x00]);
+        assert_eq!(asm("lea rax, [0x2000]").unwrap(), [0x48, 0x8d, 0x05, 0xf9, 0x0f, 0x00, 0x00]);
+        assert_eq!(
+            asm("mov eax, [rip + value]; ret; value: dd 7").unwrap(),
+            [0x8b, 0x05, 0x01, 0x00, 0x00, 0x00, 0xc3, 0x07, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn data() {
+        assert_eq!(asm("db 1, 2, 0xff").unwrap(), [0x01, 0x02, 0xff]);
+        assert_eq!(asm("dw 0x1234; dd -1").unwrap(), [0x34, 0x12, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(
+            asm("dq here; here:").unwrap(),
+            [0x08, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(asm("db 0x90 # comment").unwrap(), [0x90]);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(asm("mov [rax], 1").is_err());
+        assert!(asm("frob eax").is_err());
+        assert!(asm("call free").is_err());
+        assert!(asm("a: nop; a: nop").is_err());
+        assert!(asm("db 256").is_err());
+        assert!(asm("lock").is_err());
+        assert!(asm("db").is_err());
+        assert!(asm("nop; db").is_err());
+        assert!(asm("mov eax, ebx, ecx").is_err());
+    }
+}
@@ -0,0 +1,289 @@
+//! Reports of what a thread was doing when it received a fatal signal, taken before the signal is delivered.
+
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use iced_x86::{Decoder, DecoderOptions, Formatter, IntelFormatter};
+use libc::{SIGABRT, SIGBUS, SIGFPE, SIGILL, SIGSEGV};
+
+use crate::{
+    elf::ElfCache,
+    module::Module,
+    registers::Registers,
+    symbolize::Symbolizer,
+    target::Target,
+    unwind::{self, StackFrame, UnwindMethod},
+    util::{self, procfs::MemoryMap},
+};
+
+pub const FATAL_SIGNALS: [i32; 5] = [SIGSEGV, SIGBUS, SIGILL, SIGFPE, SIGABRT];
+
+/// Instructions shown before and after the faulting one
+const CODE_CONTEXT: usize = 4;
+
+/// How far before RIP decoding starts, to find the instructions leading up to it
+const CODE_LOOKBEHIND: u64 = 48;
+
+pub fn signal_name(signal: i32) -> String {
+    match signal {
+        SIGSEGV => "SIGSEGV".into(),
+        SIGBUS => "SIGBUS".into(),
+        SIGILL => "SIGILL".into(),
+        SIGFPE => "SIGFPE".into(),
+        SIGABRT => "SIGABRT".into(),
+        signal => format!("signal {}", signal),
+    }
+}
+
+/// Whether `signal` is about to kill the process: thread `tid` neither handles nor blocks it, as
+/// runtimes that turn faults into exceptions (JVMs, Go, Wine) raise these signals all the time.
+/// Threads whose masks can't be read are assumed to die.
+pub fn is_fatal(pid: u32, tid: u32, signal: i32) -> bool {
+    let Ok(masks) = util::procfs::signal_masks(pid, tid) else {
+        return true;
+    };
+    let bit = 1u64 << (signal - 1);
+    (masks.caught | masks.blocked) & bit == 0
+}
+
+/// What the other threads of the process were doing at the time
+pub struct ThreadState {
+    pub pid: u32,
+    pub name: String,
+    /// As in `/proc/pid/stat`, such as "R" for running or "t" for stopped by the debugger
+    pub state: String,
+    /// Only known for threads stopped under ptrace
+    pub rip: Option<u64>,
+}
+
+pub struct CrashReport {
+    pub pid: u32,
+    pub name: String,
+    pub signal: i32,
+    pub code: i32,
+    /// The address that caused the fault, for signals that have one
+    pub fault_address: Option<u64>,
+    pub regs: Registers,
+    /// The memory map containing the fault address
+    pub fault_map: Option<MemoryMap>,
+    /// Instructions around RIP as (address, text), the faulting one included
+    pub code_lines: Vec<(u64, String)>,
+    pub frames: Vec<StackFrame>,
+    /// Each frame's address, symbolized
+    pub frame_names: Vec<String>,
+    pub threads: Vec<ThreadState>,
+}
+
+impl CrashReport {
+    /// Gather everything about a thread stopped at a fatal signal.
+    pub fn capture(
+        target: &dyn Target,
+        pid: u32,
+        name: &str,
+        signal: i32,
+        maps: &[MemoryMap],
+        symbols: &Mutex<Symbolizer>,
+        elves: &Mutex<ElfCache>,
+    ) -> Result<Self> {
+        let siginfo = util::ptrace::get_siginfo(pid)?;
+        let regs = Registers::from(util::ptrace::get_regs(pid)?);
+        // Signals sent by kill(), abort() and the like have no fault address
+        let fault_address = (signal != SIGABRT && siginfo.si_code > 0).then(|| unsafe { siginfo.si_addr() } as u64);
+        let fault_map = fault_address.and_then(|address| maps.iter().find(|map| map.contains(address)).cloned());
+
+        let modules = Module::from_maps(maps);
+        let frames = unwind::backtrace(target, regs.clone(), &modules, &mut elves.lock().unwrap());
+        let frame_names = {
+            let mut symbols = symbols.lock().unwrap();
+            frames.iter().map(|frame| describe_frame(&mut symbols, frame)).collect()
+        };
+
+        Ok(Self {
+            pid,
+            name: name.to_string(),
+            signal,
+            code: siginfo.si_code,
+            fault_address,
+            code_lines: disassemble_around(target, maps, regs.rip),
+            regs,
+            fault_map,
+            frames,
+            frame_names,
+            threads: thread_states(pid),
+        })
+    }
+
+    /// Write the report to `crash-<pid>-<time>.txt` in the working directory, returning the path.
+    pub fn save(&self) -> Result<String> {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+        let path = format!("crash-{}-{}.txt", self.pid, time);
+        std::fs::write(&path, self.to_string())?;
+        Ok(path)
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Thread {} (\"{}\") received {} (code {})",
+            self.pid,
+            self.name,
+            signal_name(self.signal),
+            self.code
+        )?;
+        if let Some(address) = self.fault_address {
+            match &self.fault_map {
+                Some(map) => writeln!(
+                    f,
+                    "Fault address: {:#x}, in {:#x}-{:#x} {} {}",
+                    address,
+                    map.start,
+                    map.end,
+                    map.permissions,
+                    map.pathname.as_deref().unwrap_or("")
+                )?,
+                None => writeln!(f, "Fault address: {:#x}, not mapped", address)?,
+            }
+        }
+
+        writeln!(f, "\nRegisters:")?;
+        for row in register_list(&self.regs).chunks(4) {
+            let row = row.iter().map(|(name, value)| format!("{:>6} {:#018x}", name, value)).collect::<Vec<_>>();
+            writeln!(f, "  {}", row.join("  "))?;
+        }
+
+        writeln!(f, "\nCode:")?;
+        for (address, text) in &self.code_lines {
+            let marker = if *address == self.regs.rip { "=>" } else { "  " };
+            writeln!(f, "{} {:#x}: {}", marker, address, text)?;
+        }
+
+        writeln!(f, "\nBacktrace:")?;
+        for (i, name) in self.frame_names.iter().enumerate() {
+            writeln!(f, "  #{:<3} {}", i, name)?;
+        }
+
+        writeln!(f, "\nThreads:")?;
+        for thread in &self.threads {
+            let rip = thread.rip.map(|rip| format!(" at {:#x}", rip)).unwrap_or_default();
+            writeln!(f, "  {} (\"{}\") {}{}", thread.pid, thread.name, thread.state, rip)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn register_list(regs: &Registers) -> [(&'static str, u64); 23] {
+    [
+        ("rax", regs.rax),
+        ("rbx", regs.rbx),
+        ("rcx", regs.rcx),
+        ("rdx", regs.rdx),
+        ("rsi", regs.rsi),
+        ("rdi", regs.rdi),
+        ("rbp", regs.rbp),
+        ("rsp", regs.rsp),
+        ("r8", regs.r8),
+        ("r9", regs.r9),
+        ("r10", regs.r10),
+        ("r11", regs.r11),
+        ("r12", regs.r12),
+        ("r13", regs.r13),
+        ("r14", regs.r14),
+        ("r15", regs.r15),
+        ("rip", regs.rip),
+        ("eflags", regs.eflags),
+        ("cs", regs.cs),
+        ("ss", regs.ss),
+        ("fs_base", regs.fs_base),
+        ("gs_base", regs.gs_base),
+        ("orig_rax", regs.orig_rax),
+    ]
+}
+
+/// A frame's address along with where it is. Callers are looked up by the call instruction, as
+/// the return address may be past the end of the function.
+fn describe_frame(symbols: &mut Symbolizer, frame: &StackFrame) -> String {
+    let lookup = match frame.method {
+        UnwindMethod::Registers => frame.pc,
+        _ => frame.pc.wrapping_sub(1),
+    };
+    match symbols.symbolize(lookup) {
+        Some(mut symbolized) => {
+            let adjust = frame.pc.wrapping_sub(lookup);
+            symbolized.module_offset += adjust;
+            if let Some((_, offset)) = &mut symbolized.symbol {
+                *offset += adjust;
+            }
+            format!("{:#x} ({})", frame.pc, symbolized)
+        }
+        None => format!("{:#x}", frame.pc),
+    }
+}
+
+/// A few instructions before and after `rip`. Decoding starts a little before it and is only
+/// trusted if it lands exactly on `rip`; otherwise the listing starts at `rip`.
+fn disassemble_around(target: &dyn Target, maps: &[MemoryMap], rip: u64) -> Vec<(u64, String)> {
+    let Some(map) = maps.iter().find(|map| map.contains(rip)) else {
+        return Vec::new();
+    };
+    let decode = |start: u64| -> Vec<(u64, String)> {
+        let len = (map.end - start).min(CODE_LOOKBEHIND + 15 * (CODE_CONTEXT as u64 + 1));
+        let Ok(code) = target.read_bytes(start, len as _) else {
+            return Vec::new();
+        };
+        let mut formatter = IntelFormatter::new();
+        let options = formatter.options_mut();
+        options.set_hex_prefix("0x");
+        options.set_hex_suffix("");
+        options.set_uppercase_hex(false);
+        options.set_space_after_operand_separator(true);
+        options.set_branch_leading_zeros(false);
+        Decoder::with_ip(64, &code, start, DecoderOptions::NONE)
+            .into_iter()
+            .take_while(|instruction| !instruction.is_invalid() || instruction.ip() <= rip)
+            .map(|instruction| {
+                let mut text = String::new();
+                formatter.format(&instruction, &mut text);
+                (instruction.ip(), text)
+            })
+            .collect()
+    };
+    let mut lines = decode(rip.saturating_sub(CODE_LOOKBEHIND).max(map.start));
+    let index = match lines.iter().position(|(address, _)| *address == rip) {
+        Some(index) => index,
+        None => {
+            lines = decode(rip);
+            0
+        }
+    };
+    let first = index.saturating_sub(CODE_CONTEXT);
+    lines.into_iter().skip(first).take(index - first + CODE_CONTEXT + 1).collect()
+}
+
+fn thread_states(pid: u32) -> Vec<ThreadState> {
+    let mut tasks = util::procfs::get_tasks(pid).unwrap_or_default();
+    tasks.sort();
+    tasks
+        .into_iter()
+        .map(|tid| {
+            let read = |file: &str| std::fs::read_to_string(format!("/proc/{}/task/{}/{}", pid, tid, file)).unwrap_or_default();
+            let stat = read("stat");
+            let state = stat
+                .rsplit_once(')')
+                .and_then(|(_, rest)| rest.split_whitespace().next())
+                .unwrap_or("?")
+                .to_string();
+            ThreadState {
+                pid: tid,
+                name: read("comm").trim().to_string(),
+                state,
+                rip: util::ptrace::get_regs(tid).ok().map(|regs| regs.rip),
+            }
+        })
+        .collect()
+}
@@ -1,31 +1,72 @@
+use crate::crash::{self, CrashReport, FATAL_SIGNALS};
+use crate::elf::coredump::CoreFile;
+use crate::elf::ElfCache;
+use crate::freeze::Freezes;
+use crate::hook::ImportHook;
 use crate::hwbp::{dr_offset, HardwareBreakpoint};
-use crate::runtime::{RhaiFpRegisters, RhaiRegisters, RhaiThread};
+use crate::maps::{self, MemoryMaps};
+use crate::patch::PatchJournal;
+use crate::stats::Stats;
+use crate::symbolize::Symbolizer;
+use crate::target::{LiveProcess, Target};
+use crate::runtime::{crash_report_to_dynamic, map_changes_to_dynamic, RhaiFpRegisters, RhaiRegisters, RhaiThread};
 use crate::thread::{Thread, ThreadState, DEFAULT_PTRACE_OPTIONS};
 use crate::util;
 use crate::util::signal::WaitStatus;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
-use libc::{PTRACE_EVENT_CLONE, SIGTRAP, WSTOPSIG};
+use libc::{PTRACE_EVENT_CLONE, PTRACE_EVENT_STOP, SIGTRAP, WSTOPSIG};
 use log::{debug, error, info};
 use rhai::Dynamic;
 
 use crate::runtime::{RuntimeCallback, Script};
 
 pub struct Debugger {
-    pub threads: Vec<Thread>,
+    pub pid: u32,
+    /// Where memory, registers and maps come from, shared with everything that reads them
+    pub target: Arc<dyn Target>,
+    /// Traced threads, by pid
+    pub threads: BTreeMap<u32, Thread>,
     pub breakpoints: Vec<HardwareBreakpoint>,
     pub callbacks: Vec<RuntimeCallback>,
+    pub patches: Arc<Mutex<PatchJournal>>,
+    pub freezes: Arc<Mutex<Freezes>>,
+    pub stats: Arc<Mutex<Stats>>,
+    pub maps: Arc<Mutex<MemoryMaps>>,
+    pub elves: Arc<Mutex<ElfCache>>,
+    pub symbols: Arc<Mutex<Symbolizer>>,
+    pub import_hooks: Vec<ImportHook>,
+    /// Threads that reported their first stop before their creation was reported
+    early_stops: HashSet<u32>,
 }
 
 impl Debugger {
     pub fn new() -> Self {
+        let target: Arc<dyn Target> = Arc::new(LiveProcess { pid: 0 });
+        let maps = Arc::new(Mutex::new(MemoryMaps::new(target.clone())));
+        let elves = Arc::new(Mutex::new(ElfCache::default()));
         Self {
-            threads: Vec::new(),
+            pid: 0,
+            threads: BTreeMap::new(),
             breakpoints: Vec::new(),
             callbacks: Vec::new(),
+            patches: Arc::new(Mutex::new(PatchJournal::new(target.clone()))),
+            freezes: Arc::new(Mutex::new(Freezes::new(0))),
+            stats: Arc::new(Mutex::new(Stats::new())),
+            symbols: Arc::new(Mutex::new(Symbolizer::new(target.clone(), maps.clone(), elves.clone()))),
+            target,
+            maps,
+            elves,
+            import_hooks: Vec::new(),
+            early_stops: HashSet::new(),
         }
     }
 
     pub fn attach(&mut self, pid: u32) -> Result<()> {
+        self.set_target(pid, Arc::new(LiveProcess { pid }))?;
         let tasks = util::procfs::get_tasks(pid)?;
         for task in tasks {
             let mut thread = Thread::new(task)?;
@@ -35,18 +76,51 @@ impl Debugger {
             thread.set_options(DEFAULT_PTRACE_OPTIONS)?;
             thread.cont(None)?;
 
-            self.threads.push(thread);
+            self.threads.insert(thread.pid, thread);
         }
         info!(
             "Attached to {} threads (Thread leader: {})",
             self.threads.len(),
-            self.threads[0].pid
+            self.pid
+        );
+        Ok(())
+    }
+
+    /// Serve the target from a core file instead of attaching to a process. Its threads count as
+    /// stopped for good.
+    pub fn open_core(&mut self, core: Arc<CoreFile>) -> Result<()> {
+        self.set_target(core.pid, core.clone())?;
+        for tid in core.threads.iter().map(|thread| thread.pid) {
+            self.threads.insert(
+                tid,
+                Thread {
+                    pid: tid,
+                    name: core.name.clone(),
+                    state: ThreadState::Stopped,
+                },
+            );
+        }
+        info!(
+            "Opened core file of {} ({} threads, {} maps)",
+            core.pid,
+            self.threads.len(),
+            core.maps.len()
         );
         Ok(())
     }
 
+    fn set_target(&mut self, pid: u32, target: Arc<dyn Target>) -> Result<()> {
+        self.pid = pid;
+        self.patches.lock().unwrap().target = target.clone();
+        self.freezes.lock().unwrap().pid = pid;
+        self.symbols.lock().unwrap().target = target.clone();
+        self.maps.lock().unwrap().load(target.clone())?;
+        self.target = target;
+        Ok(())
+    }
+
     pub fn stop_all(&mut self) -> Result<()> {
-        for thread in &mut self.threads {
+        for thread in self.threads.values_mut() {
             if thread.state == ThreadState::Running {
                 thread.interrupt()?;
             }
@@ -55,7 +129,7 @@ impl Debugger {
     }
 
     pub fn continue_all(&mut self) -> Result<()> {
-        for thread in &mut self.threads {
+        for thread in self.threads.values_mut() {
             if thread.is_traced() {
                 thread.cont(None)?;
             }
@@ -64,12 +138,13 @@ impl Debugger {
     }
 
     pub fn apply_breakpoints(&mut self) -> Result<()> {
-        for thread in &mut self.threads {
+        for thread in self.threads.values_mut() {
             for breakpoint in &self.breakpoints {
                 thread.set_breakpoint(breakpoint)?;
                 debug!(
-                    "Set breakpoint at {:#x} in thread {}",
-                    breakpoint.address, thread.pid
+                    "Set breakpoint at {} in thread {}",
+                    self.symbols.lock().unwrap().describe(breakpoint.address as _),
+                    thread.pid
                 );
             }
         }
@@ -77,13 +152,14 @@ impl Debugger {
     }
 
     pub fn clear_breakpoints(&mut self) -> Result<()> {
-        for thread in &mut self.threads {
+        for thread in self.threads.values_mut() {
             for breakpoint in &self.breakpoints {
                 debug!("(about to clear breakpoint) Thread state: {:?}", thread.state);
                 thread.clear_breakpoint(breakpoint)?;
                 debug!(
-                    "Cleared breakpoint at {:#x} in thread {}",
-                    breakpoint.address, thread.pid
+                    "Cleared breakpoint at {} in thread {}",
+                    self.symbols.lock().unwrap().describe(breakpoint.address as _),
+                    thread.pid
                 );
             }
         }
@@ -91,15 +167,65 @@ impl Debugger {
         Ok(())
     }
 
-    // Main loop
+    /// Whether the script wants to hear about memory map changes, which then have to be looked
+    /// for periodically
+    fn watches_maps(&self) -> bool {
+        self.callbacks.iter().any(|cb| matches!(cb, RuntimeCallback::MapChanged(_)))
+    }
+
+    /// Let the script know about the memory map changes found by any refresh since the last call.
+    fn dispatch_map_changes(&self, script: &Script) {
+        let changes = self.maps.lock().unwrap().take_changes();
+        if changes.is_empty() {
+            return;
+        }
+        debug!(
+            "Memory maps changed: {} added, {} removed, {} changed",
+            changes.added.len(),
+            changes.removed.len(),
+            changes.changed.len()
+        );
+        let changes = map_changes_to_dynamic(&changes);
+        for cb in &self.callbacks {
+            if let RuntimeCallback::MapChanged(cb) = cb {
+                if let Err(e) = cb.call::<()>(&script.engine, &script.ast, (changes.clone(),)) {
+                    error!("Error calling map change callback: {}", e);
+                }
+            }
+        }
+    }
+
+    /// How long the main loop can wait for thread events before periodic work (freezes, map
+    /// refreshes) is due, if there is any.
+    pub fn next_timeout(&self) -> Option<Duration> {
+        let freezes = self.freezes.lock().unwrap().next_due();
+        if !self.watches_maps() {
+            return freezes;
+        }
+        let maps = maps::MAX_AGE.saturating_sub(self.maps.lock().unwrap().age());
+        Some(freezes.map_or(maps, |due| due.min(maps)))
+    }
+
+    /// Handle every pending event of the target's threads, without blocking. The main loop calls
+    /// this whenever a thread changes state (SIGCHLD) or a timer is due, see [`Debugger::next_timeout`].
     pub fn run(&mut self, script: &Script) -> Result<()> {
         let mut new_threads = Vec::new();
 
-        for thread in &mut self.threads {
-            let Ok(status) = thread.wait_nonblocking() else {
-                thread.detach().ok();
+        self.freezes.lock().unwrap().apply_due();
+        if self.watches_maps() {
+            self.maps.lock().unwrap().refresh_if_stale();
+        }
+
+        while let Some((pid, status)) = util::signal::wait_any()? {
+            let noticed = Instant::now();
+            let Some(thread) = self.threads.get_mut(&pid) else {
+                // A new thread can report its first stop before its parent reports creating it
+                if matches!(status, WaitStatus::Stopped(_)) {
+                    self.early_stops.insert(pid);
+                }
                 continue;
             };
+            thread.update_state(&status);
 
             match status {
                 WaitStatus::Stopped(status) => {
@@ -114,7 +240,9 @@ impl Debugger {
 
                         let mut new_thread = Thread::new(pid as u32)?;
                         new_thread.state = ThreadState::Tracing; // New threads are always traced
-                        new_thread.wait()?;
+                        if !self.early_stops.remove(&new_thread.pid) {
+                            new_thread.wait()?;
+                        }
                         new_thread.set_options(DEFAULT_PTRACE_OPTIONS)?;
                         for breakpoint in &self.breakpoints {
                             new_thread.set_breakpoint(breakpoint)?;
@@ -142,19 +270,28 @@ impl Debugger {
 
                         new_threads.push(new_thread);
 
+                        thread.cont(None)?;
+                    } else if status >> 8 == (SIGTRAP | PTRACE_EVENT_STOP << 8) {
+                        // An interrupt that was still pending, like one from dump_core() racing another stop
+                        debug!("Thread {} stopped by a stray interrupt", thread.pid);
                         thread.cont(None)?;
                     } else if signal == SIGTRAP {
                         let hit_breakpoints = thread.get_hit_breakpoints()?;
                         let registers = thread.get_regs()?;
+                        let mut hits = Vec::new();
                         for index in &hit_breakpoints {
                             let Some(breakpoint) = self.breakpoints.iter().find(|x| x.dr == *index)
                             else {
                                 continue;
                             };
                             debug!(
-                                "Thread {} hit breakpoint {:#x} ({:?})",
-                                thread.pid, breakpoint.address, breakpoint.kind
+                                "Thread {} hit breakpoint {:#x} ({:?}) at {}",
+                                thread.pid,
+                                breakpoint.address,
+                                breakpoint.kind,
+                                self.symbols.lock().unwrap().describe(registers.rip)
                             );
+                            let callbacks_started = Instant::now();
                             let regs = RhaiRegisters::from(&registers);
                             let regs = Dynamic::from(regs).into_shared();
                             for cb in &self.callbacks {
@@ -179,6 +316,8 @@ impl Debugger {
                                     _ => {}
                                 }
                             }
+                            hits.push((breakpoint.dr, breakpoint.address, breakpoint.kind, callbacks_started.elapsed()));
+                            self.freezes.lock().unwrap().apply_watchpoint(*index);
                             thread.clear_breakpoint_hit(*index)?;
                         }
 
@@ -187,6 +326,48 @@ impl Debugger {
                         } else {
                             thread.cont(None)?;
                         }
+                        let stopped = noticed.elapsed();
+                        let mut stats = self.stats.lock().unwrap();
+                        for (dr, address, kind, callback) in hits {
+                            stats.record_hit(dr, address, kind, callback, stopped);
+                        }
+                    } else if FATAL_SIGNALS.contains(&signal) && crash::is_fatal(self.pid, thread.pid, signal) {
+                        // Fresh maps, as the fault may be in memory mapped or unmapped moments ago
+                        let maps = {
+                            let mut maps = self.maps.lock().unwrap();
+                            if let Err(e) = maps.refresh() {
+                                debug!("Failed to refresh memory maps: {}", e);
+                            }
+                            maps.clone()
+                        };
+                        match CrashReport::capture(&*self.target, thread.pid, &thread.name, signal, &maps, &self.symbols, &self.elves) {
+                            Ok(report) => {
+                                let path = match report.save() {
+                                    Ok(path) => {
+                                        info!("Thread {} crashed, wrote report to {}", thread.pid, path);
+                                        Some(path)
+                                    }
+                                    Err(e) => {
+                                        error!("Failed to save crash report: {}\n{}", e, report);
+                                        None
+                                    }
+                                };
+                                let report = crash_report_to_dynamic(&report, path.as_deref());
+                                for cb in &self.callbacks {
+                                    if let RuntimeCallback::Crashed(cb) = cb {
+                                        if let Err(e) = cb.call::<()>(
+                                            &script.engine,
+                                            &script.ast,
+                                            (report.clone(), RhaiThread::from(&*thread)),
+                                        ) {
+                                            error!("Error calling crash callback: {}", e);
+                                        }
+                                    }
+                                }
+                            }
+                            Err(e) => error!("Failed to capture crash report for thread {}: {}", thread.pid, e),
+                        }
+                        thread.cont(Some(signal))?;
                     } else {
                         thread.cont(Some(signal))?;
                         debug!("Continued thread {}", thread.pid);
@@ -212,14 +393,18 @@ impl Debugger {
                 WaitStatus::Signaled(signal) => {
                     debug!("Thread {} signaled with {}", thread.pid, signal);
                 }
-                _ => {}
+            }
+            // Added right away, as the next status may already be theirs
+            for thread in new_threads.drain(..) {
+                self.threads.insert(thread.pid, thread);
             }
         }
         // Clean up any threads that exited or were detached
-        self.threads.retain(|thread| {
+        self.threads.retain(|_, thread| {
             !(thread.state == ThreadState::Detached || thread.state == ThreadState::Exited || !util::procfs::process_exists(thread.pid))
         });
-        self.threads.extend(new_threads);
+        // Including changes found by scripts calling refresh_maps() or map_entry() from callbacks
+        self.dispatch_map_changes(script);
         Ok(())
     }
 }
@@ -227,7 +412,7 @@ impl Debugger {
 impl Drop for Debugger {
     fn drop(&mut self) {
         debug!("Dropping debugger");
-        for thread in &mut self.threads {
+        for thread in self.threads.values_mut() {
             // thread.interrupt().expect("Failed to interrupt thread during debugger shutdown");
             if thread.interrupt().is_ok() {
                 for breakpoint in &self.breakpoints {
@@ -236,6 +421,10 @@ impl Drop for Debugger {
                         .expect("Failed to disable breakpoint during debugger shutdown");
                 }
             }
+        }
+        self.revert_import_hooks();
+        self.patches.lock().unwrap().revert_all();
+        for thread in self.threads.values_mut() {
             thread
                 .detach()
                 .ok();
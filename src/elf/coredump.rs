@@ -0,0 +1,643 @@
+//! ELF core files: writing them for a live process, in the layout the kernel uses so that gdb can
+//! open them, and reading them back so scripts can run against a dump.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    mem,
+    os::unix::fs::FileExt,
+};
+
+use anyhow::{anyhow, bail, Result};
+use libc::{user_fpregs_struct, user_regs_struct, PTRACE_EVENT_STOP, SIGTRAP};
+
+use crate::util::{self, procfs::MemoryMap};
+
+use super::{u32_at, u64_at, Header, PT_LOAD, PT_NOTE};
+
+pub const ET_CORE: u16 = 4;
+pub const EM_X86_64: u16 = 62;
+
+pub const NT_PRSTATUS: u32 = 1;
+pub const NT_FPREGSET: u32 = 2;
+pub const NT_PRPSINFO: u32 = 3;
+pub const NT_AUXV: u32 = 6;
+pub const NT_FILE: u32 = 0x46494c45;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const PAGE_SIZE: u64 = 0x1000;
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// Size of `struct elf_prstatus` on x86_64, and where its fields are
+pub const PRSTATUS_SIZE: usize = 336;
+pub const PRSTATUS_PID: usize = 32;
+pub const PRSTATUS_REGS: usize = 112;
+const PRSTATUS_FPVALID: usize = 328;
+
+/// Size of `struct elf_prpsinfo` on x86_64
+const PRPSINFO_SIZE: usize = 136;
+
+/// Memory is copied into the file a chunk at a time
+const DUMP_CHUNK_SIZE: u64 = 1 << 20;
+
+pub struct CoreSummary {
+    pub threads: usize,
+    /// Threads left out because they couldn't be stopped, so their registers couldn't be read
+    pub skipped_threads: usize,
+    pub segments: usize,
+    pub size: u64,
+}
+
+pub struct CoreThread {
+    pub pid: u32,
+    pub regs: user_regs_struct,
+    pub fp_regs: Option<user_fpregs_struct>,
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// Copy a C struct out of note data, which has no alignment guarantees
+fn from_bytes<T>(bytes: &[u8]) -> Option<T> {
+    if bytes.len() < mem::size_of::<T>() {
+        return None;
+    }
+    Some(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn push_note(notes: &mut Vec<u8>, kind: u32, desc: &[u8]) {
+    notes.extend_from_slice(&5u32.to_le_bytes());
+    notes.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    notes.extend_from_slice(&kind.to_le_bytes());
+    notes.extend_from_slice(b"CORE\0\0\0\0");
+    notes.extend_from_slice(desc);
+    notes.resize(notes.len().next_multiple_of(4), 0);
+}
+
+/// The state, parent, process group and session from `/proc/pid/stat`
+fn process_stat(pid: u32) -> Result<(char, u32, u32, u32)> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // The command name can contain anything, so fields are counted from its closing parenthesis
+    let fields = stat.rsplit_once(')').map(|(_, rest)| rest).unwrap_or_default();
+    let fields = fields.split_whitespace().collect::<Vec<_>>();
+    let field = |i: usize| fields.get(i).and_then(|field| field.parse().ok()).unwrap_or(0);
+    let state = fields.first().and_then(|state| state.chars().next()).unwrap_or('R');
+    Ok((state, field(1), field(2), field(3)))
+}
+
+fn prstatus(thread: &CoreThread, stat: (char, u32, u32, u32)) -> Vec<u8> {
+    let (_, ppid, pgrp, sid) = stat;
+    let mut desc = vec![0u8; PRSTATUS_SIZE];
+    put_u32(&mut desc, PRSTATUS_PID, thread.pid);
+    put_u32(&mut desc, PRSTATUS_PID + 4, ppid);
+    put_u32(&mut desc, PRSTATUS_PID + 8, pgrp);
+    put_u32(&mut desc, PRSTATUS_PID + 12, sid);
+    let regs = as_bytes(&thread.regs);
+    desc[PRSTATUS_REGS..PRSTATUS_REGS + regs.len()].copy_from_slice(regs);
+    put_u32(&mut desc, PRSTATUS_FPVALID, thread.fp_regs.is_some() as u32);
+    desc
+}
+
+fn prpsinfo(pid: u32, stat: (char, u32, u32, u32)) -> Vec<u8> {
+    let (state, ppid, pgrp, sid) = stat;
+    let mut desc = vec![0u8; PRPSINFO_SIZE];
+    desc[0] = "RSDTZW".find(state).unwrap_or(0) as u8;
+    desc[1] = state as u8;
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    let id = |key: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(key))
+            .and_then(|ids| ids.split_whitespace().next()?.parse().ok())
+            .unwrap_or(0)
+    };
+    put_u32(&mut desc, 16, id("Uid:"));
+    put_u32(&mut desc, 20, id("Gid:"));
+    put_u32(&mut desc, 24, pid);
+    put_u32(&mut desc, 28, ppid);
+    put_u32(&mut desc, 32, pgrp);
+    put_u32(&mut desc, 36, sid);
+    let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+    let comm = comm.trim_end().as_bytes();
+    let len = comm.len().min(15);
+    desc[40..40 + len].copy_from_slice(&comm[..len]);
+    let mut args = std::fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    args.truncate(79);
+    let len = args.len();
+    for byte in &mut args {
+        if *byte == 0 {
+            *byte = b' ';
+        }
+    }
+    desc[56..56 + len].copy_from_slice(&args);
+    desc
+}
+
+/// The files mapped into the process, as the kernel writes them: a count and the page size, then
+/// (start, end, offset in pages) for each mapping, then their paths
+fn file_note(maps: &[MemoryMap]) -> Vec<u8> {
+    let files = maps
+        .iter()
+        .filter(|map| map.pathname.as_deref().is_some_and(|path| path.starts_with('/')))
+        .collect::<Vec<_>>();
+    let mut desc = Vec::new();
+    desc.extend_from_slice(&(files.len() as u64).to_le_bytes());
+    desc.extend_from_slice(&PAGE_SIZE.to_le_bytes());
+    for map in &files {
+        desc.extend_from_slice(&map.start.to_le_bytes());
+        desc.extend_from_slice(&map.end.to_le_bytes());
+        desc.extend_from_slice(&(map.offset / PAGE_SIZE).to_le_bytes());
+    }
+    for map in &files {
+        desc.extend_from_slice(map.pathname.as_deref().unwrap_or_default().as_bytes());
+        desc.push(0);
+    }
+    desc
+}
+
+/// Whether a map's contents go into the core file. Like the kernel, special mappings that can't be
+/// read through ptrace are only described, and `[vsyscall]` is left out entirely.
+fn is_dumped(map: &MemoryMap) -> bool {
+    map.permissions.starts_with('r') && !map.pathname.as_deref().is_some_and(|path| path.starts_with("[vvar"))
+}
+
+fn segment_flags(map: &MemoryMap) -> u32 {
+    let permissions = map.permissions.as_bytes();
+    let mut flags = 0;
+    if permissions.first() == Some(&b'r') {
+        flags |= PF_R;
+    }
+    if permissions.get(1) == Some(&b'w') {
+        flags |= PF_W;
+    }
+    if permissions.get(2) == Some(&b'x') {
+        flags |= PF_X;
+    }
+    flags
+}
+
+/// Copy `[start, end)` into the file, zero-filling pages that can't be read so offsets stay right.
+fn write_memory(out: &mut impl Write, pid: u32, start: u64, end: u64) -> Result<()> {
+    let mut address = start;
+    while address < end {
+        let len = DUMP_CHUNK_SIZE.min(end - address);
+        match util::mem::read_bytes(pid, address as _, len as _) {
+            Ok(bytes) => out.write_all(&bytes)?,
+            Err(_) => {
+                for page in (address..address + len).step_by(PAGE_SIZE as usize) {
+                    let page_len = PAGE_SIZE.min(address + len - page) as usize;
+                    let bytes = util::mem::read_bytes(pid, page as _, page_len).unwrap_or_else(|_| vec![0; page_len]);
+                    out.write_all(&bytes)?;
+                }
+            }
+        }
+        address += len;
+    }
+    Ok(())
+}
+
+/// Threads that were interrupted for a dump, continued again when dropped
+struct Interrupted(Vec<u32>);
+
+impl Interrupted {
+    /// Interrupt every traced thread that isn't stopped already. A thread that stops for something
+    /// else first (a breakpoint, a signal) is stopped all the same, and that stop is left for the
+    /// debugger to handle as usual, along with the interrupt that comes after it.
+    fn stop(tasks: &[u32]) -> Self {
+        let mut interrupted = Vec::new();
+        for &task in tasks {
+            // Only threads in a ptrace-stop have readable registers
+            if util::ptrace::get_regs(task).is_ok() || util::ptrace::interrupt(task).is_err() {
+                continue;
+            }
+            if !util::signal::wait_stopped(task).unwrap_or(false) {
+                continue;
+            }
+            let interrupt = SIGTRAP | PTRACE_EVENT_STOP << 8;
+            if util::ptrace::get_siginfo(task).is_ok_and(|info| info.si_code == interrupt)
+                && util::signal::wait(task).is_ok()
+            {
+                interrupted.push(task);
+            }
+        }
+        Self(interrupted)
+    }
+}
+
+impl Drop for Interrupted {
+    fn drop(&mut self) {
+        for &task in &self.0 {
+            util::ptrace::cont(task, None).ok();
+        }
+    }
+}
+
+/// Write a core file of `pid` to `path`. Running threads are stopped for the duration of the dump,
+/// so that every thread's registers are included, the thread leader's first.
+pub fn dump_core(pid: u32, maps: &[MemoryMap], path: &str) -> Result<CoreSummary> {
+    let mut tasks = util::procfs::get_tasks(pid)?;
+    tasks.sort_by_key(|&task| (task != pid, task));
+    let _interrupted = Interrupted::stop(&tasks);
+    let threads = tasks
+        .iter()
+        .filter_map(|&task| {
+            Some(CoreThread {
+                pid: task,
+                regs: util::ptrace::get_regs(task).ok()?,
+                fp_regs: util::ptrace::get_fp_regs(task).ok(),
+            })
+        })
+        .collect::<Vec<_>>();
+    if threads.is_empty() {
+        bail!("No thread of {} is stopped, so there are no registers to dump", pid);
+    }
+
+    let stat = process_stat(pid)?;
+    let mut notes = Vec::new();
+    for (i, thread) in threads.iter().enumerate() {
+        push_note(&mut notes, NT_PRSTATUS, &prstatus(thread, stat));
+        if i == 0 {
+            push_note(&mut notes, NT_PRPSINFO, &prpsinfo(pid, stat));
+            push_note(&mut notes, NT_AUXV, &std::fs::read(format!("/proc/{}/auxv", pid))?);
+            push_note(&mut notes, NT_FILE, &file_note(maps));
+        }
+        if let Some(fp_regs) = &thread.fp_regs {
+            push_note(&mut notes, NT_FPREGSET, as_bytes(fp_regs));
+        }
+    }
+
+    let segments = maps
+        .iter()
+        .filter(|map| map.pathname.as_deref() != Some("[vsyscall]"))
+        .collect::<Vec<_>>();
+    let phnum = segments.len() + 1;
+    if phnum >= 0xffff {
+        bail!("Too many memory maps for a core file ({})", segments.len());
+    }
+    let notes_offset = (ELF_HEADER_SIZE + phnum * PROGRAM_HEADER_SIZE) as u64;
+    let data_offset = (notes_offset + notes.len() as u64).next_multiple_of(PAGE_SIZE);
+
+    let mut out = BufWriter::new(File::create(path)?);
+    let mut header = Vec::with_capacity(ELF_HEADER_SIZE);
+    header.extend_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    header.extend_from_slice(&ET_CORE.to_le_bytes());
+    header.extend_from_slice(&EM_X86_64.to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header.extend_from_slice(&0u64.to_le_bytes()); // entry
+    header.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes()); // phoff
+    header.extend_from_slice(&0u64.to_le_bytes()); // shoff
+    header.extend_from_slice(&0u32.to_le_bytes()); // flags
+    header.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    header.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    header.extend_from_slice(&(phnum as u16).to_le_bytes());
+    header.extend_from_slice(&64u16.to_le_bytes()); // shentsize
+    header.extend_from_slice(&[0; 4]); // shnum, shstrndx
+    out.write_all(&header)?;
+
+    let mut program_header = |kind: u32, flags: u32, offset: u64, vaddr: u64, filesz: u64, memsz: u64, align: u64| {
+        let mut header = Vec::with_capacity(PROGRAM_HEADER_SIZE);
+        header.extend_from_slice(&kind.to_le_bytes());
+        header.extend_from_slice(&flags.to_le_bytes());
+        for value in [offset, vaddr, 0, filesz, memsz, align] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        out.write_all(&header)
+    };
+    program_header(PT_NOTE, 0, notes_offset, 0, notes.len() as u64, 0, 4)?;
+    let mut offset = data_offset;
+    for map in &segments {
+        let size = map.end - map.start;
+        let filesz = if is_dumped(map) { size } else { 0 };
+        program_header(PT_LOAD, segment_flags(map), offset, map.start, filesz, size, PAGE_SIZE)?;
+        offset += filesz;
+    }
+
+    out.write_all(&notes)?;
+    out.write_all(&vec![0; (data_offset - notes_offset) as usize - notes.len()])?;
+    for map in segments.iter().filter(|map| is_dumped(map)) {
+        write_memory(&mut out, pid, map.start, map.end)?;
+    }
+    out.flush()?;
+
+    Ok(CoreSummary {
+        threads: threads.len(),
+        skipped_threads: tasks.len() - threads.len(),
+        segments: segments.len(),
+        size: offset,
+    })
+}
+
+/// A loadable segment of a core file: `memsz` bytes of memory at `vaddr`, of which the first
+/// `filesz` are stored in the core at `offset`.
+struct CoreSegment {
+    vaddr: u64,
+    memsz: u64,
+    offset: u64,
+    filesz: u64,
+}
+
+/// A core file opened for reading. Memory comes from the core, or for file-backed pages the
+/// kernel left out (such as unmodified code), from the mapped file itself.
+pub struct CoreFile {
+    file: File,
+    pub pid: u32,
+    pub name: String,
+    /// Threads in the order of their notes, the one that crashed (or the thread leader) first
+    pub threads: Vec<CoreThread>,
+    /// The process's memory maps, rebuilt from the segments and `NT_FILE`
+    pub maps: Vec<MemoryMap>,
+    segments: Vec<CoreSegment>,
+}
+
+impl CoreFile {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = File::open(path)?;
+        let mut ehdr = [0u8; ELF_HEADER_SIZE];
+        file.read_exact_at(&mut ehdr, 0)?;
+        let header = Header::parse(&ehdr)?;
+        if header.kind != ET_CORE || header.machine != EM_X86_64 {
+            bail!("{} isn't an x86_64 core file", path);
+        }
+        if (header.phentsize as usize) < PROGRAM_HEADER_SIZE {
+            bail!("{} has {}-byte program headers, expected {}", path, header.phentsize, PROGRAM_HEADER_SIZE);
+        }
+        // Offsets and sizes are checked against the file before anything is allocated for them
+        let file_len = file.metadata()?.len();
+        let in_file = |offset: u64, len: u64| offset.checked_add(len).is_some_and(|end| end <= file_len);
+        let phdrs_len = header.phnum as u64 * header.phentsize as u64;
+        if !in_file(header.phoff, phdrs_len) {
+            bail!("Program headers of {} run past the end of the file", path);
+        }
+        let mut phdrs = vec![0u8; phdrs_len as usize];
+        file.read_exact_at(&mut phdrs, header.phoff)?;
+
+        let mut segments = Vec::new();
+        let mut flags = Vec::new();
+        let mut notes = Vec::new();
+        for phdr in phdrs.chunks_exact(header.phentsize as usize) {
+            let truncated = || anyhow!("Truncated program header");
+            let kind = u32_at(phdr, 0).ok_or_else(truncated)?;
+            let offset = u64_at(phdr, 8).ok_or_else(truncated)?;
+            let filesz = u64_at(phdr, 32).ok_or_else(truncated)?;
+            match kind {
+                PT_LOAD => {
+                    let vaddr = u64_at(phdr, 16).ok_or_else(truncated)?;
+                    let memsz = u64_at(phdr, 40).ok_or_else(truncated)?;
+                    if vaddr.checked_add(memsz).is_none() {
+                        bail!("Segment at {:#x} of {:#x} bytes runs past the end of the address space", vaddr, memsz);
+                    }
+                    flags.push(u32_at(phdr, 4).ok_or_else(truncated)?);
+                    segments.push(CoreSegment {
+                        vaddr,
+                        memsz,
+                        offset,
+                        filesz,
+                    });
+                }
+                PT_NOTE => {
+                    if !in_file(offset, filesz) {
+                        bail!("Note segment at {:#x} of {:#x} bytes runs past the end of {}", offset, filesz, path);
+                    }
+                    let mut data = vec![0u8; filesz as usize];
+                    file.read_exact_at(&mut data, offset)?;
+                    notes.extend_from_slice(&data);
+                }
+                _ => {}
+            }
+        }
+
+        let mut core = CoreFile {
+            file,
+            pid: 0,
+            name: String::new(),
+            threads: Vec::new(),
+            maps: Vec::new(),
+            segments,
+        };
+        let mut files = Vec::new();
+        for (kind, desc) in core_notes(&notes) {
+            match kind {
+                NT_PRSTATUS => core.threads.push(parse_prstatus(desc)?),
+                NT_FPREGSET => {
+                    if let Some(thread) = core.threads.last_mut() {
+                        thread.fp_regs = from_bytes(desc);
+                    }
+                }
+                NT_PRPSINFO => {
+                    core.pid = u32_at(desc, 24).unwrap_or(0);
+                    core.name = super::str_at(desc.get(40..56).unwrap_or_default(), 0);
+                }
+                NT_FILE => files = parse_file_note(desc),
+                _ => {}
+            }
+        }
+        if core.threads.is_empty() {
+            bail!("{} has no threads", path);
+        }
+        if core.pid == 0 {
+            core.pid = core.threads[0].pid;
+        }
+
+        core.maps = core
+            .segments
+            .iter()
+            .zip(flags)
+            .map(|(segment, flags)| {
+                let file = files.iter().find(|(start, _, _, _)| *start == segment.vaddr);
+                let permissions = [(PF_R, 'r'), (PF_W, 'w'), (PF_X, 'x')]
+                    .iter()
+                    .map(|&(flag, c)| if flags & flag != 0 { c } else { '-' })
+                    .chain(['p'])
+                    .collect();
+                MemoryMap {
+                    start: segment.vaddr,
+                    end: segment.vaddr + segment.memsz,
+                    permissions,
+                    offset: file.map_or(0, |(_, _, offset, _)| *offset),
+                    device: "00:00".into(),
+                    inode: 0,
+                    pathname: file.map(|(_, _, _, path)| path.clone()),
+                }
+            })
+            .collect();
+        Ok(core)
+    }
+
+    /// Fill `buf` with the memory at `address`, which may span several segments.
+    pub fn read_memory(&self, address: u64, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let address = address + done as u64;
+            let Some((index, segment)) = self
+                .segments
+                .iter()
+                .enumerate()
+                .find(|(_, segment)| (segment.vaddr..segment.vaddr + segment.memsz).contains(&address))
+            else {
+                bail!("{:#x} isn't mapped in the core file", address);
+            };
+            let in_segment = address - segment.vaddr;
+            let chunk = &mut buf[done..];
+            let len = if in_segment < segment.filesz {
+                let len = chunk.len().min((segment.filesz - in_segment) as usize);
+                let offset = segment.offset.checked_add(in_segment).ok_or_else(|| anyhow!("Segment offset overflows"))?;
+                self.file.read_exact_at(&mut chunk[..len], offset)?;
+                len
+            } else {
+                let len = chunk.len().min((segment.memsz - in_segment) as usize);
+                let map = &self.maps[index];
+                let Some(path) = &map.pathname else {
+                    bail!("{:#x} wasn't saved in the core file", address);
+                };
+                let offset = map.offset.checked_add(in_segment).ok_or_else(|| anyhow!("File offset overflows"))?;
+                read_file_at(path, offset, &mut chunk[..len])
+                    .map_err(|e| anyhow!("{:#x} isn't in the core file, and reading it from {} failed: {}", address, path, e))?;
+                len
+            };
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+/// Read from a mapped file, with the part past its end reading as zeros the way it is mapped
+fn read_file_at(path: &str, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let file = File::open(path)?;
+    let mut done = 0;
+    while done < buf.len() {
+        match file.read_at(&mut buf[done..], offset + done as u64)? {
+            0 => {
+                buf[done..].fill(0);
+                break;
+            }
+            n => done += n,
+        }
+    }
+    Ok(())
+}
+
+/// The (type, description) of each note named "CORE", stopping at the first truncated one
+fn core_notes(notes: &[u8]) -> impl Iterator<Item = (u32, &[u8])> {
+    let mut offset = 0;
+    std::iter::from_fn(move || loop {
+        let (namesz, descsz, kind) = (u32_at(notes, offset)?, u32_at(notes, offset + 4)?, u32_at(notes, offset + 8)?);
+        let name_start = offset + 12;
+        let desc_start = name_start.checked_add((namesz as usize).next_multiple_of(4))?;
+        let desc = notes.get(desc_start..desc_start.checked_add(descsz as usize)?)?;
+        offset = desc_start + (descsz as usize).next_multiple_of(4);
+        if notes.get(name_start..name_start + 4) == Some(b"CORE") {
+            return Some((kind, desc));
+        }
+    })
+}
+
+/// A thread's id and registers from an `NT_PRSTATUS` note. Its floating point registers are in
+/// the `NT_FPREGSET` note that follows.
+fn parse_prstatus(desc: &[u8]) -> Result<CoreThread> {
+    let pid = u32_at(desc, PRSTATUS_PID).ok_or_else(|| anyhow!("Truncated NT_PRSTATUS"))?;
+    let regs = desc.get(PRSTATUS_REGS..).and_then(from_bytes).ok_or_else(|| anyhow!("Truncated NT_PRSTATUS"))?;
+    Ok(CoreThread { pid, regs, fp_regs: None })
+}
+
+/// (start, end, file offset in bytes, path) of each file mapping in an `NT_FILE` note
+fn parse_file_note(desc: &[u8]) -> Vec<(u64, u64, u64, String)> {
+    let count = u64_at(desc, 0).unwrap_or(0) as usize;
+    let page_size = u64_at(desc, 8).unwrap_or(PAGE_SIZE);
+    let mut names = desc.get(16 + count.saturating_mul(24)..).unwrap_or_default().split(|&b| b == 0);
+    (0..count)
+        .map_while(|i| {
+            let entry = 16 + i * 24;
+            Some((
+                u64_at(desc, entry)?,
+                u64_at(desc, entry + 8)?,
+                u64_at(desc, entry + 16)?.checked_mul(page_size)?,
+                String::from_utf8_lossy(names.next()?).to_string(),
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(start: u64, end: u64, offset: u64, pathname: Option<&str>) -> MemoryMap {
+        MemoryMap {
+            start,
+            end,
+            permissions: "r-xp".into(),
+            offset,
+            device: "00:00".into(),
+            inode: 0,
+            pathname: pathname.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn notes_parse_back() {
+        let mut regs: user_regs_struct = unsafe { mem::zeroed() };
+        regs.rip = 0x401000;
+        regs.rsp = 0x7ffc0000;
+        regs.r15 = u64::MAX;
+        let threads = [
+            CoreThread { pid: 100, regs, fp_regs: None },
+            CoreThread { pid: 101, regs: unsafe { mem::zeroed() }, fp_regs: None },
+        ];
+        let maps = [
+            map(0x400000, 0x401000, 0, Some("/bin/a")),
+            map(0x401000, 0x403000, 0x3000, Some("/bin/a")),
+            map(0x500000, 0x501000, 0, Some("[heap]")),
+            map(0x600000, 0x601000, 0, None),
+            map(0x7f0000, 0x7f1000, 0x1000, Some("/lib/b with spaces.so")),
+        ];
+
+        let mut notes = Vec::new();
+        push_note(&mut notes, NT_PRSTATUS, &prstatus(&threads[0], ('S', 1, 2, 3)));
+        push_note(&mut notes, NT_FILE, &file_note(&maps));
+        push_note(&mut notes, NT_AUXV, &[1, 2, 3]);
+        push_note(&mut notes, NT_PRSTATUS, &prstatus(&threads[1], ('S', 1, 2, 3)));
+
+        let parsed = core_notes(&notes).collect::<Vec<_>>();
+        assert_eq!(parsed.iter().map(|(kind, _)| *kind).collect::<Vec<_>>(), [NT_PRSTATUS, NT_FILE, NT_AUXV, NT_PRSTATUS]);
+        assert_eq!(parsed[2].1, [1, 2, 3]);
+
+        for (thread, (_, desc)) in threads.iter().zip(parsed.iter().filter(|(kind, _)| *kind == NT_PRSTATUS)) {
+            let parsed = parse_prstatus(desc).unwrap();
+            assert_eq!(parsed.pid, thread.pid);
+            assert_eq!(as_bytes(&parsed.regs), as_bytes(&thread.regs));
+        }
+
+        assert_eq!(
+            parse_file_note(parsed[1].1),
+            [
+                (0x400000, 0x401000, 0, "/bin/a".to_string()),
+                (0x401000, 0x403000, 0x3000, "/bin/a".to_string()),
+                (0x7f0000, 0x7f1000, 0x1000, "/lib/b with spaces.so".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_notes_are_rejected() {
+        let mut notes = Vec::new();
+        push_note(&mut notes, NT_PRSTATUS, &[0; PRSTATUS_REGS]);
+        let (kind, desc) = core_notes(&notes).next().unwrap();
+        assert_eq!(kind, NT_PRSTATUS);
+        assert!(parse_prstatus(desc).is_err());
+
+        let mut notes = Vec::new();
+        push_note(&mut notes, NT_FILE, &file_note(&[map(0x400000, 0x401000, 0, Some("/bin/a"))]));
+        assert!(core_notes(&notes[..notes.len() - 8]).next().is_none());
+        let (_, desc) = core_notes(&notes).next().unwrap();
+        assert!(parse_file_note(&desc[..desc.len() - 8]).is_empty());
+    }
+}
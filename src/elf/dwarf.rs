@@ -0,0 +1,781 @@
+//! DWARF debug information and call frame information of ELF files.
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{bail, Result};
+use gimli::{AttributeValue, EvaluationResult, Location, Piece, Reader as _, UnitOffset};
+
+use super::ElfFile;
+use crate::{registers::Registers, target::Target};
+
+type Slice = gimli::EndianArcSlice<gimli::LittleEndian>;
+
+fn slice(data: Vec<u8>) -> Slice {
+    Slice::new(Arc::from(data), gimli::LittleEndian)
+}
+
+/// Value of a DWARF register (in the x86_64 numbering) in a thread's register set
+pub fn register_value(regs: &Registers, register: gimli::Register) -> Option<u64> {
+    let value = match register {
+        gimli::X86_64::RAX => regs.rax,
+        gimli::X86_64::RDX => regs.rdx,
+        gimli::X86_64::RCX => regs.rcx,
+        gimli::X86_64::RBX => regs.rbx,
+        gimli::X86_64::RSI => regs.rsi,
+        gimli::X86_64::RDI => regs.rdi,
+        gimli::X86_64::RBP => regs.rbp,
+        gimli::X86_64::RSP => regs.rsp,
+        gimli::X86_64::R8 => regs.r8,
+        gimli::X86_64::R9 => regs.r9,
+        gimli::X86_64::R10 => regs.r10,
+        gimli::X86_64::R11 => regs.r11,
+        gimli::X86_64::R12 => regs.r12,
+        gimli::X86_64::R13 => regs.r13,
+        gimli::X86_64::R14 => regs.r14,
+        gimli::X86_64::R15 => regs.r15,
+        gimli::X86_64::RA => regs.rip,
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn set_register_value(regs: &mut Registers, register: gimli::Register, value: u64) {
+    let slot = match register {
+        gimli::X86_64::RAX => &mut regs.rax,
+        gimli::X86_64::RDX => &mut regs.rdx,
+        gimli::X86_64::RCX => &mut regs.rcx,
+        gimli::X86_64::RBX => &mut regs.rbx,
+        gimli::X86_64::RSI => &mut regs.rsi,
+        gimli::X86_64::RDI => &mut regs.rdi,
+        gimli::X86_64::RBP => &mut regs.rbp,
+        gimli::X86_64::RSP => &mut regs.rsp,
+        gimli::X86_64::R8 => &mut regs.r8,
+        gimli::X86_64::R9 => &mut regs.r9,
+        gimli::X86_64::R10 => &mut regs.r10,
+        gimli::X86_64::R11 => &mut regs.r11,
+        gimli::X86_64::R12 => &mut regs.r12,
+        gimli::X86_64::R13 => &mut regs.r13,
+        gimli::X86_64::R14 => &mut regs.r14,
+        gimli::X86_64::R15 => &mut regs.r15,
+        gimli::X86_64::RA => &mut regs.rip,
+        _ => return,
+    };
+    *slot = value;
+}
+
+/// Evaluate a CFI expression, which only ever needs registers and memory
+fn evaluate_cfi_expression(
+    expression: gimli::Expression<Slice>,
+    regs: &Registers,
+    target: &dyn Target,
+    cfa: Option<u64>,
+) -> Result<u64> {
+    let encoding = gimli::Encoding {
+        address_size: 8,
+        format: gimli::Format::Dwarf32,
+        version: 4,
+    };
+    let mut evaluation = expression.evaluation(encoding);
+    if let Some(cfa) = cfa {
+        evaluation.set_initial_value(cfa);
+    }
+    let mut result = evaluation.evaluate()?;
+    loop {
+        result = match result {
+            EvaluationResult::Complete => break,
+            EvaluationResult::RequiresMemory { address, .. } => {
+                evaluation.resume_with_memory(gimli::Value::Generic(target.read::<u64>(address)?))?
+            }
+            EvaluationResult::RequiresRegister { register, .. } => {
+                let value = register_value(regs, register).ok_or(anyhow::anyhow!("Unsupported register {}", register.0))?;
+                evaluation.resume_with_register(gimli::Value::Generic(value))?
+            }
+            _ => bail!("Unsupported CFI expression"),
+        };
+    }
+    match evaluation.result().first().map(|piece| &piece.location) {
+        Some(Location::Address { address }) => Ok(*address),
+        Some(Location::Value { value }) => Ok(value.to_u64(u64::MAX)?),
+        _ => bail!("Unsupported CFI expression result"),
+    }
+}
+
+/// The result of applying a frame's unwinding rules.
+pub struct Unwound {
+    /// Canonical frame address: the stack pointer before the call that created the frame
+    pub cfa: u64,
+    /// Registers of the calling frame, or `None` for the outermost frame
+    pub caller: Option<Registers>,
+}
+
+/// `.eh_frame` and `.debug_frame` of an ELF file, used to find the canonical frame address (CFA)
+/// and the saved registers of a frame.
+pub struct CallFrameInfo {
+    eh_frame: Option<(gimli::EhFrame<Slice>, gimli::BaseAddresses)>,
+    debug_frame: Option<gimli::DebugFrame<Slice>>,
+}
+
+impl CallFrameInfo {
+    pub fn load(elf: &ElfFile) -> Self {
+        let eh_frame = elf.section(".eh_frame").zip(elf.section_data(".eh_frame")).map(|(section, data)| {
+            let mut bases = gimli::BaseAddresses::default().set_eh_frame(section.addr);
+            if let Some(text) = elf.section(".text") {
+                bases = bases.set_text(text.addr);
+            }
+            if let Some(got) = elf.section(".got") {
+                bases = bases.set_got(got.addr);
+            }
+            (gimli::EhFrame::from(slice(data)), bases)
+        });
+        let debug_frame = elf.section_data(".debug_frame").map(|data| gimli::DebugFrame::from(slice(data)));
+        Self { eh_frame, debug_frame }
+    }
+
+    /// Apply the unwinding rules in effect at an unrelocated address to the registers of a frame
+    pub fn unwind(&self, vaddr: u64, regs: &Registers, target: &dyn Target) -> Result<Unwound> {
+        if let Some((eh_frame, bases)) = &self.eh_frame {
+            match Self::unwind_with(eh_frame, bases, vaddr, regs, target) {
+                Err(e) if self.debug_frame.is_some() && e.is::<gimli::Error>() => {}
+                result => return result,
+            }
+        }
+        match &self.debug_frame {
+            Some(debug_frame) => Self::unwind_with(debug_frame, &gimli::BaseAddresses::default(), vaddr, regs, target),
+            None => bail!("No call frame information"),
+        }
+    }
+
+    fn unwind_with<S: gimli::UnwindSection<Slice>>(
+        section: &S,
+        bases: &gimli::BaseAddresses,
+        vaddr: u64,
+        regs: &Registers,
+        target: &dyn Target,
+    ) -> Result<Unwound> {
+        let mut context = Box::new(gimli::UnwindContext::new());
+        let row = section.unwind_info_for_address(bases, &mut context, vaddr, S::cie_from_offset)?;
+        let cfa = match row.cfa() {
+            gimli::CfaRule::RegisterAndOffset { register, offset } => register_value(regs, *register)
+                .ok_or(anyhow::anyhow!("Unsupported register {}", register.0))?
+                .wrapping_add_signed(*offset),
+            gimli::CfaRule::Expression(expression) => {
+                evaluate_cfi_expression(expression.get(section)?, regs, target, None)?
+            }
+        };
+        // The return address is only undefined in the outermost frame (e.g. `_start`)
+        if let gimli::RegisterRule::Undefined = row.register(gimli::X86_64::RA) {
+            return Ok(Unwound { cfa, caller: None });
+        }
+        // Registers without a rule keep their value, as callee-saved registers that were never touched
+        let mut caller = regs.clone();
+        caller.rsp = cfa;
+        for (register, rule) in row.registers() {
+            let value = match rule {
+                gimli::RegisterRule::Offset(offset) => target.read::<u64>(cfa.wrapping_add_signed(*offset))?,
+                gimli::RegisterRule::ValOffset(offset) => cfa.wrapping_add_signed(*offset),
+                gimli::RegisterRule::Register(other) => match register_value(regs, *other) {
+                    Some(value) => value,
+                    None => continue,
+                },
+                gimli::RegisterRule::Expression(expression) => {
+                    let address = evaluate_cfi_expression(expression.get(section)?, regs, target, Some(cfa))?;
+                    target.read::<u64>(address)?
+                }
+                gimli::RegisterRule::ValExpression(expression) => {
+                    evaluate_cfi_expression(expression.get(section)?, regs, target, Some(cfa))?
+                }
+                gimli::RegisterRule::Constant(value) => *value,
+                _ => continue,
+            };
+            set_register_value(&mut caller, *register, value);
+        }
+        Ok(Unwound {
+            cfa,
+            caller: Some(caller),
+        })
+    }
+}
+
+/// What a thread is doing, as needed to evaluate DWARF location expressions.
+pub struct Frame<'a> {
+    pub target: &'a dyn Target,
+    pub regs: &'a Registers,
+    /// Load bias of the module the debug information belongs to
+    pub bias: u64,
+    pub cfi: &'a CallFrameInfo,
+}
+
+impl Frame<'_> {
+    fn register(&self, register: gimli::Register) -> Result<u64> {
+        register_value(self.regs, register).ok_or(anyhow::anyhow!("Unsupported register {}", register.0))
+    }
+
+    fn cfa(&self) -> Result<u64> {
+        let vaddr = self.regs.rip.wrapping_sub(self.bias);
+        Ok(self.cfi.unwind(vaddr, self.regs, self.target)?.cfa)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TypeKind {
+    Signed,
+    Unsigned,
+    Float,
+    Bool,
+    Pointer,
+    /// Structures, unions, arrays and anything else that is not a single value
+    Aggregate,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TypeInfo {
+    pub kind: TypeKind,
+    pub size: u64,
+}
+
+/// A variable's contents, read from the target.
+pub struct Variable {
+    pub bytes: Vec<u8>,
+    pub ty: TypeInfo,
+}
+
+enum VariableLocation {
+    /// A location expression, or a reference to a location list
+    Expression(AttributeValue<Slice>),
+    Constant(AttributeValue<Slice>),
+}
+
+/// A variable's debug information entry, with what is needed to evaluate its location.
+struct VariableEntry {
+    unit: usize,
+    location: VariableLocation,
+    ty: Option<UnitOffset>,
+    frame_base: Option<AttributeValue<Slice>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LineInfo {
+    pub file: String,
+    pub line: u64,
+    pub column: u64,
+}
+
+struct LineRow {
+    address: u64,
+    file: usize,
+    line: u64,
+    column: u64,
+    is_stmt: bool,
+}
+
+/// A contiguous run of machine code covered by a line table.
+struct Sequence {
+    start: u64,
+    end: u64,
+    rows: Vec<LineRow>,
+}
+
+/// Parsed DWARF of an ELF file. All addresses are unrelocated.
+pub struct DebugInfo {
+    dwarf: gimli::Dwarf<Slice>,
+    units: Vec<gimli::Unit<Slice>>,
+    files: Vec<String>,
+    sequences: Vec<Sequence>,
+}
+
+impl DebugInfo {
+    /// Load the DWARF sections of an ELF file, or of its separate debug file installed under
+    /// `/usr/lib/debug/.build-id`. Returns `None` if neither has any.
+    pub fn load(elf: &ElfFile) -> Result<Option<Self>> {
+        if elf.section(".debug_info").is_some() {
+            return Self::parse(elf).map(Some);
+        }
+        let Some(build_id) = elf.build_id().filter(|id| id.len() > 1) else {
+            return Ok(None);
+        };
+        let hex = build_id.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        let path = format!("/usr/lib/debug/.build-id/{}/{}.debug", &hex[..2], &hex[2..]);
+        let Ok(data) = std::fs::read(&path) else {
+            return Ok(None);
+        };
+        let debug = ElfFile::parse(data)?;
+        if debug.section(".debug_info").is_none() {
+            return Ok(None);
+        }
+        Self::parse(&debug).map(Some)
+    }
+
+    fn parse(elf: &ElfFile) -> Result<Self> {
+        let dwarf = gimli::Dwarf::load(|id| -> Result<Slice, gimli::Error> {
+            Ok(slice(elf.section_data(id.name()).unwrap_or_default()))
+        })?;
+        let mut units = Vec::new();
+        let mut headers = dwarf.units();
+        while let Some(header) = headers.next()? {
+            units.push(dwarf.unit(header)?);
+        }
+        let mut info = Self {
+            dwarf,
+            units,
+            files: Vec::new(),
+            sequences: Vec::new(),
+        };
+        info.parse_line_tables()?;
+        Ok(info)
+    }
+
+    fn parse_line_tables(&mut self) -> Result<()> {
+        let mut file_indices = HashMap::new();
+        for unit in &self.units {
+            let Some(program) = unit.line_program.clone() else {
+                continue;
+            };
+            // Line tables number their files per unit
+            let mut unit_files = HashMap::new();
+            let mut rows = program.rows();
+            let mut current: Vec<LineRow> = Vec::new();
+            while let Some((header, row)) = rows.next_row()? {
+                if row.end_sequence() {
+                    if let Some(first) = current.first() {
+                        let start = first.address;
+                        self.sequences.push(Sequence {
+                            start,
+                            end: row.address(),
+                            rows: std::mem::take(&mut current),
+                        });
+                    }
+                    continue;
+                }
+                let file = match unit_files.get(&row.file_index()) {
+                    Some(&file) => file,
+                    None => {
+                        let path = row
+                            .file(header)
+                            .map(|file| self.file_path(unit, header, file))
+                            .unwrap_or_else(|| "??".to_string());
+                        let next = self.files.len();
+                        let file = *file_indices.entry(path.clone()).or_insert(next);
+                        if file == next {
+                            self.files.push(path);
+                        }
+                        unit_files.insert(row.file_index(), file);
+                        file
+                    }
+                };
+                current.push(LineRow {
+                    address: row.address(),
+                    file,
+                    line: row.line().map(|line| line.get()).unwrap_or(0),
+                    column: match row.column() {
+                        gimli::ColumnType::LeftEdge => 0,
+                        gimli::ColumnType::Column(column) => column.get(),
+                    },
+                    is_stmt: row.is_stmt(),
+                });
+            }
+        }
+        self.sequences.sort_by_key(|sequence| sequence.start);
+        Ok(())
+    }
+
+    fn attr_string(&self, unit: &gimli::Unit<Slice>, attr: AttributeValue<Slice>) -> Option<String> {
+        let string = self.dwarf.attr_string(unit, attr).ok()?;
+        Some(string.to_string_lossy().ok()?.into_owned())
+    }
+
+    fn file_path(
+        &self,
+        unit: &gimli::Unit<Slice>,
+        header: &gimli::LineProgramHeader<Slice>,
+        file: &gimli::FileEntry<Slice>,
+    ) -> String {
+        let name = self.attr_string(unit, file.path_name()).unwrap_or_default();
+        if name.starts_with('/') {
+            return name;
+        }
+        let mut path = String::new();
+        let directory = file.directory(header).and_then(|dir| self.attr_string(unit, dir)).unwrap_or_default();
+        if !directory.starts_with('/') {
+            if let Some(comp_dir) = unit.comp_dir.as_ref().and_then(|dir| dir.to_string_lossy().ok()) {
+                path.push_str(&comp_dir);
+                path.push('/');
+            }
+        }
+        if !directory.is_empty() {
+            path.push_str(&directory);
+            path.push('/');
+        }
+        path.push_str(&name);
+        path
+    }
+
+    /// Source location of an address
+    pub fn line(&self, vaddr: u64) -> Option<LineInfo> {
+        let index = self.sequences.partition_point(|sequence| sequence.start <= vaddr).checked_sub(1)?;
+        let sequence = &self.sequences[index];
+        if vaddr >= sequence.end {
+            return None;
+        }
+        let row = &sequence.rows[sequence.rows.partition_point(|row| row.address <= vaddr).checked_sub(1)?];
+        Some(LineInfo {
+            file: self.files[row.file].clone(),
+            line: row.line,
+            column: row.column,
+        })
+    }
+
+    /// Addresses of the statements on a source line, where `file` is either a full path or a
+    /// trailing part of one (e.g. `main.cc`). Lines without code resolve to the next line that has some,
+    /// which is returned along with the addresses (one per sequence, since a line can be emitted more than once).
+    pub fn line_addresses(&self, file: &str, line: u64) -> Option<(u64, Vec<u64>)> {
+        let suffix = format!("/{}", file.trim_start_matches('/'));
+        let matches = |index: usize| self.files[index] == file || self.files[index].ends_with(&suffix);
+        let rows = self
+            .sequences
+            .iter()
+            .flat_map(|sequence| sequence.rows.iter().map(move |row| (sequence.start, row)))
+            .filter(|(_, row)| row.is_stmt && row.line >= line && matches(row.file));
+        let line = rows.clone().map(|(_, row)| row.line).min()?;
+        let mut addresses: Vec<(u64, u64)> = Vec::new();
+        for (sequence, row) in rows.filter(|(_, row)| row.line == line) {
+            match addresses.iter_mut().find(|(start, _)| *start == sequence) {
+                Some((_, address)) => *address = (*address).min(row.address),
+                None => addresses.push((sequence, row.address)),
+            }
+        }
+        let mut addresses = addresses.into_iter().map(|(_, address)| address).collect::<Vec<_>>();
+        addresses.sort();
+        Some((line, addresses))
+    }
+
+    /// An attribute of an entry, or of the entry it completes (inlined and out-of-line definitions only
+    /// carry what differs from their abstract origin or declaration).
+    fn attr(
+        &self,
+        unit: &gimli::Unit<Slice>,
+        entry: &gimli::DebuggingInformationEntry<Slice>,
+        name: gimli::DwAt,
+    ) -> Option<AttributeValue<Slice>> {
+        if let Some(value) = entry.attr_value(name).ok()? {
+            return Some(value);
+        }
+        for origin in [gimli::DW_AT_abstract_origin, gimli::DW_AT_specification] {
+            if let Some(AttributeValue::UnitRef(offset)) = entry.attr_value(origin).ok()? {
+                let origin = unit.entry(offset).ok()?;
+                return self.attr(unit, &origin, name);
+            }
+        }
+        None
+    }
+
+    fn name(&self, unit: &gimli::Unit<Slice>, entry: &gimli::DebuggingInformationEntry<Slice>) -> Option<String> {
+        self.attr_string(unit, self.attr(unit, entry, gimli::DW_AT_name)?)
+    }
+
+    fn contains(
+        &self,
+        unit: &gimli::Unit<Slice>,
+        entry: &gimli::DebuggingInformationEntry<Slice>,
+        vaddr: u64,
+    ) -> Result<bool> {
+        let mut ranges = self.dwarf.die_ranges(unit, entry)?;
+        while let Some(range) = ranges.next()? {
+            if range.begin <= vaddr && vaddr < range.end {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Look for a variable among the children of `node`, descending into the scopes that contain `pc`
+    /// first so that the innermost declaration wins.
+    fn find_in_scope(
+        &self,
+        index: usize,
+        node: gimli::EntriesTreeNode<Slice>,
+        pc: Option<u64>,
+        name: &str,
+        frame_base: Option<AttributeValue<Slice>>,
+    ) -> Result<Option<VariableEntry>> {
+        let unit = &self.units[index];
+        let mut found = None;
+        let mut children = node.children();
+        while let Some(child) = children.next()? {
+            let entry = child.entry();
+            match entry.tag() {
+                gimli::DW_TAG_variable | gimli::DW_TAG_formal_parameter if found.is_none() => {
+                    // Variables that were folded into a constant have a value instead of a location
+                    let location = match entry.attr_value(gimli::DW_AT_location)? {
+                        Some(location) => VariableLocation::Expression(location),
+                        None => match entry.attr_value(gimli::DW_AT_const_value)? {
+                            Some(value) => VariableLocation::Constant(value),
+                            None => continue,
+                        },
+                    };
+                    if self.name(unit, entry).as_deref() == Some(name) {
+                        let ty = match self.attr(unit, entry, gimli::DW_AT_type) {
+                            Some(AttributeValue::UnitRef(offset)) => Some(offset),
+                            _ => None,
+                        };
+                        found = Some(VariableEntry {
+                            unit: index,
+                            location,
+                            ty,
+                            frame_base: frame_base.clone(),
+                        });
+                    }
+                }
+                gimli::DW_TAG_namespace => {
+                    if let Some(variable) = self.find_in_scope(index, child, pc, name, frame_base.clone())? {
+                        return Ok(Some(variable));
+                    }
+                }
+                gimli::DW_TAG_subprogram | gimli::DW_TAG_lexical_block | gimli::DW_TAG_inlined_subroutine => {
+                    let Some(pc) = pc else {
+                        continue;
+                    };
+                    if !self.contains(unit, entry, pc)? {
+                        continue;
+                    }
+                    let frame_base = match entry.tag() {
+                        gimli::DW_TAG_subprogram => entry.attr_value(gimli::DW_AT_frame_base)?,
+                        _ => frame_base.clone(),
+                    };
+                    if let Some(variable) = self.find_in_scope(index, child, Some(pc), name, frame_base)? {
+                        return Ok(Some(variable));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(found)
+    }
+
+    /// Find a variable visible at `vaddr`: locals of the enclosing scopes first, then globals.
+    fn find_variable(&self, vaddr: u64, name: &str) -> Result<Option<VariableEntry>> {
+        for (index, unit) in self.units.iter().enumerate() {
+            let mut ranges = self.dwarf.unit_ranges(unit)?;
+            let mut contains = false;
+            while let Some(range) = ranges.next()? {
+                contains |= range.begin <= vaddr && vaddr < range.end;
+            }
+            if !contains {
+                continue;
+            }
+            let mut tree = unit.entries_tree(None)?;
+            if let Some(variable) = self.find_in_scope(index, tree.root()?, Some(vaddr), name, None)? {
+                return Ok(Some(variable));
+            }
+        }
+        for (index, unit) in self.units.iter().enumerate() {
+            let mut tree = unit.entries_tree(None)?;
+            if let Some(variable) = self.find_in_scope(index, tree.root()?, None, name, None)? {
+                return Ok(Some(variable));
+            }
+        }
+        Ok(None)
+    }
+
+    fn resolve_type(&self, unit: &gimli::Unit<Slice>, offset: UnitOffset) -> Result<TypeInfo> {
+        let mut offset = offset;
+        // Follow typedefs and qualifiers, bounded in case of malformed cycles
+        for _ in 0..32 {
+            let entry = unit.entry(offset)?;
+            let size = entry.attr_value(gimli::DW_AT_byte_size)?.and_then(|size| size.udata_value());
+            let kind = match entry.tag() {
+                gimli::DW_TAG_typedef
+                | gimli::DW_TAG_const_type
+                | gimli::DW_TAG_volatile_type
+                | gimli::DW_TAG_restrict_type
+                | gimli::DW_TAG_atomic_type => match entry.attr_value(gimli::DW_AT_type)? {
+                    Some(AttributeValue::UnitRef(next)) => {
+                        offset = next;
+                        continue;
+                    }
+                    _ => bail!("Variable has type void"),
+                },
+                gimli::DW_TAG_base_type => match entry.attr_value(gimli::DW_AT_encoding)? {
+                    Some(AttributeValue::Encoding(gimli::DW_ATE_signed | gimli::DW_ATE_signed_char)) => TypeKind::Signed,
+                    Some(AttributeValue::Encoding(gimli::DW_ATE_float)) => TypeKind::Float,
+                    Some(AttributeValue::Encoding(gimli::DW_ATE_boolean)) => TypeKind::Bool,
+                    _ => TypeKind::Unsigned,
+                },
+                gimli::DW_TAG_pointer_type
+                | gimli::DW_TAG_reference_type
+                | gimli::DW_TAG_rvalue_reference_type
+                | gimli::DW_TAG_ptr_to_member_type => {
+                    return Ok(TypeInfo {
+                        kind: TypeKind::Pointer,
+                        size: size.unwrap_or(8),
+                    })
+                }
+                gimli::DW_TAG_enumeration_type => TypeKind::Signed,
+                gimli::DW_TAG_array_type if size.is_none() => {
+                    return Ok(TypeInfo {
+                        kind: TypeKind::Aggregate,
+                        size: self.array_size(unit, offset)?,
+                    })
+                }
+                _ => TypeKind::Aggregate,
+            };
+            return Ok(TypeInfo {
+                kind,
+                size: size.unwrap_or(0),
+            });
+        }
+        bail!("Type chain too long")
+    }
+
+    /// Size of an array type without a byte size of its own: its element size times all dimensions
+    fn array_size(&self, unit: &gimli::Unit<Slice>, offset: UnitOffset) -> Result<u64> {
+        let entry = unit.entry(offset)?;
+        let element = match entry.attr_value(gimli::DW_AT_type)? {
+            Some(AttributeValue::UnitRef(element)) => self.resolve_type(unit, element)?.size,
+            _ => 0,
+        };
+        let mut size = element;
+        let mut tree = unit.entries_tree(Some(offset))?;
+        let mut children = tree.root()?.children();
+        while let Some(child) = children.next()? {
+            let entry = child.entry();
+            if entry.tag() != gimli::DW_TAG_subrange_type {
+                continue;
+            }
+            let count = match entry.attr_value(gimli::DW_AT_count)?.and_then(|count| count.udata_value()) {
+                Some(count) => count,
+                None => entry
+                    .attr_value(gimli::DW_AT_upper_bound)?
+                    .and_then(|bound| bound.udata_value())
+                    .map(|bound| bound + 1)
+                    .unwrap_or(0),
+            };
+            size *= count;
+        }
+        Ok(size)
+    }
+
+    /// Run a DWARF expression against the state of a thread
+    fn evaluate(
+        &self,
+        unit: &gimli::Unit<Slice>,
+        expression: gimli::Expression<Slice>,
+        frame: &Frame,
+        frame_base: Option<&AttributeValue<Slice>>,
+    ) -> Result<Vec<Piece<Slice>>> {
+        let mut evaluation = expression.evaluation(unit.encoding());
+        let mut result = evaluation.evaluate()?;
+        loop {
+            result = match result {
+                EvaluationResult::Complete => return Ok(evaluation.result()),
+                EvaluationResult::RequiresMemory { address, size, .. } => {
+                    let bytes = frame.target.read_bytes(address, size as _)?;
+                    let mut value = [0u8; 8];
+                    value[..bytes.len().min(8)].copy_from_slice(&bytes[..bytes.len().min(8)]);
+                    evaluation.resume_with_memory(gimli::Value::Generic(u64::from_le_bytes(value)))?
+                }
+                EvaluationResult::RequiresRegister { register, .. } => {
+                    evaluation.resume_with_register(gimli::Value::Generic(frame.register(register)?))?
+                }
+                EvaluationResult::RequiresFrameBase => {
+                    let Some(AttributeValue::Exprloc(expression)) = frame_base else {
+                        bail!("Variable needs a frame base, but its function has none");
+                    };
+                    let base = match self.evaluate(unit, expression.clone(), frame, None)?.first() {
+                        Some(Piece {
+                            location: Location::Address { address },
+                            ..
+                        }) => *address,
+                        Some(Piece {
+                            location: Location::Register { register },
+                            ..
+                        }) => frame.register(*register)?,
+                        _ => bail!("Unsupported frame base"),
+                    };
+                    evaluation.resume_with_frame_base(base)?
+                }
+                EvaluationResult::RequiresCallFrameCfa => evaluation.resume_with_call_frame_cfa(frame.cfa()?)?,
+                EvaluationResult::RequiresRelocatedAddress(address) => {
+                    evaluation.resume_with_relocated_address(address.wrapping_add(frame.bias))?
+                }
+                EvaluationResult::RequiresIndexedAddress { index, relocate } => {
+                    let address = self.dwarf.address(unit, index)?;
+                    let address = if relocate { address.wrapping_add(frame.bias) } else { address };
+                    evaluation.resume_with_indexed_address(address)?
+                }
+                EvaluationResult::RequiresBaseType(offset) => {
+                    let entry = unit.entry(offset)?;
+                    let value_type = gimli::ValueType::from_entry(&entry)?.unwrap_or(gimli::ValueType::Generic);
+                    evaluation.resume_with_base_type(value_type)?
+                }
+                EvaluationResult::RequiresTls(_) => bail!("Thread-local variables are not supported"),
+                _ => bail!("Unsupported DWARF expression"),
+            };
+        }
+    }
+
+    /// Read a variable's current contents, as seen from the innermost frame of a thread
+    pub fn read_variable(&self, frame: &Frame, name: &str) -> Result<Option<Variable>> {
+        let vaddr = frame.regs.rip.wrapping_sub(frame.bias);
+        let Some(variable) = self.find_variable(vaddr, name)? else {
+            return Ok(None);
+        };
+        let unit = &self.units[variable.unit];
+        let Some(ty) = variable.ty else {
+            bail!("Variable {} has no type", name);
+        };
+        let ty = self.resolve_type(unit, ty)?;
+
+        let expression = match variable.location {
+            VariableLocation::Constant(value) => {
+                let mut bytes = match value {
+                    AttributeValue::Block(block) => block.to_slice()?.to_vec(),
+                    value => match value.sdata_value().or(value.udata_value().map(|value| value as i64)) {
+                        Some(value) => value.to_le_bytes().to_vec(),
+                        None => bail!("Unsupported constant value for {}", name),
+                    },
+                };
+                bytes.resize(ty.size as usize, 0);
+                return Ok(Some(Variable { bytes, ty }));
+            }
+            VariableLocation::Expression(AttributeValue::Exprloc(expression)) => expression,
+            VariableLocation::Expression(location) => {
+                let mut locations =
+                    self.dwarf.attr_locations(unit, location)?.ok_or(anyhow::anyhow!("Bad location attribute"))?;
+                let mut found = None;
+                while let Some(entry) = locations.next()? {
+                    if entry.range.begin <= vaddr && vaddr < entry.range.end {
+                        found = Some(entry.data);
+                        break;
+                    }
+                }
+                found.ok_or(anyhow::anyhow!("{} is optimized out here", name))?
+            }
+        };
+        let pieces = self.evaluate(unit, expression, frame, variable.frame_base.as_ref())?;
+        // Usually a single piece without a size, holding the whole variable
+        let mut bytes = Vec::new();
+        for piece in &pieces {
+            let size = piece.size_in_bits.map(|bits| bits.div_ceil(8)).unwrap_or(ty.size);
+            bytes.extend(self.piece_bytes(&piece.location, size, frame)?);
+        }
+        bytes.resize(ty.size as usize, 0);
+        Ok(Some(Variable { bytes, ty }))
+    }
+
+    fn piece_bytes(&self, location: &Location<Slice>, size: u64, frame: &Frame) -> Result<Vec<u8>> {
+        let mut bytes = match location {
+            Location::Empty => bail!("Variable is optimized out"),
+            Location::Address { address } => return frame.target.read_bytes(*address, size as _),
+            Location::Register { register } => frame.register(*register)?.to_le_bytes().to_vec(),
+            Location::Value { value } => match *value {
+                gimli::Value::F32(value) => value.to_le_bytes().to_vec(),
+                gimli::Value::F64(value) => value.to_le_bytes().to_vec(),
+                value => value.to_u64(u64::MAX)?.to_le_bytes().to_vec(),
+            },
+            Location::Bytes { value } => value.to_slice()?.to_vec(),
+            Location::ImplicitPointer { .. } => bail!("Implicit pointers are not supported"),
+        };
+        bytes.resize(size as usize, 0);
+        Ok(bytes)
+    }
+}
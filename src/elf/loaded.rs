@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock},
+};
+
+use anyhow::Result;
+use log::debug;
+
+use super::{
+    dwarf::{CallFrameInfo, DebugInfo, Frame, Variable},
+    ElfFile, Header, Segment, PT_LOAD,
+};
+use crate::{module::Module, registers::Registers, target::Target};
+
+/// Program headers of the ELF image mapped at the module's base in the target, along with its load
+/// bias (the difference between the addresses it was linked at and where it was actually loaded).
+pub fn loaded_segments(target: &dyn Target, module: &Module) -> Result<(Header, Vec<Segment>, u64)> {
+    let base = module.base;
+    let ehdr = target.read_bytes(base, 64)?;
+    let header = Header::parse(&ehdr)?;
+    // The header comes from target memory, so it can be anything
+    let phdrs_end = (header.phnum as u64)
+        .checked_mul(header.phentsize as u64)
+        .and_then(|size| size.checked_add(header.phoff))
+        .filter(|end| base.checked_add(*end).is_some_and(|end| end <= module.end))
+        .ok_or_else(|| anyhow::anyhow!("Program headers of {} run past the end of the module", module.name))?;
+    let mut data = target.read_bytes(base, phdrs_end.max(64) as usize)?;
+    data[60..62].fill(0); // Section headers are never loaded
+    let elf = ElfFile::parse(data)?;
+
+    // The first loadable segment is mapped at the module base
+    let first = elf
+        .segments
+        .iter()
+        .find(|segment| segment.kind == PT_LOAD && segment.offset == 0)
+        .ok_or(anyhow::anyhow!("No loadable segment at offset 0"))?;
+    let bias = base.wrapping_sub(first.vaddr & !0xfff);
+    Ok((elf.header, elf.segments, bias))
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ElfSource {
+    Disk,
+    Memory,
+}
+
+/// A module's ELF file, along with where it was loaded.
+pub struct LoadedElf {
+    pub elf: ElfFile,
+    pub bias: u64,
+    pub source: ElfSource,
+    debug: OnceLock<Option<DebugInfo>>,
+    cfi: OnceLock<CallFrameInfo>,
+}
+
+impl LoadedElf {
+    /// Read a module's ELF file from disk if it is still there (and is the same build),
+    /// otherwise reconstruct what we can from the in-memory image.
+    pub fn load(target: &dyn Target, module: &Module) -> Result<Self> {
+        let (_, _, bias) = loaded_segments(target, module)?;
+        match Self::load_from_disk(target, module, bias) {
+            Ok(elf) => Ok(elf),
+            Err(e) => {
+                debug!("Reading {} from memory: {}", module.name, e);
+                Self::load_from_memory(target, module)
+            }
+        }
+    }
+
+    fn load_from_disk(target: &dyn Target, module: &Module, bias: u64) -> Result<Self> {
+        if module.path.ends_with(" (deleted)") {
+            return Err(anyhow::anyhow!("File was deleted"));
+        }
+        let elf = ElfFile::parse(std::fs::read(&module.path)?)?;
+        if let Some(loaded) = module.build_id(target) {
+            if elf.build_id().is_some_and(|on_disk| on_disk != loaded) {
+                return Err(anyhow::anyhow!("File on disk has a different build ID"));
+            }
+        }
+        Ok(Self {
+            elf,
+            bias,
+            source: ElfSource::Disk,
+            debug: OnceLock::new(),
+            cfi: OnceLock::new(),
+        })
+    }
+
+    /// Rebuild the file layout of an ELF image from its loaded segments.
+    /// Section headers (and so `.symtab`) are lost, but everything reachable from the dynamic section remains.
+    pub fn load_from_memory(target: &dyn Target, module: &Module) -> Result<Self> {
+        let (_, segments, bias) = loaded_segments(target, module)?;
+        let loads = segments.iter().filter(|segment| segment.kind == PT_LOAD);
+        let size = loads.clone().map(|segment| segment.offset + segment.filesz).max().unwrap_or(0);
+        let mut data = vec![0u8; size as usize];
+        for segment in loads {
+            let range = segment.offset as usize..(segment.offset + segment.filesz) as usize;
+            match target.read_bytes(bias.wrapping_add(segment.vaddr), range.len()) {
+                Ok(bytes) => data[range].copy_from_slice(&bytes),
+                Err(e) => debug!("Failed to read segment at {:#x}: {}", bias.wrapping_add(segment.vaddr), e),
+            }
+        }
+        data[60..62].fill(0);
+        Ok(Self {
+            elf: ElfFile::parse_with_bias(data, bias)?,
+            bias,
+            source: ElfSource::Memory,
+            debug: OnceLock::new(),
+            cfi: OnceLock::new(),
+        })
+    }
+
+    /// Relocate an address from the ELF file to where it is in the target
+    pub fn address(&self, vaddr: u64) -> u64 {
+        self.bias.wrapping_add(vaddr)
+    }
+
+    /// DWARF debug information, parsed on first use
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug
+            .get_or_init(|| {
+                DebugInfo::load(&self.elf).unwrap_or_else(|e| {
+                    debug!("Failed to parse debug information: {}", e);
+                    None
+                })
+            })
+            .as_ref()
+    }
+
+    pub fn call_frames(&self) -> &CallFrameInfo {
+        self.cfi.get_or_init(|| CallFrameInfo::load(&self.elf))
+    }
+
+    /// Read a variable by name, from the scope a thread is currently in (or globals)
+    pub fn read_variable(&self, target: &dyn Target, regs: &Registers, name: &str) -> Result<Option<Variable>> {
+        let Some(debug_info) = self.debug_info() else {
+            return Ok(None);
+        };
+        let frame = Frame {
+            target,
+            regs,
+            bias: self.bias,
+            cfi: self.call_frames(),
+        };
+        debug_info.read_variable(&frame, name)
+    }
+}
+
+/// Parsed ELF files of loaded modules, keyed by path and base address.
+#[derive(Default)]
+pub struct ElfCache {
+    entries: HashMap<(String, u64), Arc<LoadedElf>>,
+}
+
+impl ElfCache {
+    pub fn get(&mut self, target: &dyn Target, module: &Module) -> Result<Arc<LoadedElf>> {
+        let key = (module.path.clone(), module.base);
+        if let Some(elf) = self.entries.get(&key) {
+            return Ok(elf.clone());
+        }
+        let elf = Arc::new(LoadedElf::load(target, module)?);
+        self.entries.insert(key, elf.clone());
+        Ok(elf)
+    }
+}
@@ -0,0 +1,544 @@
+//! Minimal reader for 64-bit little-endian ELF files, as used on x86_64 Linux.
+
+use anyhow::Result;
+
+pub mod coredump;
+pub mod dwarf;
+pub mod loaded;
+
+pub use loaded::{ElfCache, LoadedElf};
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_NOTE: u32 = 4;
+
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_NOBITS: u32 = 8;
+pub const SHT_DYNSYM: u32 = 11;
+
+pub const SHN_ABS: u16 = 0xfff1;
+
+pub const SHF_COMPRESSED: u64 = 0x800;
+const ELFCOMPRESS_ZLIB: u32 = 1;
+
+pub const DT_NULL: i64 = 0;
+pub const DT_PLTRELSZ: i64 = 2;
+pub const DT_HASH: i64 = 4;
+pub const DT_STRTAB: i64 = 5;
+pub const DT_SYMTAB: i64 = 6;
+pub const DT_RELA: i64 = 7;
+pub const DT_RELASZ: i64 = 8;
+pub const DT_JMPREL: i64 = 23;
+pub const DT_GNU_HASH: i64 = 0x6ffffef5;
+
+pub const R_X86_64_GLOB_DAT: u32 = 6;
+pub const R_X86_64_JUMP_SLOT: u32 = 7;
+
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// Dynamic tags whose values are addresses, which the dynamic linker relocates in memory
+const DT_POINTERS: [i64; 6] = [DT_HASH, DT_STRTAB, DT_SYMTAB, DT_RELA, DT_JMPREL, DT_GNU_HASH];
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+fn str_at(data: &[u8], offset: usize) -> String {
+    let Some(bytes) = data.get(offset..) else {
+        return String::new();
+    };
+    let nul = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..nul]).to_string()
+}
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub kind: u16,
+    pub machine: u16,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+impl Header {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.get(..4) != Some(b"\x7fELF") {
+            return Err(anyhow::anyhow!("Not an ELF file"));
+        }
+        if data.get(4) != Some(&2) || data.get(5) != Some(&1) {
+            return Err(anyhow::anyhow!("Only 64-bit little-endian ELF files are supported"));
+        }
+        let truncated = || anyhow::anyhow!("Truncated ELF header");
+        Ok(Self {
+            kind: u16_at(data, 16).ok_or_else(truncated)?,
+            machine: u16_at(data, 18).ok_or_else(truncated)?,
+            entry: u64_at(data, 24).ok_or_else(truncated)?,
+            phoff: u64_at(data, 32).ok_or_else(truncated)?,
+            shoff: u64_at(data, 40).ok_or_else(truncated)?,
+            phentsize: u16_at(data, 54).ok_or_else(truncated)?,
+            phnum: u16_at(data, 56).ok_or_else(truncated)?,
+            shentsize: u16_at(data, 58).ok_or_else(truncated)?,
+            shnum: u16_at(data, 60).ok_or_else(truncated)?,
+            shstrndx: u16_at(data, 62).ok_or_else(truncated)?,
+        })
+    }
+}
+
+/// A program header
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SymbolSource {
+    Symtab,
+    Dynsym,
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    /// Unrelocated virtual address
+    pub value: u64,
+    pub size: u64,
+    pub kind: u8,
+    pub bind: u8,
+    pub shndx: u16,
+    pub source: SymbolSource,
+}
+
+impl Symbol {
+    pub fn is_defined(&self) -> bool {
+        self.shndx != 0
+    }
+
+    pub fn kind_name(&self) -> &'static str {
+        match self.kind {
+            0 => "notype",
+            1 => "object",
+            2 => "func",
+            3 => "section",
+            4 => "file",
+            6 => "tls",
+            10 => "ifunc",
+            _ => "unknown",
+        }
+    }
+
+    pub fn bind_name(&self) -> &'static str {
+        match self.bind {
+            0 => "local",
+            1 => "global",
+            2 => "weak",
+            10 => "unique",
+            _ => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    /// Unrelocated address of the location being relocated
+    pub offset: u64,
+    pub kind: u32,
+    pub symbol: Option<String>,
+    pub addend: i64,
+}
+
+impl Relocation {
+    pub fn kind_name(&self) -> String {
+        match self.kind {
+            0 => "NONE".into(),
+            1 => "64".into(),
+            2 => "PC32".into(),
+            5 => "COPY".into(),
+            R_X86_64_GLOB_DAT => "GLOB_DAT".into(),
+            R_X86_64_JUMP_SLOT => "JUMP_SLOT".into(),
+            8 => "RELATIVE".into(),
+            16 => "DTPMOD64".into(),
+            17 => "DTPOFF64".into(),
+            18 => "TPOFF64".into(),
+            37 => "IRELATIVE".into(),
+            kind => format!("{}", kind),
+        }
+    }
+}
+
+/// An imported function's GOT slot, and the PLT stub that jumps through it (if known).
+#[derive(Debug, Clone)]
+pub struct PltEntry {
+    pub symbol: String,
+    /// Unrelocated address of the GOT slot
+    pub got: u64,
+    /// Unrelocated address of the PLT stub. Only known when section headers are available.
+    pub plt: Option<u64>,
+}
+
+pub struct ElfFile {
+    pub data: Vec<u8>,
+    pub header: Header,
+    pub segments: Vec<Segment>,
+    pub sections: Vec<Section>,
+    pub dynamic: Vec<(i64, u64)>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+    pub plt: Vec<PltEntry>,
+}
+
+impl ElfFile {
+    /// Parse an ELF file laid out as it is on disk.
+    pub fn parse(data: Vec<u8>) -> Result<Self> {
+        Self::parse_with_bias(data, 0)
+    }
+
+    /// Parse an ELF file whose dynamic section was read from a process where it was loaded at `bias`,
+    /// and whose pointers have been relocated by the dynamic linker.
+    pub fn parse_with_bias(data: Vec<u8>, bias: u64) -> Result<Self> {
+        let header = Header::parse(&data)?;
+        let mut elf = Self {
+            data,
+            header,
+            segments: Vec::new(),
+            sections: Vec::new(),
+            dynamic: Vec::new(),
+            symbols: Vec::new(),
+            relocations: Vec::new(),
+            plt: Vec::new(),
+        };
+        elf.segments = elf.parse_segments();
+        elf.sections = elf.parse_sections();
+        elf.dynamic = elf.parse_dynamic(bias);
+
+        for kind in [SHT_SYMTAB, SHT_DYNSYM] {
+            let symbols = elf.parse_symbol_section(kind);
+            elf.symbols.extend(symbols);
+        }
+        let dynsym = elf.parse_dynamic_symbols();
+        if !elf.symbols.iter().any(|symbol| symbol.source == SymbolSource::Dynsym) {
+            // Section headers usually aren't loaded, so in-memory images only have the dynamic symbol table
+            elf.symbols.extend(dynsym.iter().cloned());
+        }
+        elf.relocations = elf.parse_relocations(&dynsym);
+        elf.plt = elf.parse_plt();
+        Ok(elf)
+    }
+
+    fn parse_segments(&self) -> Vec<Segment> {
+        let header = &self.header;
+        (0..header.phnum as usize)
+            .filter_map(|i| {
+                let at = header.phoff as usize + i * header.phentsize as usize;
+                Some(Segment {
+                    kind: u32_at(&self.data, at)?,
+                    flags: u32_at(&self.data, at + 4)?,
+                    offset: u64_at(&self.data, at + 8)?,
+                    vaddr: u64_at(&self.data, at + 16)?,
+                    filesz: u64_at(&self.data, at + 32)?,
+                    memsz: u64_at(&self.data, at + 40)?,
+                })
+            })
+            .collect()
+    }
+
+    fn parse_sections(&self) -> Vec<Section> {
+        let header = &self.header;
+        let sections: Vec<(u32, Section)> = (0..header.shnum as usize)
+            .map_while(|i| {
+                let at = header.shoff as usize + i * header.shentsize as usize;
+                let name = u32_at(&self.data, at)?;
+                Some((
+                    name,
+                    Section {
+                        name: String::new(),
+                        kind: u32_at(&self.data, at + 4)?,
+                        flags: u64_at(&self.data, at + 8)?,
+                        addr: u64_at(&self.data, at + 16)?,
+                        offset: u64_at(&self.data, at + 24)?,
+                        size: u64_at(&self.data, at + 32)?,
+                        link: u32_at(&self.data, at + 40)?,
+                    },
+                ))
+            })
+            .collect();
+        if sections.len() != header.shnum as usize {
+            return Vec::new(); // Section headers weren't (completely) available
+        }
+        let strtab = sections
+            .get(header.shstrndx as usize)
+            .map(|(_, section)| section.offset as usize);
+        sections
+            .into_iter()
+            .map(|(name, mut section)| {
+                if let Some(strtab) = strtab {
+                    section.name = str_at(&self.data, strtab + name as usize);
+                }
+                section
+            })
+            .collect()
+    }
+
+    fn parse_dynamic(&self, bias: u64) -> Vec<(i64, u64)> {
+        let Some(segment) = self.segments.iter().find(|segment| segment.kind == PT_DYNAMIC) else {
+            return Vec::new();
+        };
+        let mut dynamic = Vec::new();
+        let mut at = segment.offset as usize;
+        while let (Some(tag), Some(value)) = (u64_at(&self.data, at), u64_at(&self.data, at + 8)) {
+            let tag = tag as i64;
+            if tag == DT_NULL || at >= (segment.offset + segment.filesz) as usize {
+                break;
+            }
+            let value = if bias != 0 && DT_POINTERS.contains(&tag) && value >= bias {
+                value - bias
+            } else {
+                value
+            };
+            dynamic.push((tag, value));
+            at += 16;
+        }
+        dynamic
+    }
+
+    pub fn dynamic_value(&self, tag: i64) -> Option<u64> {
+        self.dynamic.iter().find(|(t, _)| *t == tag).map(|(_, value)| *value)
+    }
+
+    /// File offset of an unrelocated virtual address
+    pub fn vaddr_to_offset(&self, vaddr: u64) -> Option<usize> {
+        self.segments
+            .iter()
+            .find(|segment| segment.kind == PT_LOAD && vaddr >= segment.vaddr && vaddr < segment.vaddr + segment.filesz)
+            .map(|segment| (vaddr - segment.vaddr + segment.offset) as usize)
+    }
+
+    fn parse_symbols(&self, offset: usize, count: usize, strtab: usize, source: SymbolSource) -> Vec<Symbol> {
+        (0..count)
+            .map_while(|i| {
+                let at = offset + i * 24;
+                let info = *self.data.get(at + 4)?;
+                Some(Symbol {
+                    name: str_at(&self.data, strtab + u32_at(&self.data, at)? as usize),
+                    value: u64_at(&self.data, at + 8)?,
+                    size: u64_at(&self.data, at + 16)?,
+                    kind: info & 0xf,
+                    bind: info >> 4,
+                    shndx: u16_at(&self.data, at + 6)?,
+                    source,
+                })
+            })
+            .collect()
+    }
+
+    fn parse_symbol_section(&self, kind: u32) -> Vec<Symbol> {
+        let Some(section) = self.sections.iter().find(|section| section.kind == kind) else {
+            return Vec::new();
+        };
+        let Some(strtab) = self.sections.get(section.link as usize) else {
+            return Vec::new();
+        };
+        let source = if kind == SHT_SYMTAB { SymbolSource::Symtab } else { SymbolSource::Dynsym };
+        self.parse_symbols(
+            section.offset as usize,
+            (section.size / 24) as usize,
+            strtab.offset as usize,
+            source,
+        )
+    }
+
+    /// The dynamic symbol table, located through the dynamic section rather than section headers.
+    fn parse_dynamic_symbols(&self) -> Vec<Symbol> {
+        let (Some(symtab), Some(strtab)) = (
+            self.dynamic_value(DT_SYMTAB).and_then(|vaddr| self.vaddr_to_offset(vaddr)),
+            self.dynamic_value(DT_STRTAB).and_then(|vaddr| self.vaddr_to_offset(vaddr)),
+        ) else {
+            return Vec::new();
+        };
+        let count = self.dynamic_symbol_count().unwrap_or(0);
+        self.parse_symbols(symtab, count, strtab, SymbolSource::Dynsym)
+    }
+
+    /// The dynamic symbol table has no explicit length, it has to be derived from the hash tables.
+    fn dynamic_symbol_count(&self) -> Option<usize> {
+        if let Some(hash) = self.dynamic_value(DT_HASH).and_then(|vaddr| self.vaddr_to_offset(vaddr)) {
+            return u32_at(&self.data, hash + 4).map(|nchain| nchain as usize);
+        }
+        let hash = self.dynamic_value(DT_GNU_HASH).and_then(|vaddr| self.vaddr_to_offset(vaddr))?;
+        let nbuckets = u32_at(&self.data, hash)? as usize;
+        let symoffset = u32_at(&self.data, hash + 4)? as usize;
+        let bloom_size = u32_at(&self.data, hash + 8)? as usize;
+        let buckets = hash + 16 + bloom_size * 8;
+        let chains = buckets + nbuckets * 4;
+        let last = (0..nbuckets)
+            .filter_map(|i| u32_at(&self.data, buckets + i * 4))
+            .max()? as usize;
+        if last < symoffset {
+            return Some(symoffset);
+        }
+        // Walk the last chain until its terminator (lowest bit set)
+        let mut index = last;
+        while u32_at(&self.data, chains + (index - symoffset) * 4)? & 1 == 0 {
+            index += 1;
+        }
+        Some(index + 1)
+    }
+
+    fn parse_relocations(&self, dynsym: &[Symbol]) -> Vec<Relocation> {
+        let tables = [(DT_RELA, DT_RELASZ), (DT_JMPREL, DT_PLTRELSZ)];
+        let mut relocations = Vec::new();
+        for (address_tag, size_tag) in tables {
+            let (Some(offset), Some(size)) = (
+                self.dynamic_value(address_tag).and_then(|vaddr| self.vaddr_to_offset(vaddr)),
+                self.dynamic_value(size_tag),
+            ) else {
+                continue;
+            };
+            for i in 0..(size / 24) as usize {
+                let at = offset + i * 24;
+                let (Some(r_offset), Some(info), Some(addend)) =
+                    (u64_at(&self.data, at), u64_at(&self.data, at + 8), u64_at(&self.data, at + 16))
+                else {
+                    break;
+                };
+                let symbol = (info >> 32) as usize;
+                relocations.push(Relocation {
+                    offset: r_offset,
+                    kind: info as u32,
+                    symbol: (symbol != 0)
+                        .then(|| dynsym.get(symbol).map(|symbol| symbol.name.clone()))
+                        .flatten(),
+                    addend: addend as i64,
+                });
+            }
+        }
+        relocations
+    }
+
+    fn parse_plt(&self) -> Vec<PltEntry> {
+        // With IBT, the stubs that are actually called live in .plt.sec; otherwise each .plt stub follows PLT0
+        let stubs = match self.section(".plt.sec") {
+            Some(section) => Some(section.addr),
+            None => self.section(".plt").map(|section| section.addr + 16),
+        };
+        let mut plt = Vec::new();
+        let jump_slots = self.relocations.iter().filter(|r| r.kind == R_X86_64_JUMP_SLOT);
+        for (i, relocation) in jump_slots.enumerate() {
+            let Some(symbol) = &relocation.symbol else {
+                continue;
+            };
+            plt.push(PltEntry {
+                symbol: symbol.clone(),
+                got: relocation.offset,
+                plt: stubs.map(|stubs| stubs + 16 * i as u64),
+            });
+        }
+        // Imports that bypass the PLT (-fno-plt, or taking a function's address) go through GLOB_DAT slots
+        for relocation in self.relocations.iter().filter(|r| r.kind == R_X86_64_GLOB_DAT) {
+            let Some(symbol) = &relocation.symbol else {
+                continue;
+            };
+            if !plt.iter().any(|entry| entry.symbol == *symbol) {
+                plt.push(PltEntry {
+                    symbol: symbol.clone(),
+                    got: relocation.offset,
+                    plt: None,
+                });
+            }
+        }
+        plt
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    /// Contents of a section, inflated if it was compressed (as debug sections often are)
+    pub fn section_data(&self, name: &str) -> Option<Vec<u8>> {
+        let section = self.section(name)?;
+        if section.kind == SHT_NOBITS {
+            return None;
+        }
+        let data = self.data.get(section.offset as usize..(section.offset + section.size) as usize)?;
+        if section.flags & SHF_COMPRESSED == 0 {
+            return Some(data.to_vec());
+        }
+        // Elf64_Chdr: ch_type, ch_reserved, ch_size, ch_addralign
+        if u32_at(data, 0)? != ELFCOMPRESS_ZLIB {
+            return None;
+        }
+        let mut inflated = Vec::with_capacity(u64_at(data, 8)? as usize);
+        std::io::Read::read_to_end(&mut flate2::read::ZlibDecoder::new(data.get(24..)?), &mut inflated).ok()?;
+        Some(inflated)
+    }
+
+    /// Find a defined symbol by name, preferring the full symbol table
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols
+            .iter()
+            .filter(|symbol| symbol.is_defined() && symbol.name == name)
+            .min_by_key(|symbol| symbol.source == SymbolSource::Dynsym)
+    }
+
+    pub fn plt_entry(&self, symbol: &str) -> Option<&PltEntry> {
+        self.plt.iter().find(|entry| entry.symbol == symbol)
+    }
+
+    pub fn build_id(&self) -> Option<Vec<u8>> {
+        self.segments
+            .iter()
+            .filter(|segment| segment.kind == PT_NOTE)
+            .find_map(|segment| {
+                let notes = self.data.get(segment.offset as usize..(segment.offset + segment.filesz) as usize)?;
+                parse_build_id_note(notes)
+            })
+    }
+}
+
+/// Find the GNU build ID in the contents of a PT_NOTE segment.
+pub fn parse_build_id_note(notes: &[u8]) -> Option<Vec<u8>> {
+    let mut offset = 0;
+    while offset + 12 <= notes.len() {
+        let namesz = u32_at(notes, offset)? as usize;
+        let descsz = u32_at(notes, offset + 4)? as usize;
+        let kind = u32_at(notes, offset + 8)?;
+        let name = offset + 12;
+        let desc = name + namesz.next_multiple_of(4);
+        if desc + descsz > notes.len() {
+            return None;
+        }
+        if kind == NT_GNU_BUILD_ID && &notes[name..name + namesz] == b"GNU\0" {
+            return Some(notes[desc..desc + descsz].to_vec());
+        }
+        offset = desc + descsz.next_multiple_of(4);
+    }
+    None
+}
@@ -0,0 +1,104 @@
+use std::{
+    sync::atomic::AtomicUsize,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::debug;
+
+use crate::util;
+
+pub static FREEZE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A value that the debugger keeps rewriting, either periodically or whenever the target writes to it.
+pub struct Freeze {
+    pub id: usize,
+    pub address: u64,
+    pub value: Vec<u8>,
+    pub interval: Duration,
+    pub last_write: Option<Instant>,
+    /// Debug register of the write watchpoint restoring the value, if any
+    pub watchpoint: Option<usize>,
+}
+
+impl Freeze {
+    pub fn write(&mut self, pid: u32) {
+        if let Err(e) = util::mem::write_bytes(pid, self.address as _, &self.value) {
+            debug!("Failed to write frozen value at {:#x}: {}", self.address, e);
+        }
+        self.last_write = Some(Instant::now());
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.watchpoint.is_none() && self.last_write.is_none_or(|last| last.elapsed() >= self.interval)
+    }
+}
+
+/// Every frozen value, serviced from the debugger's main loop.
+pub struct Freezes {
+    pub pid: u32,
+    pub entries: Vec<Freeze>,
+}
+
+impl Freezes {
+    pub fn new(pid: u32) -> Self {
+        Self {
+            pid,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Keep `value` written at `address`. Periodic freezes are rewritten every `interval`,
+    /// watched ones whenever the write watchpoint in debug register `watchpoint` fires.
+    pub fn freeze(&mut self, address: u64, value: Vec<u8>, interval: Duration, watchpoint: Option<usize>) -> usize {
+        let id = FREEZE_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let mut freeze = Freeze {
+            id,
+            address,
+            value,
+            interval,
+            last_write: None,
+            watchpoint,
+        };
+        freeze.write(self.pid);
+        self.entries.push(freeze);
+        id
+    }
+
+    /// Stop rewriting a frozen value. A watchpoint backing it stays armed (but inert) until the script is reloaded,
+    /// since debug registers can only be changed while the threads are stopped.
+    pub fn unfreeze(&mut self, id: usize) -> Result<()> {
+        let Some(index) = self.entries.iter().position(|freeze| freeze.id == id) else {
+            return Err(anyhow::anyhow!("No such freeze: {}", id));
+        };
+        self.entries.remove(index);
+        Ok(())
+    }
+
+    /// Rewrite every periodically frozen value whose interval has elapsed.
+    pub fn apply_due(&mut self) {
+        for freeze in self.entries.iter_mut().filter(|freeze| freeze.is_due()) {
+            freeze.write(self.pid);
+        }
+    }
+
+    /// Time until the next periodic freeze is due, if there are any.
+    pub fn next_due(&self) -> Option<Duration> {
+        self.entries
+            .iter()
+            .filter(|freeze| freeze.watchpoint.is_none())
+            .map(|freeze| freeze.last_write.map_or(Duration::ZERO, |last| freeze.interval.saturating_sub(last.elapsed())))
+            .min()
+    }
+
+    /// Restore the values watched by the watchpoint in debug register `dr`, after it was hit.
+    pub fn apply_watchpoint(&mut self, dr: usize) {
+        for freeze in self.entries.iter_mut().filter(|freeze| freeze.watchpoint == Some(dr)) {
+            freeze.write(self.pid);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,118 @@
+use anyhow::Result;
+use log::{debug, error, info};
+
+use crate::{debugger::Debugger, util};
+
+/// A GOT slot redirected to a replacement function.
+pub struct ImportHook {
+    pub module: String,
+    pub symbol: String,
+    pub got: u64,
+    pub original: u64,
+    pub replacement: u64,
+}
+
+/// Convert map permissions (as in /proc/pid/maps) to mprotect flags
+fn protection(permissions: &str) -> u64 {
+    let mut prot = libc::PROT_NONE;
+    for (flag, c) in [(libc::PROT_READ, 'r'), (libc::PROT_WRITE, 'w'), (libc::PROT_EXEC, 'x')] {
+        if permissions.contains(c) {
+            prot |= flag;
+        }
+    }
+    prot as u64
+}
+
+impl Debugger {
+    /// Write to memory that the target itself can't write to, such as a GOT made read-only by RELRO,
+    /// by making the pages writable for the duration of the write. Needs a stopped thread to inject mprotect into.
+    pub fn write_protected(&mut self, address: u64, bytes: &[u8]) -> Result<()> {
+        if util::mem::write_bytes(self.pid, address as _, bytes).is_ok() {
+            return Ok(());
+        }
+        let Some(thread) = self.threads.values().find(|thread| thread.is_traced()) else {
+            return Err(anyhow::anyhow!("No stopped thread to call mprotect from"));
+        };
+        let (prot, instruction) = {
+            let mut maps = self.maps.lock().unwrap();
+            // The protection is put back afterwards, so it has to be current
+            maps.refresh()?;
+            let map = maps
+                .iter()
+                .find(|map| map.contains(address))
+                .ok_or(anyhow::anyhow!("{:#x} is not mapped", address))?;
+            // Pages in other maps can have other protections, which would be overwritten
+            if address + bytes.len() as u64 > map.end {
+                return Err(anyhow::anyhow!(
+                    "{:#x}+{:#x} crosses the end of its map at {:#x}",
+                    address,
+                    bytes.len(),
+                    map.end
+                ));
+            }
+            let instruction = util::syscall::find_syscall_instruction(self.pid, &maps)
+                .ok_or(anyhow::anyhow!("Couldn't find a syscall instruction"))?;
+            (protection(&map.permissions), instruction)
+        };
+
+        let page = address & !0xfff;
+        let len = (address + bytes.len() as u64 - page).next_multiple_of(0x1000);
+        let mprotect = |prot: u64| -> Result<()> {
+            let res = util::syscall::inject(thread.pid, instruction, libc::SYS_mprotect as _, &[page, len, prot])?;
+            if res < 0 {
+                return Err(anyhow::anyhow!("mprotect failed: {}", std::io::Error::from_raw_os_error(-res as i32)));
+            }
+            Ok(())
+        };
+        mprotect(prot | libc::PROT_WRITE as u64)?;
+        let written = util::mem::write_bytes(self.pid, address as _, bytes);
+        mprotect(prot)?;
+        written
+    }
+
+    /// Point the GOT slot at `got` to `replacement`, returning the pointer it held before.
+    pub fn hook_import(&mut self, module: &str, symbol: &str, got: u64, replacement: u64) -> Result<u64> {
+        let original = util::mem::read::<u64>(self.pid, got as _)?;
+        self.write_protected(got, &replacement.to_le_bytes())?;
+        debug!("Hooked {}!{} ({:#x} -> {:#x})", module, symbol, original, replacement);
+        self.import_hooks.push(ImportHook {
+            module: module.to_string(),
+            symbol: symbol.to_string(),
+            got,
+            original,
+            replacement,
+        });
+        Ok(original)
+    }
+
+    /// Restore the most recent hook of a module's import.
+    pub fn unhook_import(&mut self, module: &str, symbol: &str) -> Result<()> {
+        let Some(index) = self
+            .import_hooks
+            .iter()
+            .rposition(|hook| hook.module == module && hook.symbol == symbol)
+        else {
+            return Err(anyhow::anyhow!("{}!{} is not hooked", module, symbol));
+        };
+        let hook = self.import_hooks.remove(index);
+        self.write_protected(hook.got, &hook.original.to_le_bytes())
+    }
+
+    /// Restore every hooked GOT slot, newest first.
+    pub fn revert_import_hooks(&mut self) {
+        if self.import_hooks.is_empty() {
+            return;
+        }
+        let count = self.import_hooks.len();
+        while let Some(hook) = self.import_hooks.pop() {
+            match self.write_protected(hook.got, &hook.original.to_le_bytes()) {
+                Ok(_) => debug!(
+                    "Unhooked {}!{} ({:#x} -> {:#x})",
+                    hook.module, hook.symbol, hook.replacement, hook.original
+                ),
+                Err(e) => error!("Failed to unhook {}!{}: {}", hook.module, hook.symbol, e),
+            }
+        }
+        info!("Reverted {} import hooks", count);
+    }
+}
@@ -22,8 +22,9 @@ impl From<HardwareBreakpointType> for u8 {
     fn from(value: HardwareBreakpointType) -> Self {
         match value {
             HardwareBreakpointType::Access => 0x03,
-            HardwareBreakpointType::Write => 0x02,
-            HardwareBreakpointType::Read => 0x01,
+            // x86 has no read-only watchpoints (0x02 selects I/O breakpoints), so reads also trap on writes
+            HardwareBreakpointType::Read => 0x03,
+            HardwareBreakpointType::Write => 0x01,
             HardwareBreakpointType::Execute => 0x00,
         }
     }
@@ -35,8 +36,7 @@ impl TryFrom<u8> for HardwareBreakpointType {
     fn try_from(value: u8) -> Result<Self> {
         Ok(match value {
             0x03 => HardwareBreakpointType::Access,
-            0x02 => HardwareBreakpointType::Write,
-            0x01 => HardwareBreakpointType::Read,
+            0x01 => HardwareBreakpointType::Write,
             0x00 => HardwareBreakpointType::Execute,
             _ => return Err(anyhow::anyhow!("Invalid hardware breakpoint type")),
         })
@@ -1,5 +1,9 @@
 #![feature(try_trait_v2)]
-use std::sync::{mpsc, Arc, Mutex};
+use std::{
+    os::fd::AsRawFd,
+    sync::{mpsc, Arc},
+    time::Duration,
+};
 
 use anyhow::Result;
 use debugger::Debugger;
@@ -8,20 +12,52 @@ mod debugger;
 mod registers;
 mod thread;
 mod util;
+mod freeze;
+mod maps;
+mod elf;
+mod hook;
 mod hwbp;
+mod module;
+mod patch;
 mod runtime;
+mod symbolize;
+mod unwind;
+mod asm;
+mod target;
+mod crash;
+mod stats;
 
 use hwbp::{HardwareBreakpoint, HardwareBreakpointType, DR_COUNTER};
 use log::{debug, error, info};
 use runtime::{Context, Script};
-use signal_hook::{iterator::Signals, consts::{SIGINT, SIGTERM}};
-use util::{inotify::watch_file_for_changes, procfs::MemoryMap};
+use libc::{SIGCHLD, SIGINT, SIGTERM};
+use util::{inotify::FileWatcher, signal::SignalFd};
 
 pub enum Event {
     Exit,
     FileModified,
 }
 
+/// Log what the breakpoints have cost the target, if any were hit.
+fn log_stats(context: &Context) {
+    let stats = context.stats();
+    if !stats.breakpoints.is_empty() {
+        info!("{}", stats.summary(&mut context.symbols()));
+    }
+}
+
+/// Run a script once against a core file. Nothing runs, so there is no main loop.
+fn run_offline(core_path: &str, script_path: &str) -> Result<()> {
+    let core = elf::coredump::CoreFile::open(core_path)?;
+    let mut debugger = Debugger::new();
+    debugger.open_core(Arc::new(core))?;
+
+    let (tx, _rx) = mpsc::channel();
+    let context = Context::new(debugger, tx);
+    let script = Script::new(&std::fs::read_to_string(script_path)?, context)?;
+    script.run()
+}
+
 fn main() -> Result<()> {
     simplelog::TermLogger::init(
         simplelog::LevelFilter::Info,
@@ -30,24 +66,25 @@ fn main() -> Result<()> {
         simplelog::ColorChoice::Auto,
     )?;
 
-    let pid = std::env::args().nth(1).unwrap().parse::<u32>()?;
-    let script_path = std::env::args().nth(2).unwrap();
-
-    let (tx, rx): (mpsc::Sender<Event>, mpsc::Receiver<Event>) = mpsc::channel();
-
-    let tx_clone = tx.clone();
-    std::thread::spawn(move || {
-        let mut signals = Signals::new(&[SIGINT, SIGTERM]).expect("Failed to create signals");
-        for _ in signals.forever() {
-            tx_clone.send(Event::Exit).unwrap();
+    // xenon3 <pid> [script] [--dump-core <path>], or xenon3 --core <path> <script>
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.first().is_some_and(|arg| arg == "--core") {
+        let (Some(core_path), Some(script_path)) = (args.get(1), args.get(2)) else {
+            return Err(anyhow::anyhow!("Usage: xenon3 --core <path> <script>"));
+        };
+        return run_offline(core_path, script_path);
+    }
+    let dump_core_path = match args.iter().position(|arg| arg == "--dump-core") {
+        Some(i) if i + 1 < args.len() => {
+            let path = args.remove(i + 1);
+            args.remove(i);
+            Some(path)
         }
-    });
-
-    let tx_clone = tx.clone();
-    let script_path_clone = script_path.clone();
-    std::thread::spawn(move || {
-        watch_file_for_changes(tx_clone, script_path_clone.into()).unwrap();
-    });
+        Some(_) => return Err(anyhow::anyhow!("--dump-core needs a path")),
+        None => None,
+    };
+    let pid = args.first().ok_or_else(|| anyhow::anyhow!("Usage: xenon3 <pid> [script] [--dump-core <path>]"))?.parse::<u32>()?;
+    let script_path = args.get(1).cloned();
 
     let mut debugger = Debugger::new();
     // debugger.breakpoints.push(HardwareBreakpoint::new(
@@ -58,12 +95,26 @@ fn main() -> Result<()> {
     debugger.attach(pid)?;
     debugger.stop_all()?;
 
-    let context = Context {
-        debugger: Arc::new(Mutex::new(debugger)),
-        maps: Arc::new(Mutex::new(MemoryMap::parse_maps(pid)?)),
-        tx,
+    if let Some(path) = &dump_core_path {
+        let maps = debugger.maps.lock().unwrap().clone();
+        let summary = elf::coredump::dump_core(pid, &maps, path)?;
+        info!("Wrote {} ({} threads, {} segments, {} bytes)", path, summary.threads, summary.segments, summary.size);
+    }
+    let Some(script_path) = script_path else {
+        debugger.continue_all()?;
+        return Ok(());
     };
 
+    // Thread state changes (SIGCHLD), Ctrl+C and script edits all wake up the main loop, which
+    // otherwise sleeps until the next freeze or map refresh is due, if any
+    let signals = SignalFd::new(&[SIGCHLD, SIGINT, SIGTERM])?;
+    let mut watcher = FileWatcher::new(script_path.as_ref())?;
+
+    // Only for quit() from scripts, which runs on this thread
+    let (tx, rx): (mpsc::Sender<Event>, mpsc::Receiver<Event>) = mpsc::channel();
+
+    let context = Context::new(debugger, tx);
+
     let mut script = Script::new(&std::fs::read_to_string(&script_path)?, context.clone()).unwrap();
 
     script.run()?;
@@ -72,35 +123,54 @@ fn main() -> Result<()> {
     context.debugger().continue_all()?;
 
     loop {
-        match rx.try_recv() {
-            Ok(Event::Exit) => {
-                break;
-            },
-            Ok(Event::FileModified) => {
-                {
-                    context.debugger().stop_all()?;
-                    context.debugger().clear_breakpoints()?;
-                    context.debugger().callbacks.clear();
-                    DR_COUNTER.store(0, std::sync::atomic::Ordering::Relaxed);
-                }
-                info!("Reloading script");
-                script = Script::new(&std::fs::read_to_string(&script_path)?, context.clone()).unwrap();
-                match script.run() {
-                    Ok(_) => {},
-                    Err(e) => {
-                        error!("Error running script: {}", e);
-                    }
-                }
-                {
-                    context.debugger().apply_breakpoints()?;
-                    context.debugger().continue_all()?;
-                }
-            },
-            _ => {}
+        context.debugger().run(&script)?;
+        if context.debugger().threads.is_empty() {
+            info!("Target exited");
+            break;
         }
 
-        context.debugger().run(&script)?;
+        let mut events = rx.try_iter().collect::<Vec<_>>();
+        let timeout = if events.is_empty() { context.debugger().next_timeout() } else { Some(Duration::ZERO) };
+        util::signal::poll(&[signals.as_raw_fd(), watcher.as_raw_fd()], timeout)?;
+        // SIGCHLD needs no handling here, as the next run() reaps every thread that stopped
+        if signals.read().iter().any(|&signal| signal == SIGINT || signal == SIGTERM) {
+            events.push(Event::Exit);
+        }
+        if watcher.modified()? {
+            events.push(Event::FileModified);
+        }
+
+        if events.iter().any(|event| matches!(event, Event::Exit)) {
+            break;
+        }
+        if events.iter().any(|event| matches!(event, Event::FileModified)) {
+            {
+                context.debugger().stop_all()?;
+                context.debugger().clear_breakpoints()?;
+                context.debugger().revert_import_hooks();
+                context.patches().revert_all();
+                context.debugger().callbacks.clear();
+                context.freezes().clear();
+                // Debug registers are handed out again from the start, so the numbers would get mixed up
+                log_stats(&context);
+                context.stats().clear();
+                DR_COUNTER.store(0, std::sync::atomic::Ordering::Relaxed);
+            }
+            info!("Reloading script");
+            script = Script::new(&std::fs::read_to_string(&script_path)?, context.clone()).unwrap();
+            match script.run() {
+                Ok(_) => {},
+                Err(e) => {
+                    error!("Error running script: {}", e);
+                }
+            }
+            {
+                context.debugger().apply_breakpoints()?;
+                context.debugger().continue_all()?;
+            }
+        }
     }
+    log_stats(&context);
     info!("Gracefully exiting...");
     Ok(())
 }
@@ -0,0 +1,85 @@
+use std::{
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::debug;
+
+use crate::{
+    target::Target,
+    util::procfs::{MapChanges, MemoryMap},
+};
+
+/// How old the maps can get before readers have them re-read, and how often they are re-read in the
+/// background while the script has map change callbacks. Polling is all there is: mmap, munmap and
+/// mprotect aren't traced, so changes undone within this interval go unnoticed.
+pub const MAX_AGE: Duration = Duration::from_millis(250);
+
+/// The target's memory maps as last read. Every refresh goes through here, and keeps what changed
+/// until the debugger hands it to the script's map change callbacks.
+pub struct MemoryMaps {
+    pub target: Arc<dyn Target>,
+    maps: Vec<MemoryMap>,
+    /// Changes the callbacks haven't seen yet
+    pending: MapChanges,
+    refreshed: Instant,
+}
+
+impl MemoryMaps {
+    pub fn new(target: Arc<dyn Target>) -> Self {
+        Self {
+            target,
+            maps: Vec::new(),
+            pending: MapChanges::default(),
+            refreshed: Instant::now(),
+        }
+    }
+
+    /// Start tracking `target`, whose current maps are taken as they are rather than as changes.
+    pub fn load(&mut self, target: Arc<dyn Target>) -> Result<()> {
+        self.target = target;
+        self.maps.clear();
+        self.refresh()?;
+        self.pending = MapChanges::default();
+        Ok(())
+    }
+
+    /// Re-read the maps, returning what changed since they were last read.
+    pub fn refresh(&mut self) -> Result<MapChanges> {
+        let new = self.target.maps()?;
+        self.refreshed = Instant::now();
+        let changes = MemoryMap::diff(&self.maps, &new);
+        self.maps = new;
+        self.pending.extend(changes.clone());
+        Ok(changes)
+    }
+
+    /// Re-read the maps unless that was done less than [`MAX_AGE`] ago. Failures keep the old maps.
+    pub fn refresh_if_stale(&mut self) {
+        if self.age() >= MAX_AGE {
+            if let Err(e) = self.refresh() {
+                debug!("Failed to refresh memory maps: {}", e);
+            }
+        }
+    }
+
+    /// Time since the maps were last read
+    pub fn age(&self) -> Duration {
+        self.refreshed.elapsed()
+    }
+
+    /// Every change since the last call
+    pub fn take_changes(&mut self) -> MapChanges {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+impl Deref for MemoryMaps {
+    type Target = Vec<MemoryMap>;
+
+    fn deref(&self) -> &Vec<MemoryMap> {
+        &self.maps
+    }
+}
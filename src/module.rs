@@ -0,0 +1,70 @@
+use crate::{
+    elf::{self, PT_NOTE},
+    target::Target,
+    util::procfs::MemoryMap,
+};
+
+/// A loaded object (the executable or a shared library), made up of all file-backed maps of the same file.
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub name: String,
+    pub path: String,
+    pub base: u64,
+    pub end: u64,
+    /// Start and end of every executable map
+    pub executable: Vec<(u64, u64)>,
+}
+
+impl Module {
+    /// Group the file-backed entries of `maps` (which must be sorted, as in /proc/pid/maps) into modules.
+    pub fn from_maps(maps: &[MemoryMap]) -> Vec<Module> {
+        let mut modules: Vec<Module> = Vec::new();
+        for map in maps {
+            let Some(path) = map.pathname.as_ref().filter(|path| path.starts_with('/')) else {
+                continue;
+            };
+            // A map at offset 0 starts a new module, even if the same file was already loaded before
+            let continues_last = modules
+                .last()
+                .is_some_and(|module| module.path == *path && map.offset != 0);
+            if !continues_last {
+                let name = path.rsplit('/').next().unwrap_or(path);
+                modules.push(Module {
+                    name: name.trim_end_matches(" (deleted)").to_string(),
+                    path: path.clone(),
+                    base: map.start,
+                    end: map.end,
+                    executable: Vec::new(),
+                });
+            }
+            let module = modules.last_mut().unwrap();
+            module.end = map.end;
+            if map.permissions.contains('x') {
+                module.executable.push((map.start, map.end));
+            }
+        }
+        modules
+    }
+
+    /// Whether `name` refers to this module, either by file name or full path.
+    pub fn matches(&self, name: &str) -> bool {
+        self.name == name || self.path == name
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.base && address < self.end
+    }
+
+    /// Read the GNU build ID note from the module's ELF headers, as they are mapped in memory.
+    pub fn build_id(&self, target: &dyn Target) -> Option<Vec<u8>> {
+        let (_, segments, bias) = elf::loaded::loaded_segments(target, self).ok()?;
+        segments
+            .iter()
+            .filter(|segment| segment.kind == PT_NOTE)
+            .find_map(|segment| {
+                let address = bias.wrapping_add(segment.vaddr);
+                let notes = target.read_bytes(address, segment.filesz as _).ok()?;
+                elf::parse_build_id_note(&notes)
+            })
+    }
+}
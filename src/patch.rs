@@ -0,0 +1,93 @@
+use std::sync::{atomic::AtomicUsize, Arc};
+
+use anyhow::Result;
+use log::{debug, error, info};
+
+use crate::target::Target;
+
+pub static PATCH_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A journaled write to the target's memory, along with the bytes it replaced.
+pub struct Patch {
+    pub id: usize,
+    pub address: u64,
+    pub original: Vec<u8>,
+    pub patched: Vec<u8>,
+}
+
+impl Patch {
+    pub fn overlaps(&self, other: &Patch) -> bool {
+        self.address < other.address + other.patched.len() as u64
+            && other.address < self.address + self.patched.len() as u64
+    }
+}
+
+/// Every patch currently applied to the target, oldest first.
+pub struct PatchJournal {
+    pub target: Arc<dyn Target>,
+    pub patches: Vec<Patch>,
+}
+
+impl PatchJournal {
+    pub fn new(target: Arc<dyn Target>) -> Self {
+        Self {
+            target,
+            patches: Vec::new(),
+        }
+    }
+
+    /// Write `bytes` to `address`, remembering the original contents so the write can be undone.
+    pub fn apply(&mut self, address: u64, bytes: &[u8]) -> Result<usize> {
+        if bytes.is_empty() {
+            return Err(anyhow::anyhow!("Patch is empty"));
+        }
+        let original = self.target.read_bytes(address, bytes.len())?;
+        self.target.write_memory(address, bytes)?;
+
+        let id = PATCH_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        debug!("Applied patch {} ({} bytes at {:#x})", id, bytes.len(), address);
+        self.patches.push(Patch {
+            id,
+            address,
+            original,
+            patched: bytes.to_vec(),
+        });
+        Ok(id)
+    }
+
+    /// Restore the bytes overwritten by a single patch.
+    /// Fails if a newer patch overlaps it, since its original bytes would then be clobbered.
+    pub fn revert(&mut self, id: usize) -> Result<()> {
+        let Some(index) = self.patches.iter().position(|patch| patch.id == id) else {
+            return Err(anyhow::anyhow!("No such patch: {}", id));
+        };
+        let patch = &self.patches[index];
+        if let Some(newer) = self.patches[index + 1..].iter().find(|newer| newer.overlaps(patch)) {
+            return Err(anyhow::anyhow!(
+                "Patch {} is overlapped by newer patch {}, revert that first",
+                id,
+                newer.id
+            ));
+        }
+        self.target.write_memory(patch.address, &patch.original)?;
+        debug!("Reverted patch {} at {:#x}", id, patch.address);
+        self.patches.remove(index);
+        Ok(())
+    }
+
+    /// Restore every journaled patch, newest first, so overlapping patches unwind correctly.
+    /// Patches that can no longer be written back are logged and dropped from the journal.
+    pub fn revert_all(&mut self) {
+        if self.patches.is_empty() {
+            return;
+        }
+        let count = self.patches.len();
+        while let Some(patch) = self.patches.pop() {
+            match self.target.write_memory(patch.address, &patch.original) {
+                Ok(_) => debug!("Reverted patch {} at {:#x}", patch.id, patch.address),
+                Err(e) => error!("Failed to revert patch {} at {:#x}: {}", patch.id, patch.address, e),
+            }
+        }
+        info!("Reverted {} patches", count);
+    }
+}
@@ -1,5 +1,6 @@
 use libc::user_regs_struct;
 
+#[derive(Clone)]
 pub struct Registers {
     pub r15: u64,
     pub r14: u64,
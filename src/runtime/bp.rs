@@ -1,11 +1,29 @@
-use rhai::Engine;
+use rhai::{Dynamic, Engine};
 
 use crate::hwbp::{HardwareBreakpoint, HardwareBreakpointType};
 
-use super::{Context, RuntimeCallback};
+use super::{dwarf, Context, RuntimeCallback};
 pub fn register_functions(engine: &mut Engine, context: Context) {
+    // Nothing runs in a core file, so no breakpoint would ever be hit
     let ctx = context.clone();
     engine.register_fn("breakpoint", move |addr: i64, callback: rhai::FnPtr| {
+        if ctx.unsupported_offline("Breakpoints and watchpoints") {
+            return;
+        }
+        let breakpoint = HardwareBreakpoint::new(addr as _, HardwareBreakpointType::Execute, 1).unwrap();
+        let callback = RuntimeCallback::Breakpoint(breakpoint.dr, callback);
+        ctx.debugger().callbacks.push(callback);
+        ctx.debugger().breakpoints.push(breakpoint);
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("breakpoint", move |location: &str, callback: rhai::FnPtr| {
+        if ctx.unsupported_offline("Breakpoints and watchpoints") {
+            return;
+        }
+        let Some(addr) = dwarf::resolve_line(&ctx, location) else {
+            return;
+        };
         let breakpoint = HardwareBreakpoint::new(addr as _, HardwareBreakpointType::Execute, 1).unwrap();
         let callback = RuntimeCallback::Breakpoint(breakpoint.dr, callback);
         ctx.debugger().callbacks.push(callback);
@@ -14,9 +32,37 @@ pub fn register_functions(engine: &mut Engine, context: Context) {
 
     let ctx = context.clone();
     engine.register_fn("watchpoint", move |addr: i64, length: i64, callback: rhai::FnPtr| {
+        if ctx.unsupported_offline("Breakpoints and watchpoints") {
+            return;
+        }
         let breakpoint = HardwareBreakpoint::new(addr as _, HardwareBreakpointType::Access, length as _).unwrap();
         let callback = RuntimeCallback::Breakpoint(breakpoint.dr, callback);
         ctx.debugger().callbacks.push(callback);
         ctx.debugger().breakpoints.push(breakpoint);
     });
+
+    // Hit counts and overhead of each breakpoint and watchpoint since the script was loaded, most expensive first
+    let ctx = context;
+    engine.register_fn("stats", move || -> rhai::Array {
+        let stats = ctx.stats();
+        let elapsed = stats.elapsed();
+        stats
+            .sorted()
+            .into_iter()
+            .map(|(dr, stats)| {
+                let mut map = rhai::Map::new();
+                map.insert("address".into(), (stats.address as i64).into());
+                map.insert("location".into(), ctx.symbols().describe(stats.address).into());
+                map.insert("kind".into(), format!("{:?}", stats.kind).to_lowercase().into());
+                map.insert("debug_register".into(), (dr as i64).into());
+                map.insert("hits".into(), (stats.hits as i64).into());
+                map.insert("hits_per_second".into(), (stats.hits as f64 / elapsed).into());
+                map.insert("callback_us".into(), (stats.callback_time.as_micros() as i64).into());
+                map.insert("stopped_us".into(), (stats.stopped_time.as_micros() as i64).into());
+                map.insert("average_stopped_us".into(), (stats.average_stopped_time().as_micros() as i64).into());
+                map.insert("max_stopped_us".into(), (stats.max_stopped_time.as_micros() as i64).into());
+                Dynamic::from(map)
+            })
+            .collect()
+    });
 }
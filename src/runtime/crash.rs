@@ -0,0 +1,90 @@
+//! Crash report callbacks
+
+use rhai::{Dynamic, Engine};
+
+use crate::crash::{self, CrashReport};
+
+use super::{mem::memory_map_to_dynamic, Context, RuntimeCallback};
+
+/// Script representation of a [`CrashReport`], along with where it was saved (if it was)
+pub fn crash_report_to_dynamic(report: &CrashReport, path: Option<&str>) -> Dynamic {
+    let mut result = rhai::Map::new();
+    result.insert("signal".into(), (report.signal as i64).into());
+    result.insert("signal_name".into(), crash::signal_name(report.signal).into());
+    result.insert("code".into(), (report.code as i64).into());
+    result.insert(
+        "fault_address".into(),
+        report.fault_address.map(|address| Dynamic::from(address as i64)).unwrap_or(Dynamic::UNIT),
+    );
+    let mut registers = rhai::Map::new();
+    for (name, value) in crash::register_list(&report.regs) {
+        registers.insert(name.into(), (value as i64).into());
+    }
+    result.insert("registers".into(), registers.into());
+    result.insert(
+        "map".into(),
+        report.fault_map.as_ref().map(memory_map_to_dynamic).unwrap_or(Dynamic::UNIT),
+    );
+    result.insert(
+        "disassembly".into(),
+        report
+            .code_lines
+            .iter()
+            .map(|(address, text)| {
+                let mut line = rhai::Map::new();
+                line.insert("address".into(), (*address as i64).into());
+                line.insert("text".into(), text.clone().into());
+                Dynamic::from(line)
+            })
+            .collect::<rhai::Array>()
+            .into(),
+    );
+    result.insert(
+        "backtrace".into(),
+        report
+            .frames
+            .iter()
+            .zip(&report.frame_names)
+            .map(|(frame, name)| {
+                let mut entry = rhai::Map::new();
+                entry.insert("pc".into(), (frame.pc as i64).into());
+                entry.insert("sp".into(), (frame.sp as i64).into());
+                entry.insert("description".into(), name.clone().into());
+                Dynamic::from(entry)
+            })
+            .collect::<rhai::Array>()
+            .into(),
+    );
+    result.insert(
+        "threads".into(),
+        report
+            .threads
+            .iter()
+            .map(|thread| {
+                let mut entry = rhai::Map::new();
+                entry.insert("pid".into(), (thread.pid as i64).into());
+                entry.insert("name".into(), thread.name.clone().into());
+                entry.insert("state".into(), thread.state.clone().into());
+                entry.insert(
+                    "rip".into(),
+                    thread.rip.map(|rip| Dynamic::from(rip as i64)).unwrap_or(Dynamic::UNIT),
+                );
+                Dynamic::from(entry)
+            })
+            .collect::<rhai::Array>()
+            .into(),
+    );
+    result.insert("path".into(), path.map(|path| Dynamic::from(path.to_string())).unwrap_or(Dynamic::UNIT));
+    result.insert("text".into(), report.to_string().into());
+    Dynamic::from(result)
+}
+
+pub fn register_functions(engine: &mut Engine, context: Context) {
+    // Called with the report and the thread before the fatal signal is delivered
+    engine.register_fn("on_crash", move |callback: rhai::FnPtr| {
+        if context.unsupported_offline("Crash callbacks") {
+            return;
+        }
+        context.debugger().callbacks.push(RuntimeCallback::Crashed(callback));
+    });
+}
@@ -0,0 +1,224 @@
+//! Disassembling and assembling the target's code
+
+use iced_x86::{
+    Decoder, DecoderOptions, Formatter, Instruction, InstructionInfoFactory, IntelFormatter, OpAccess, OpKind, Register,
+    SymbolResolver, SymbolResult,
+};
+use log::error;
+use rhai::{Dynamic, Engine};
+
+use crate::asm;
+
+use super::{elf::load_elf, Context};
+
+const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+/// How far back `previous_instruction` starts decoding. x86 decoding resynchronizes within a few
+/// instructions, so starting far enough back almost always lands on the real instruction boundaries.
+const RESYNC_DISTANCE: u64 = 64;
+
+/// Shows branch targets and RIP-relative operands as symbols
+struct Symbols(Context);
+
+impl SymbolResolver for Symbols {
+    fn symbol(
+        &mut self,
+        _instruction: &Instruction,
+        _operand: u32,
+        _instruction_operand: Option<u32>,
+        address: u64,
+        _address_size: u32,
+    ) -> Option<SymbolResult<'_>> {
+        let symbolized = self.0.symbols().symbolize(address)?;
+        // Plain module offsets are more noise than help in a listing
+        symbolized.symbol.as_ref()?;
+        Some(SymbolResult::with_string(address, symbolized.to_string()))
+    }
+}
+
+fn formatter(ctx: &Context) -> IntelFormatter {
+    let mut formatter = IntelFormatter::with_options(Some(Box::new(Symbols(ctx.clone()))), None);
+    let options = formatter.options_mut();
+    options.set_hex_prefix("0x");
+    options.set_hex_suffix("");
+    options.set_uppercase_hex(false);
+    options.set_space_after_operand_separator(true);
+    options.set_branch_leading_zeros(false);
+    formatter
+}
+
+/// Read up to `len` bytes of code, stopping at the end of the memory map instead of failing
+fn read_code(ctx: &Context, address: u64, len: usize) -> Option<Vec<u8>> {
+    let end = ctx.maps().iter().find(|map| map.contains(address))?.end;
+    let len = len.min((end - address) as usize);
+    ctx.target.read_bytes(address, len).ok()
+}
+
+fn register_name(register: Register) -> Dynamic {
+    match register {
+        Register::None => Dynamic::UNIT,
+        register => format!("{:?}", register).to_lowercase().into(),
+    }
+}
+
+fn access_name(access: OpAccess) -> &'static str {
+    match access {
+        OpAccess::Read | OpAccess::CondRead => "read",
+        OpAccess::Write | OpAccess::CondWrite => "write",
+        OpAccess::ReadWrite | OpAccess::ReadCondWrite => "read_write",
+        _ => "none",
+    }
+}
+
+fn operand_to_dynamic(instruction: &Instruction, info: &iced_x86::InstructionInfo, operand: u32) -> Dynamic {
+    let mut map = rhai::Map::new();
+    let kind = match instruction.op_kind(operand) {
+        OpKind::Register => {
+            map.insert("register".into(), register_name(instruction.op_register(operand)));
+            "register"
+        }
+        OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64 => {
+            map.insert("target".into(), (instruction.near_branch_target() as i64).into());
+            "branch"
+        }
+        OpKind::FarBranch16 | OpKind::FarBranch32 => {
+            map.insert("target".into(), (instruction.far_branch32() as i64).into());
+            map.insert("selector".into(), (instruction.far_branch_selector() as i64).into());
+            "branch"
+        }
+        OpKind::Memory => {
+            map.insert("base".into(), register_name(instruction.memory_base()));
+            map.insert("index".into(), register_name(instruction.memory_index()));
+            map.insert("scale".into(), (instruction.memory_index_scale() as i64).into());
+            map.insert("displacement".into(), (instruction.memory_displacement64() as i64).into());
+            map.insert("size".into(), (instruction.memory_size().size() as i64).into());
+            // RIP-relative operands have their target computed already
+            if instruction.is_ip_rel_memory_operand() {
+                map.insert("address".into(), (instruction.ip_rel_memory_address() as i64).into());
+            }
+            "memory"
+        }
+        OpKind::Immediate8
+        | OpKind::Immediate8_2nd
+        | OpKind::Immediate16
+        | OpKind::Immediate32
+        | OpKind::Immediate64
+        | OpKind::Immediate8to16
+        | OpKind::Immediate8to32
+        | OpKind::Immediate8to64
+        | OpKind::Immediate32to64 => {
+            map.insert("value".into(), (instruction.immediate(operand) as i64).into());
+            "immediate"
+        }
+        // String instructions (movs, stos, ...) implicitly address memory through rsi/rdi
+        _ => "memory",
+    };
+    map.insert("kind".into(), kind.into());
+    map.insert("access".into(), access_name(info.op_access(operand)).into());
+    Dynamic::from(map)
+}
+
+fn instruction_to_dynamic(
+    instruction: &Instruction,
+    bytes: &[u8],
+    formatter: &mut IntelFormatter,
+    info_factory: &mut InstructionInfoFactory,
+) -> Dynamic {
+    let mut text = String::new();
+    formatter.format(instruction, &mut text);
+    let info = info_factory.info(instruction);
+    let mut map = rhai::Map::new();
+    map.insert("address".into(), (instruction.ip() as i64).into());
+    map.insert("length".into(), (instruction.len() as i64).into());
+    map.insert("bytes".into(), Dynamic::from_blob(bytes.to_vec()));
+    map.insert("text".into(), text.into());
+    map.insert("mnemonic".into(), format!("{:?}", instruction.mnemonic()).to_lowercase().into());
+    map.insert(
+        "operands".into(),
+        (0..instruction.op_count())
+            .map(|operand| operand_to_dynamic(instruction, info, operand))
+            .collect::<rhai::Array>()
+            .into(),
+    );
+    Dynamic::from(map)
+}
+
+/// Decode up to `count` instructions, stopping early at undecodable bytes
+fn decode(ctx: &Context, code: &[u8], address: u64, count: usize) -> rhai::Array {
+    let mut formatter = formatter(ctx);
+    let mut info_factory = InstructionInfoFactory::new();
+    let mut decoder = Decoder::with_ip(64, code, address, DecoderOptions::NONE);
+    let mut instructions = rhai::Array::new();
+    let mut instruction = Instruction::default();
+    while instructions.len() < count && decoder.can_decode() {
+        let offset = decoder.position();
+        decoder.decode_out(&mut instruction);
+        if instruction.is_invalid() {
+            break;
+        }
+        let bytes = &code[offset..offset + instruction.len()];
+        instructions.push(instruction_to_dynamic(&instruction, bytes, &mut formatter, &mut info_factory));
+    }
+    instructions
+}
+
+pub fn register_functions(engine: &mut Engine, context: Context) {
+    let ctx = context.clone();
+    engine.register_fn("disassemble", move |address: i64, count: i64| -> Dynamic {
+        let count = count.max(0) as usize;
+        let Some(code) = read_code(&ctx, address as u64, count.saturating_mul(MAX_INSTRUCTION_LENGTH)) else {
+            return Dynamic::UNIT;
+        };
+        decode(&ctx, &code, address as u64, count).into()
+    });
+
+    // Data watchpoints trap after the access, so the culprit is the instruction before rip
+    let ctx = context.clone();
+    engine.register_fn("previous_instruction", move |address: i64| -> Dynamic {
+        let address = address as u64;
+        let Some(last) = address.checked_sub(1) else {
+            return Dynamic::UNIT;
+        };
+        let Some(start) = ctx.maps().iter().find(|map| map.contains(last)).map(|map| map.start) else {
+            return Dynamic::UNIT;
+        };
+        let start = start.max(address.saturating_sub(RESYNC_DISTANCE));
+        let Some(code) = read_code(&ctx, start, (address - start) as usize) else {
+            return Dynamic::UNIT;
+        };
+        // Take the longest run of valid instructions that ends exactly at `address`
+        for skip in 0..code.len() {
+            let mut decoder = Decoder::with_ip(64, &code[skip..], start + skip as u64, DecoderOptions::NONE);
+            let mut instruction = Instruction::default();
+            while decoder.can_decode() {
+                decoder.decode_out(&mut instruction);
+                if instruction.is_invalid() {
+                    break;
+                }
+            }
+            if !instruction.is_invalid() && instruction.next_ip() == address {
+                let offset = (instruction.ip() - start) as usize;
+                let bytes = &code[offset..offset + instruction.len()];
+                return decode(&ctx, bytes, instruction.ip(), 1).pop().unwrap_or(Dynamic::UNIT);
+            }
+        }
+        Dynamic::UNIT
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("assemble", move |source: &str, address: i64| -> Dynamic {
+        // Names that aren't labels are symbols, as module!name
+        let resolve = |name: &str| {
+            let (module, name) = name.split_once('!')?;
+            let elf = load_elf(&ctx, module)?;
+            elf.elf.symbol(name).map(|symbol| elf.address(symbol.value))
+        };
+        match asm::assemble(source, address as u64, resolve) {
+            Ok(code) => Dynamic::from_blob(code),
+            Err(e) => {
+                error!("Failed to assemble: {}", e);
+                Dynamic::UNIT
+            }
+        }
+    });
+}
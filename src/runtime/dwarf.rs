@@ -0,0 +1,108 @@
+//! Source-level functions, for modules that ship DWARF debug information
+
+use log::{error, info, warn};
+use rhai::{Dynamic, Engine};
+
+use crate::{
+    elf::dwarf::{TypeKind, Variable},
+    module::Module,
+    registers::Registers,
+};
+
+use super::{Context, RhaiThread};
+
+/// Resolve a `file:line` source location to the address of its first statement.
+pub fn resolve_line(ctx: &Context, location: &str) -> Option<u64> {
+    let Some((file, line)) = location.rsplit_once(':').and_then(|(file, line)| Some((file, line.parse().ok()?)))
+    else {
+        error!("Invalid source location: {} (expected file:line)", location);
+        return None;
+    };
+    for module in Module::from_maps(&ctx.maps()) {
+        let Ok(elf) = ctx.elves().get(&*ctx.target, &module) else {
+            continue;
+        };
+        let Some((found, addresses)) = elf.debug_info().and_then(|debug| debug.line_addresses(file, line)) else {
+            continue;
+        };
+        let address = elf.address(addresses[0]);
+        if found != line {
+            info!("{} has no code, using line {} instead", location, found);
+        }
+        if addresses.len() > 1 {
+            warn!("{} has {} locations, only using the first one ({:#x})", location, addresses.len(), address);
+        }
+        return Some(address);
+    }
+    error!("No debug information for {}", location);
+    None
+}
+
+fn variable_to_dynamic(variable: Variable) -> Dynamic {
+    let bytes = variable.bytes;
+    let size = bytes.len().min(8);
+    let mut raw = [0u8; 8];
+    raw[..size].copy_from_slice(&bytes[..size]);
+    let raw = u64::from_le_bytes(raw);
+    match (variable.ty.kind, size) {
+        (TypeKind::Signed, 1..=8) => {
+            let shift = 64 - size * 8;
+            Dynamic::from(((raw << shift) as i64) >> shift)
+        }
+        (TypeKind::Unsigned | TypeKind::Pointer, 1..=8) => Dynamic::from(raw as i64),
+        (TypeKind::Bool, 1..=8) => Dynamic::from(raw != 0),
+        (TypeKind::Float, 4) if bytes.len() == 4 => Dynamic::from(f32::from_bits(raw as u32) as f64),
+        (TypeKind::Float, 8) if bytes.len() == 8 => Dynamic::from(f64::from_bits(raw)),
+        _ => Dynamic::from_blob(bytes),
+    }
+}
+
+pub fn register_functions(engine: &mut Engine, context: Context) {
+    let ctx = context.clone();
+    engine.register_fn("addr_to_line", move |address: i64| -> Dynamic {
+        let modules = Module::from_maps(&ctx.maps());
+        let Some(module) = modules.iter().find(|module| module.contains(address as u64)) else {
+            return Dynamic::UNIT;
+        };
+        let Ok(elf) = ctx.elves().get(&*ctx.target, module) else {
+            return Dynamic::UNIT;
+        };
+        let Some(line) = elf.debug_info().and_then(|debug| debug.line(address as u64 - elf.bias)) else {
+            return Dynamic::UNIT;
+        };
+        let mut map = rhai::Map::new();
+        map.insert("file".into(), line.file.into());
+        map.insert("line".into(), (line.line as i64).into());
+        map.insert("column".into(), (line.column as i64).into());
+        Dynamic::from(map)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("read_var", move |task: RhaiThread, name: &str| -> Dynamic {
+        let regs = match ctx.target.regs(task.pid as _) {
+            Ok(regs) => Registers::from(regs),
+            Err(e) => {
+                error!("Failed to get registers of {}: {}", task.pid, e);
+                return Dynamic::UNIT;
+            }
+        };
+        // Locals can only be in the module the thread is executing, so it goes first
+        let mut modules = Module::from_maps(&ctx.maps());
+        modules.sort_by_key(|module| !module.contains(regs.rip));
+        for module in &modules {
+            let Ok(elf) = ctx.elves().get(&*ctx.target, module) else {
+                continue;
+            };
+            match elf.read_variable(&*ctx.target, &regs, name) {
+                Ok(Some(variable)) => return variable_to_dynamic(variable),
+                Ok(None) => continue,
+                Err(e) => {
+                    error!("Failed to read {}: {}", name, e);
+                    return Dynamic::UNIT;
+                }
+            }
+        }
+        error!("No such variable: {}", name);
+        Dynamic::UNIT
+    });
+}
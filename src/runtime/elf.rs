@@ -0,0 +1,246 @@
+//! ELF inspection functions for loaded modules
+
+use std::sync::Arc;
+
+use log::error;
+use rhai::{Dynamic, Engine};
+
+use crate::{
+    elf::{loaded::ElfSource, LoadedElf, PltEntry},
+    module::Module,
+    target::Target,
+};
+
+use super::Context;
+
+/// Parse (or fetch from the cache) the ELF file of the module called `name`.
+pub fn load_elf(ctx: &Context, name: &str) -> Option<Arc<LoadedElf>> {
+    let modules = Module::from_maps(&ctx.maps());
+    let Some(module) = modules.iter().find(|module| module.matches(name)) else {
+        error!("No such module: {}", name);
+        return None;
+    };
+    match ctx.elves().get(&*ctx.target, module) {
+        Ok(elf) => Some(elf),
+        Err(e) => {
+            error!("Failed to read ELF file of {}: {}", name, e);
+            None
+        }
+    }
+}
+
+fn plt_entry_to_dynamic(elf: &LoadedElf, entry: &PltEntry, target: &dyn Target) -> Dynamic {
+    let got = elf.address(entry.got);
+    let mut map = rhai::Map::new();
+    map.insert("symbol".into(), entry.symbol.clone().into());
+    map.insert("got".into(), (got as i64).into());
+    map.insert(
+        "plt".into(),
+        entry.plt.map(|plt| Dynamic::from(elf.address(plt) as i64)).unwrap_or(Dynamic::UNIT),
+    );
+    map.insert(
+        "value".into(),
+        target.read::<i64>(got).map(Dynamic::from).unwrap_or(Dynamic::UNIT),
+    );
+    Dynamic::from(map)
+}
+
+pub fn register_functions(engine: &mut Engine, context: Context) {
+    let ctx = context.clone();
+    engine.register_fn("elf_info", move |module: &str| -> Dynamic {
+        let Some(elf) = load_elf(&ctx, module) else {
+            return Dynamic::UNIT;
+        };
+        let mut map = rhai::Map::new();
+        let source = match elf.source {
+            ElfSource::Disk => "disk",
+            ElfSource::Memory => "memory",
+        };
+        map.insert("source".into(), source.into());
+        map.insert("bias".into(), (elf.bias as i64).into());
+        map.insert("type".into(), (elf.elf.header.kind as i64).into());
+        map.insert("machine".into(), (elf.elf.header.machine as i64).into());
+        map.insert("entry".into(), (elf.address(elf.elf.header.entry) as i64).into());
+        map.insert(
+            "build_id".into(),
+            elf.elf
+                .build_id()
+                .map(|id| id.iter().map(|b| format!("{:02x}", b)).collect::<String>().into())
+                .unwrap_or(Dynamic::UNIT),
+        );
+        Dynamic::from(map)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("elf_sections", move |module: &str| -> Dynamic {
+        let Some(elf) = load_elf(&ctx, module) else {
+            return Dynamic::UNIT;
+        };
+        elf.elf
+            .sections
+            .iter()
+            .map(|section| {
+                let mut map = rhai::Map::new();
+                map.insert("name".into(), section.name.clone().into());
+                map.insert("type".into(), (section.kind as i64).into());
+                map.insert("flags".into(), (section.flags as i64).into());
+                // Sections that aren't loaded have no address
+                let address = (section.addr != 0).then(|| Dynamic::from(elf.address(section.addr) as i64));
+                map.insert("address".into(), address.unwrap_or(Dynamic::UNIT));
+                map.insert("offset".into(), (section.offset as i64).into());
+                map.insert("size".into(), (section.size as i64).into());
+                Dynamic::from(map)
+            })
+            .collect::<rhai::Array>()
+            .into()
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("elf_segments", move |module: &str| -> Dynamic {
+        let Some(elf) = load_elf(&ctx, module) else {
+            return Dynamic::UNIT;
+        };
+        elf.elf
+            .segments
+            .iter()
+            .map(|segment| {
+                let mut map = rhai::Map::new();
+                map.insert("type".into(), (segment.kind as i64).into());
+                map.insert("flags".into(), (segment.flags as i64).into());
+                map.insert("offset".into(), (segment.offset as i64).into());
+                map.insert("address".into(), (elf.address(segment.vaddr) as i64).into());
+                map.insert("file_size".into(), (segment.filesz as i64).into());
+                map.insert("memory_size".into(), (segment.memsz as i64).into());
+                Dynamic::from(map)
+            })
+            .collect::<rhai::Array>()
+            .into()
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("elf_symbols", move |module: &str| -> Dynamic {
+        let Some(elf) = load_elf(&ctx, module) else {
+            return Dynamic::UNIT;
+        };
+        elf.elf
+            .symbols
+            .iter()
+            .filter(|symbol| !symbol.name.is_empty())
+            .map(|symbol| {
+                let mut map = rhai::Map::new();
+                map.insert("name".into(), symbol.name.clone().into());
+                // Imported symbols aren't defined in this module, so they don't have an address here
+                let address = symbol.is_defined().then(|| Dynamic::from(elf.address(symbol.value) as i64));
+                map.insert("address".into(), address.unwrap_or(Dynamic::UNIT));
+                map.insert("size".into(), (symbol.size as i64).into());
+                map.insert("type".into(), symbol.kind_name().into());
+                map.insert("bind".into(), symbol.bind_name().into());
+                map.insert("dynamic".into(), (symbol.source == crate::elf::SymbolSource::Dynsym).into());
+                Dynamic::from(map)
+            })
+            .collect::<rhai::Array>()
+            .into()
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("elf_symbol", move |module: &str, name: &str| -> Dynamic {
+        let Some(elf) = load_elf(&ctx, module) else {
+            return Dynamic::UNIT;
+        };
+        elf.elf
+            .symbol(name)
+            .map(|symbol| Dynamic::from(elf.address(symbol.value) as i64))
+            .unwrap_or(Dynamic::UNIT)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("elf_relocations", move |module: &str| -> Dynamic {
+        let Some(elf) = load_elf(&ctx, module) else {
+            return Dynamic::UNIT;
+        };
+        elf.elf
+            .relocations
+            .iter()
+            .map(|relocation| {
+                let mut map = rhai::Map::new();
+                map.insert("address".into(), (elf.address(relocation.offset) as i64).into());
+                map.insert("type".into(), relocation.kind_name().into());
+                map.insert(
+                    "symbol".into(),
+                    relocation.symbol.clone().map(Dynamic::from).unwrap_or(Dynamic::UNIT),
+                );
+                map.insert("addend".into(), relocation.addend.into());
+                Dynamic::from(map)
+            })
+            .collect::<rhai::Array>()
+            .into()
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("got_entries", move |module: &str| -> Dynamic {
+        let Some(elf) = load_elf(&ctx, module) else {
+            return Dynamic::UNIT;
+        };
+        elf.elf
+            .plt
+            .iter()
+            .map(|entry| plt_entry_to_dynamic(&elf, entry, &*ctx.target))
+            .collect::<rhai::Array>()
+            .into()
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("got_entry", move |module: &str, symbol: &str| -> Dynamic {
+        let Some(elf) = load_elf(&ctx, module) else {
+            return Dynamic::UNIT;
+        };
+        elf.elf
+            .plt_entry(symbol)
+            .map(|entry| plt_entry_to_dynamic(&elf, entry, &*ctx.target))
+            .unwrap_or(Dynamic::UNIT)
+    });
+
+    // GOT hooks need a stopped thread to make RELRO pages writable, so they can't be installed from callbacks
+    let ctx = context.clone();
+    engine.register_fn(
+        "hook_import",
+        move |module: &str, symbol: &str, replacement: i64| -> Dynamic {
+            if ctx.unsupported_offline("Import hooks") {
+                return Dynamic::UNIT;
+            }
+            let Some(elf) = load_elf(&ctx, module) else {
+                return Dynamic::UNIT;
+            };
+            let Some(entry) = elf.elf.plt_entry(symbol) else {
+                error!("{} does not import {}", module, symbol);
+                return Dynamic::UNIT;
+            };
+            let Ok(mut debugger) = ctx.debugger.try_lock() else {
+                error!("hook_import can only be called while the script is loading");
+                return Dynamic::UNIT;
+            };
+            match debugger.hook_import(module, symbol, elf.address(entry.got), replacement as u64) {
+                Ok(original) => Dynamic::from(original as i64),
+                Err(e) => {
+                    error!("Failed to hook {}!{}: {}", module, symbol, e);
+                    Dynamic::UNIT
+                }
+            }
+        },
+    );
+
+    let ctx = context.clone();
+    engine.register_fn("unhook_import", move |module: &str, symbol: &str| -> bool {
+        let Ok(mut debugger) = ctx.debugger.try_lock() else {
+            error!("unhook_import can only be called while the script is loading");
+            return false;
+        };
+        match debugger.unhook_import(module, symbol) {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Failed to unhook {}!{}: {}", module, symbol, e);
+                false
+            }
+        }
+    });
+}
@@ -0,0 +1,89 @@
+//! Value freezing functions
+
+use std::time::Duration;
+
+use log::error;
+use rhai::{Dynamic, Engine};
+
+use crate::hwbp::{HardwareBreakpoint, HardwareBreakpointType};
+
+use super::{value::ValueType, Context};
+
+fn encode(address: &Dynamic, kind: &str, value: &Dynamic) -> Option<(u64, Vec<u8>)> {
+    let address = address.as_int().unwrap_or(-1);
+    if address < 0 {
+        return None;
+    }
+    match ValueType::parse(kind, value).and_then(|kind| kind.encode(value)) {
+        Ok(bytes) => Some((address as u64, bytes)),
+        Err(e) => {
+            error!("Failed to freeze {:#x}: {}", address, e);
+            None
+        }
+    }
+}
+
+pub fn register_functions(engine: &mut Engine, context: Context) {
+    let ctx = context.clone();
+    engine.register_fn(
+        "freeze",
+        move |address: Dynamic, kind: &str, value: Dynamic, interval_ms: i64| -> Dynamic {
+            if ctx.unsupported_offline("Freezes") {
+                return Dynamic::UNIT;
+            }
+            // A zero interval would keep the value due forever, and the main loop spinning
+            if interval_ms < 1 {
+                error!("Failed to freeze: the interval must be at least 1 ms, got {}", interval_ms);
+                return Dynamic::UNIT;
+            }
+            let Some((address, bytes)) = encode(&address, kind, &value) else {
+                return Dynamic::UNIT;
+            };
+            let interval = Duration::from_millis(interval_ms as u64);
+            Dynamic::from(ctx.freezes().freeze(address, bytes, interval, None) as i64)
+        },
+    );
+
+    // Like `breakpoint`, this has to be called while the script is being loaded
+    let ctx = context.clone();
+    engine.register_fn(
+        "freeze_on_write",
+        move |address: Dynamic, kind: &str, value: Dynamic| -> Dynamic {
+            if ctx.unsupported_offline("Freezes") {
+                return Dynamic::UNIT;
+            }
+            let Some((address, bytes)) = encode(&address, kind, &value) else {
+                return Dynamic::UNIT;
+            };
+            if !address.is_multiple_of(bytes.len() as u64) {
+                error!("Failed to freeze {:#x}: watched values must be aligned to their size", address);
+                return Dynamic::UNIT;
+            }
+            let Ok(mut debugger) = ctx.debugger.try_lock() else {
+                error!("freeze_on_write can only be called while the script is loading");
+                return Dynamic::UNIT;
+            };
+            let breakpoint = match HardwareBreakpoint::new(address, HardwareBreakpointType::Write, bytes.len()) {
+                Ok(breakpoint) => breakpoint,
+                Err(e) => {
+                    error!("Failed to freeze {:#x}: {}", address, e);
+                    return Dynamic::UNIT;
+                }
+            };
+            let id = ctx.freezes().freeze(address, bytes, Duration::ZERO, Some(breakpoint.dr));
+            debugger.breakpoints.push(breakpoint);
+            Dynamic::from(id as i64)
+        },
+    );
+
+    let ctx = context.clone();
+    engine.register_fn("unfreeze", move |id: i64| -> bool {
+        match ctx.freezes().unfreeze(id as _) {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Failed to unfreeze: {}", e);
+                false
+            }
+        }
+    });
+}
@@ -1,11 +1,17 @@
 use std::ops::Try;
 
-use log::info;
+use log::{debug, error};
 use rhai::{Dynamic, Engine};
 
-use crate::util;
+use crate::{
+    target::Target,
+    util::procfs::{MapChanges, MemoryMap},
+};
 
-use super::{Context, RhaiThread};
+use super::{
+    value::{parse_u64, u64_to_dynamic, ValueType},
+    Context, RhaiThread, RuntimeCallback,
+};
 
 pub fn integer_to_dynamic<T: Into<i64>>(value: Option<T>) -> Dynamic {
     match value {
@@ -21,125 +27,379 @@ pub fn float_to_dynamic<T: Into<f64>>(value: Option<T>) -> Dynamic {
     }
 }
 
+pub fn memory_map_to_dynamic(map: &MemoryMap) -> Dynamic {
+    let mut result = rhai::Map::new();
+    result.insert("start".into(), (map.start as i64).into());
+    result.insert("end".into(), (map.end as i64).into());
+    result.insert("length".into(), ((map.end - map.start) as i64).into());
+    result.insert("permissions".into(), map.permissions.to_string().into());
+    result.insert("offset".into(), (map.offset as i64).into());
+    result.insert("device".into(), map.device.to_string().into());
+    result.insert("inode".into(), (map.inode as i64).into());
+    result.insert(
+        "pathname".into(),
+        map.pathname.clone().map(Dynamic::from).unwrap_or(Dynamic::UNIT),
+    );
+    Dynamic::from(result)
+}
+
+/// Script representation of [`MapChanges`]: `added` and `removed` are arrays of map entries,
+/// `changed` an array of `#{ old: ..., new: ... }` pairs.
+pub fn map_changes_to_dynamic(changes: &MapChanges) -> Dynamic {
+    let mut result = rhai::Map::new();
+    result.insert(
+        "added".into(),
+        changes.added.iter().map(memory_map_to_dynamic).collect::<rhai::Array>().into(),
+    );
+    result.insert(
+        "removed".into(),
+        changes.removed.iter().map(memory_map_to_dynamic).collect::<rhai::Array>().into(),
+    );
+    result.insert(
+        "changed".into(),
+        changes
+            .changed
+            .iter()
+            .map(|(old, new)| {
+                let mut pair = rhai::Map::new();
+                pair.insert("old".into(), memory_map_to_dynamic(old));
+                pair.insert("new".into(), memory_map_to_dynamic(new));
+                Dynamic::from(pair)
+            })
+            .collect::<rhai::Array>()
+            .into(),
+    );
+    Dynamic::from(result)
+}
+
+/// Read a pointer at `address + offset` for each offset, starting from the previous pointer.
+/// On failure, returns the level that failed and the address it couldn't read.
+fn follow_ptr_chain(target: &dyn Target, address: u64, chain: &rhai::Array) -> Result<u64, (usize, u64)> {
+    let mut ptr = address;
+    for (level, offset) in chain.iter().enumerate() {
+        let address = ptr.wrapping_add(offset.as_int().unwrap_or(0) as u64);
+        ptr = target.read::<u64>(address).map_err(|_| (level, address))?;
+    }
+    Ok(ptr)
+}
+
+/// Longest single read_bytes or read_many range
+const MAX_READ_LENGTH: i64 = 32767 * 1000;
+
+/// A varint is at most 10 bytes for 64-bit values
+const MAX_VARINT_LENGTH: usize = 10;
+
+/// Read an unsigned LEB128 value, and how many bytes it took
+pub fn read_varint(target: &dyn Target, address: u64) -> Option<(u64, usize)> {
+    // The varint may end right before an unmapped page, so fall back to reading byte by byte
+    let bytes = target.read_bytes(address, MAX_VARINT_LENGTH).unwrap_or_else(|_| {
+        (0..MAX_VARINT_LENGTH as u64)
+            .map_while(|i| target.read::<u8>(address + i).ok())
+            .collect()
+    });
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().enumerate() {
+        value |= ((byte & 0x7f) as u64).checked_shl(7 * i as u32).unwrap_or(0);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+fn encode_varint(mut value: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn varint_to_dynamic(varint: Option<(u64, usize)>, value_to_dynamic: impl Fn(u64) -> Dynamic) -> Dynamic {
+    let Some((value, length)) = varint else {
+        return Dynamic::UNIT;
+    };
+    let mut map = rhai::Map::new();
+    map.insert("value".into(), value_to_dynamic(value));
+    map.insert("length".into(), (length as i64).into());
+    Dynamic::from(map)
+}
+
 pub fn register_functions(engine: &mut Engine, context: Context) {
     let ctx = context.clone();
-    let thread_leader = ctx.debugger().threads[0].pid;
-
     engine.register_fn("read_i8", move |address: Dynamic| -> Dynamic {
         let address = address.as_int().unwrap_or(-1);
         if address < 0 {
             return Dynamic::UNIT;
         }
-        integer_to_dynamic(util::mem::read::<i8>(thread_leader, address as _).ok())
+        integer_to_dynamic(ctx.target.read::<i8>(address as _).ok())
     });
 
+    let ctx = context.clone();
     engine.register_fn("read_i16", move |address: Dynamic| -> Dynamic {
         let address = address.as_int().unwrap_or(-1);
         if address < 0 {
             return Dynamic::UNIT;
         }
-        integer_to_dynamic(util::mem::read::<i16>(thread_leader, address as _).ok())
+        integer_to_dynamic(ctx.target.read::<i16>(address as _).ok())
     });
 
+    let ctx = context.clone();
     engine.register_fn("read_i32", move |address: Dynamic| -> Dynamic {
         let address = address.as_int().unwrap_or(-1);
         if address < 0 {
             return Dynamic::UNIT;
         }
-        integer_to_dynamic(util::mem::read::<i32>(thread_leader, address as _).ok())
+        integer_to_dynamic(ctx.target.read::<i32>(address as _).ok())
     });
 
+    let ctx = context.clone();
     engine.register_fn("read_i64", move |address: Dynamic| -> Dynamic {
         let address = address.as_int().unwrap_or(-1);
         if address < 0 {
             return Dynamic::UNIT;
         }
-        integer_to_dynamic(util::mem::read::<i64>(thread_leader, address as _).ok())
+        integer_to_dynamic(ctx.target.read::<i64>(address as _).ok())
     });
 
+    let ctx = context.clone();
     engine.register_fn("read_f32", move |address: Dynamic| -> Dynamic {
         let address = address.as_int().unwrap_or(-1);
         if address < 0 {
             return Dynamic::UNIT;
         }
-        float_to_dynamic(util::mem::read::<f32>(thread_leader, address as _).ok())
+        float_to_dynamic(ctx.target.read::<f32>(address as _).ok())
     });
 
+    let ctx = context.clone();
     engine.register_fn("read_f64", move |address: Dynamic| -> Dynamic {
         let address = address.as_int().unwrap_or(-1);
         if address < 0 {
             return Dynamic::UNIT;
         }
-        float_to_dynamic(util::mem::read::<f64>(thread_leader, address as _).ok())
+        float_to_dynamic(ctx.target.read::<f64>(address as _).ok())
     });
 
+    let ctx = context.clone();
     engine.register_fn("write_i8", move |address: Dynamic, value: Dynamic| {
         let address = address.as_int().unwrap_or(-1);
         let value = value.as_int().unwrap_or(-1) as i8;
         if address >= 0 {
-            util::mem::write(thread_leader, address as _, &value).ok();
+            if let Err(e) = ctx.target.write(address as _, &value) {
+                error!("{}", e);
+            }
         }
     });
 
+    let ctx = context.clone();
     engine.register_fn("write_i16", move |address: Dynamic, value: Dynamic| {
         let address = address.as_int().unwrap_or(-1);
         let value = value.as_int().unwrap_or(-1) as i16;
         if address >= 0 {
-            util::mem::write(thread_leader, address as _, &value).ok();
+            if let Err(e) = ctx.target.write(address as _, &value) {
+                error!("{}", e);
+            }
         }
     });
 
+    let ctx = context.clone();
     engine.register_fn("write_i32", move |address: Dynamic, value: Dynamic| {
         let address = address.as_int().unwrap_or(-1);
         let value = value.as_int().unwrap_or(-1) as i32;
         if address >= 0 {
-            util::mem::write(thread_leader, address as _, &value).ok();
+            if let Err(e) = ctx.target.write(address as _, &value) {
+                error!("{}", e);
+            }
         }
     });
 
+    let ctx = context.clone();
     engine.register_fn("write_i64", move |address: Dynamic, value: Dynamic| {
         let address = address.as_int().unwrap_or(-1);
         let value = value.as_int().unwrap_or(-1) as i64;
         if address >= 0 {
-            util::mem::write(thread_leader, address as _, &value).ok();
+            if let Err(e) = ctx.target.write(address as _, &value) {
+                error!("{}", e);
+            }
         }
     });
 
+    let ctx = context.clone();
     engine.register_fn("write_f32", move |address: Dynamic, value: Dynamic| {
         let address = address.as_int().unwrap_or(-1);
         let value = value.as_float().unwrap_or(-1.0) as f32;
         if address >= 0 {
-            util::mem::write(thread_leader, address as _, &value).ok();
+            if let Err(e) = ctx.target.write(address as _, &value) {
+                error!("{}", e);
+            }
         }
     });
 
+    let ctx = context.clone();
     engine.register_fn("write_f64", move |address: Dynamic, value: Dynamic| {
         let address = address.as_int().unwrap_or(-1);
         let value = value.as_float().unwrap_or(-1.0) as f64;
         if address >= 0 {
-            util::mem::write(thread_leader, address as _, &value).ok();
+            if let Err(e) = ctx.target.write(address as _, &value) {
+                error!("{}", e);
+            }
         }
     });
 
+    // Unsigned types, and big-endian (network order) variants of every number type: read_u32_be, ...
+    let unsigned = ["u8", "u16", "u32", "u64"].map(|name| (name, false));
+    let big_endian = ["i16", "i32", "i64", "u16", "u32", "u64", "f32", "f64"].map(|name| (name, true));
+    for (name, be) in unsigned.into_iter().chain(big_endian) {
+        let kind = ValueType::parse(name, &Dynamic::UNIT).unwrap();
+        let suffix = if be { "_be" } else { "" };
+        let ctx = context.clone();
+        engine.register_fn(format!("read_{}{}", name, suffix), move |address: Dynamic| -> Dynamic {
+            let address = address.as_int().unwrap_or(-1);
+            if address < 0 {
+                return Dynamic::UNIT;
+            }
+            match ctx.target.read_bytes(address as _, kind.size()) {
+                Ok(bytes) if be => kind.decode_be(&bytes),
+                Ok(bytes) => kind.decode(&bytes),
+                Err(_) => Dynamic::UNIT,
+            }
+        });
+        let ctx = context.clone();
+        engine.register_fn(format!("write_{}{}", name, suffix), move |address: Dynamic, value: Dynamic| {
+            let address = address.as_int().unwrap_or(-1);
+            let encoded = if be { kind.encode_be(&value) } else { kind.encode(&value) };
+            match encoded {
+                Ok(bytes) if address >= 0 => {
+                    if let Err(e) = ctx.target.write_memory(address as _, &bytes) {
+                        error!("{}", e);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => error!("Failed to write {}: {}", name, e),
+            }
+        });
+    }
+
+    // LEB128 varints, as in protobuf and DWARF. Returns #{ value, length }.
+    let ctx = context.clone();
+    engine.register_fn("read_varint", move |address: Dynamic| -> Dynamic {
+        let address = address.as_int().unwrap_or(-1);
+        if address < 0 {
+            return Dynamic::UNIT;
+        }
+        varint_to_dynamic(read_varint(&*ctx.target, address as _), u64_to_dynamic)
+    });
+
+    // Zigzag-encoded signed varints, as protobuf's sint32/sint64
+    let ctx = context.clone();
+    engine.register_fn("read_svarint", move |address: Dynamic| -> Dynamic {
+        let address = address.as_int().unwrap_or(-1);
+        if address < 0 {
+            return Dynamic::UNIT;
+        }
+        varint_to_dynamic(read_varint(&*ctx.target, address as _), |value| {
+            (((value >> 1) as i64) ^ -((value & 1) as i64)).into()
+        })
+    });
+
+    // Returns the number of bytes written
+    let ctx = context.clone();
+    engine.register_fn("write_varint", move |address: Dynamic, value: Dynamic| -> Dynamic {
+        let address = address.as_int().unwrap_or(-1);
+        let value = match value.read_lock::<rhai::ImmutableString>() {
+            Some(s) => parse_u64(&s),
+            None => value.as_int().map(|value| value as u64).map_err(|_| anyhow::anyhow!("Expected a number")),
+        };
+        match value {
+            Ok(value) if address >= 0 => {
+                let bytes = encode_varint(value);
+                match ctx.target.write_memory(address as _, &bytes) {
+                    Ok(()) => (bytes.len() as i64).into(),
+                    Err(e) => {
+                        error!("{}", e);
+                        Dynamic::UNIT
+                    }
+                }
+            }
+            Ok(_) => Dynamic::UNIT,
+            Err(e) => {
+                error!("Failed to write varint: {}", e);
+                Dynamic::UNIT
+            }
+        }
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("write_svarint", move |address: Dynamic, value: i64| -> Dynamic {
+        let address = address.as_int().unwrap_or(-1);
+        if address < 0 {
+            return Dynamic::UNIT;
+        }
+        let bytes = encode_varint(((value << 1) ^ (value >> 63)) as u64);
+        match ctx.target.write_memory(address as _, &bytes) {
+            Ok(()) => (bytes.len() as i64).into(),
+            Err(e) => {
+                error!("{}", e);
+                Dynamic::UNIT
+            }
+        }
+    });
+
+    let ctx = context.clone();
     engine.register_fn("read_bytes", move |address: Dynamic, len: Dynamic| -> Dynamic {
         let address = address.as_int().unwrap_or(-1);
-        let len = len.as_int().unwrap_or(256).min(32767 * 1000);
+        let len = len.as_int().unwrap_or(256).min(MAX_READ_LENGTH);
         if address < 0 {
             return Dynamic::UNIT;
         }
-        match util::mem::read_bytes(thread_leader, address as _, len as _) {
+        match ctx.target.read_bytes(address as _, len as _) {
             Ok(bytes) => Dynamic::from(bytes),
             Err(_) => Dynamic::UNIT,
         }
     });
 
+    // Many reads in one syscall, as [[address, length], ...]. Ranges that can't be read are ().
+    let ctx = context.clone();
+    engine.register_fn("read_many", move |ranges: rhai::Array| -> Dynamic {
+        let mut parsed = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            let range = range.try_cast::<rhai::Array>().unwrap_or_default();
+            let (Some(address), Some(len)) = (
+                range.first().and_then(|address| address.as_int().ok()),
+                range.get(1).and_then(|len| len.as_int().ok()),
+            ) else {
+                error!("read_many expects [address, length] pairs");
+                return Dynamic::UNIT;
+            };
+            if address < 0 || !(0..=MAX_READ_LENGTH).contains(&len) {
+                error!("Invalid range to read: {:#x}, {} bytes", address, len);
+                return Dynamic::UNIT;
+            }
+            parsed.push((address as u64, len as usize));
+        }
+        ctx.target.read_batch(&parsed)
+            .into_iter()
+            .map(|bytes| bytes.map(Dynamic::from).unwrap_or(Dynamic::UNIT))
+            .collect::<rhai::Array>()
+            .into()
+    });
+
+    let ctx = context.clone();
     engine.register_fn("write_bytes", move |address: Dynamic, bytes: Vec<u8>| {
         let address = address.as_int().unwrap_or(-1);
         if address >= 0 {
-            util::mem::write_bytes(thread_leader, address as _, &bytes).ok();
+            if let Err(e) = ctx.target.write_memory(address as _, &bytes) {
+                error!("{}", e);
+            }
         }
     });
 
+    let ctx = context.clone();
     engine.register_fn(
         "read_string",
         move |address: Dynamic, len: Dynamic| -> Dynamic {
@@ -148,7 +408,7 @@ pub fn register_functions(engine: &mut Engine, context: Context) {
             if address < 0 {
                 return Dynamic::UNIT;
             }
-            let Ok(bytes) = util::mem::read_bytes(thread_leader, address as _, len) else {
+            let Ok(bytes) = ctx.target.read_bytes(address as _, len) else {
                 return Dynamic::UNIT;
             };
             let nul = bytes.iter().position(|&b| b == 0).unwrap_or(len);
@@ -156,13 +416,14 @@ pub fn register_functions(engine: &mut Engine, context: Context) {
         },
     );
 
+    let ctx = context.clone();
     engine.register_fn("hexdump", move |address: Dynamic, len: Dynamic| -> Dynamic {
         let address = match address.as_int() {
             Ok(address) => address as usize,
             _ => return Dynamic::UNIT,
         };
         let length = len.as_int().unwrap_or(256) as usize;
-        let data = match util::mem::read_bytes(thread_leader, address as _, length as _) {
+        let data = match ctx.target.read_bytes(address as _, length as _) {
             Ok(data) => data,
             Err(_) => return Dynamic::UNIT,
         };
@@ -192,44 +453,94 @@ pub fn register_functions(engine: &mut Engine, context: Context) {
                     }
                 }
             }
+            // Point out aligned values that point into a module
+            for i in (0..16).step_by(8) {
+                let Some(bytes) = data.get(offset + i..offset + i + 8) else {
+                    continue;
+                };
+                if (address + offset + i) % 8 != 0 {
+                    continue;
+                }
+                let value = u64::from_le_bytes(bytes.try_into().unwrap());
+                if let Some(symbolized) = ctx.symbols().symbolize(value) {
+                    out.push_str(&format!("  <{}>", symbolized));
+                }
+            }
             out.push('\n');
             offset += 16;
         }
         out.trim().to_string().into()
     });
 
+    let ctx = context.clone();
     engine.register_fn(
         "read_ptr_chain",
-        move |address: i64, chain: Vec<i64>| -> Dynamic {
-            let mut ptr = address as usize;
-            for offset in chain {
-                ptr = util::mem::read::<usize>(thread_leader, ptr + offset as usize).unwrap();
+        move |address: i64, chain: rhai::Array| -> Dynamic {
+            match follow_ptr_chain(&*ctx.target, address as u64, &chain) {
+                Ok(ptr) => Dynamic::from(ptr as i64),
+                Err((level, _)) => {
+                    debug!("Pointer chain from {:#x} broke at level {}", address, level);
+                    Dynamic::UNIT
+                }
             }
-            Dynamic::from(ptr)
         },
     );
 
+    // Like read_ptr_chain, but says where the chain broke: #{ value } or #{ level, address }
+    let ctx = context.clone();
+    engine.register_fn(
+        "follow_ptr_chain",
+        move |address: i64, chain: rhai::Array| -> Dynamic {
+            let mut result = rhai::Map::new();
+            match follow_ptr_chain(&*ctx.target, address as u64, &chain) {
+                Ok(ptr) => {
+                    result.insert("value".into(), (ptr as i64).into());
+                }
+                Err((level, address)) => {
+                    result.insert("level".into(), (level as i64).into());
+                    result.insert("address".into(), (address as i64).into());
+                }
+            }
+            Dynamic::from(result)
+        },
+    );
+
+    let ctx = context.clone();
     engine.register_fn("read_stack", move |task: RhaiThread, offset: i64| -> Dynamic {
-        let Ok(regs) = util::ptrace::get_regs(task.pid as _) else {
+        let Ok(regs) = ctx.target.regs(task.pid as _) else {
             return Dynamic::UNIT;
         };
-        integer_to_dynamic(util::mem::read::<i64>(thread_leader, regs.rsp as usize - offset as usize).ok())
+        integer_to_dynamic(ctx.target.read::<i64>(regs.rsp.wrapping_sub(offset as u64)).ok())
     });
 
+    let ctx = context.clone();
     engine.register_fn("map_entry", move |address: i64| -> Dynamic {
-        let maps = context.maps();
-        let Some(map) = maps.iter().find(|map| {
-            address >= map.start as i64 && address < map.end as i64
-        }) else {
+        let maps = ctx.fresh_maps();
+        let Some(map) = maps.iter().find(|map| map.contains(address as u64)) else {
             return Dynamic::UNIT;
         };
-        let mut result = rhai::Map::new();
-        result.insert("start".into(), (map.start as i64).into());
-        result.insert("end".into(), (map.end as i64).into());
-        result.insert("length".into(), ((map.end - map.start) as i64).into());
-        result.insert("permissions".into(), map.permissions.to_string().into());
-        result.insert("offset".into(), (map.offset as i64).into());
-        result.insert("device".into(), map.device.to_string().into());
-        Dynamic::from(result)
+        memory_map_to_dynamic(map)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("maps", move || -> rhai::Array {
+        ctx.fresh_maps().iter().map(memory_map_to_dynamic).collect()
+    });
+
+    // Maps are refreshed as they are used, this forces a refresh and returns what changed. The
+    // changes also reach on_map_change callbacks.
+    let ctx = context.clone();
+    engine.register_fn("refresh_maps", move || -> Dynamic {
+        match ctx.maps.lock().unwrap().refresh() {
+            Ok(changes) => map_changes_to_dynamic(&changes),
+            Err(_) => Dynamic::UNIT,
+        }
+    });
+
+    engine.register_fn("on_map_change", move |callback: rhai::FnPtr| {
+        if context.unsupported_offline("Map change callbacks") {
+            return;
+        }
+        context.debugger().callbacks.push(RuntimeCallback::MapChanged(callback));
     });
 }
@@ -1,36 +1,124 @@
 use std::sync::{mpsc, Arc, Mutex, MutexGuard};
 
 use anyhow::Result;
+use log::{debug, error};
 use rhai::{Engine, AST};
 
-use crate::{debugger::Debugger, util::procfs::MemoryMap, Event};
+use crate::{
+    debugger::Debugger, elf::ElfCache, freeze::Freezes, maps::MemoryMaps, patch::PatchJournal, stats::Stats,
+    symbolize::Symbolizer, target::Target, Event,
+};
 
 pub mod mem;
 pub mod bp;
+mod crash;
 mod io;
 mod regs;
 mod thread;
 mod http;
+mod disasm;
+mod dwarf;
+mod elf;
 mod flow;
+mod freeze;
+mod module;
+mod patch;
+mod scan;
+mod snapshot;
+mod strings;
+mod structs;
+mod unwind;
+mod value;
 
+pub use crash::crash_report_to_dynamic;
+pub use mem::map_changes_to_dynamic;
 pub use regs::*;
 pub use thread::*;
 
+/// What the script runtime can reach of the debugger. `Debugger::run` holds the debugger lock while
+/// it calls script callbacks, so the state that callbacks work on (maps, patches, freezes, stats,
+/// ...) has its own lock, shared with the debugger, instead of being reached through it.
 #[derive(Clone)]
 pub struct Context {
     pub debugger: Arc<Mutex<Debugger>>,
-    pub maps: Arc<Mutex<Vec<MemoryMap>>>,
+    /// Where scripts read memory, registers and threads from, a live process or a core file
+    pub target: Arc<dyn Target>,
+    pub maps: Arc<Mutex<MemoryMaps>>,
+    pub patches: Arc<Mutex<PatchJournal>>,
+    pub freezes: Arc<Mutex<Freezes>>,
+    pub stats: Arc<Mutex<Stats>>,
+    pub elves: Arc<Mutex<ElfCache>>,
+    pub symbols: Arc<Mutex<Symbolizer>>,
     pub tx: mpsc::Sender<Event>,
 }
 
 impl Context {
-    pub fn new(debugger: Arc<Mutex<Debugger>>, maps: Arc<Mutex<Vec<MemoryMap>>>, tx: mpsc::Sender<Event>) -> Self {
-        Self { debugger, maps, tx }
+    /// Share the debugger with the script runtime, along with its separately locked state.
+    // The debugger holds script callbacks and the target, which aren't Send, but it is only ever
+    // used from the main thread. Scripts need the Mutex for try_lock while a callback holds it.
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub fn new(debugger: Debugger, tx: mpsc::Sender<Event>) -> Self {
+        Self {
+            target: debugger.target.clone(),
+            maps: debugger.maps.clone(),
+            patches: debugger.patches.clone(),
+            freezes: debugger.freezes.clone(),
+            stats: debugger.stats.clone(),
+            elves: debugger.elves.clone(),
+            symbols: debugger.symbols.clone(),
+            debugger: Arc::new(Mutex::new(debugger)),
+            tx,
+        }
     }
 
-    /// Get a lock on the memory maps
-    pub fn maps(&self) -> MutexGuard<Vec<MemoryMap>> {
-        self.maps.lock().unwrap()
+    /// Get a lock on the memory maps, re-read unless they were read very recently
+    pub fn maps(&self) -> MutexGuard<'_, MemoryMaps> {
+        let mut maps = self.maps.lock().unwrap();
+        maps.refresh_if_stale();
+        maps
+    }
+
+    /// Get a lock on the memory maps, re-read right now. Failures keep the old maps.
+    pub fn fresh_maps(&self) -> MutexGuard<'_, MemoryMaps> {
+        let mut maps = self.maps.lock().unwrap();
+        if let Err(e) = maps.refresh() {
+            debug!("Failed to refresh memory maps: {}", e);
+        }
+        maps
+    }
+
+    /// Get a lock on the patch journal
+    pub fn patches(&self) -> MutexGuard<'_, PatchJournal> {
+        self.patches.lock().unwrap()
+    }
+
+    /// Get a lock on the frozen values
+    pub fn freezes(&self) -> MutexGuard<'_, Freezes> {
+        self.freezes.lock().unwrap()
+    }
+
+    /// Get a lock on the breakpoint statistics
+    pub fn stats(&self) -> MutexGuard<'_, Stats> {
+        self.stats.lock().unwrap()
+    }
+
+    /// Get a lock on the cache of parsed ELF files
+    pub fn elves(&self) -> MutexGuard<'_, ElfCache> {
+        self.elves.lock().unwrap()
+    }
+
+    /// Get a lock on the symbolizer
+    pub fn symbols(&self) -> MutexGuard<'_, Symbolizer> {
+        self.symbols.lock().unwrap()
+    }
+
+    /// Log an error and return true when `feature` needs a live process but the target is a core file.
+    pub fn unsupported_offline(&self, feature: &str) -> bool {
+        if self.target.is_live() {
+            return false;
+        }
+        error!("{} aren't supported on core files", feature);
+        true
     }
 
     /// Get a lock on the debugger
@@ -70,11 +158,26 @@ pub fn register_functions(engine: &mut Engine, context: Context) {
     io::register_functions(engine, context.clone());
     http::register_functions(engine);
     regs::register_functions(engine, context.clone());
-    flow::register_functions(engine, context);
+    flow::register_functions(engine, context.clone());
+    patch::register_functions(engine, context.clone());
+    scan::register_functions(engine, context.clone());
+    snapshot::register_functions(engine, context.clone());
+    strings::register_functions(engine, context.clone());
+    structs::register_functions(engine, context.clone());
+    freeze::register_functions(engine, context.clone());
+    module::register_functions(engine, context.clone());
+    elf::register_functions(engine, context.clone());
+    dwarf::register_functions(engine, context.clone());
+    unwind::register_functions(engine, context.clone());
+    thread::register_functions(engine, context.clone());
+    crash::register_functions(engine, context.clone());
+    disasm::register_functions(engine, context);
 }
 
 pub enum RuntimeCallback {
     Breakpoint(usize, rhai::FnPtr),
     ThreadCreated(rhai::FnPtr),
     ThreadExited(rhai::FnPtr),
+    MapChanged(rhai::FnPtr),
+    Crashed(rhai::FnPtr),
 }
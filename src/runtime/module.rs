@@ -0,0 +1,90 @@
+//! Loaded module (executable and shared library) functions
+
+use rhai::{Dynamic, Engine};
+
+use crate::{module::Module, target::Target};
+
+use super::Context;
+
+pub fn module_to_dynamic(module: &Module, target: &dyn Target) -> Dynamic {
+    let mut result = rhai::Map::new();
+    result.insert("name".into(), module.name.clone().into());
+    result.insert("path".into(), module.path.clone().into());
+    result.insert("base".into(), (module.base as i64).into());
+    result.insert("end".into(), (module.end as i64).into());
+    result.insert(
+        "executable".into(),
+        module
+            .executable
+            .iter()
+            .map(|(start, end)| {
+                let mut range = rhai::Map::new();
+                range.insert("start".into(), (*start as i64).into());
+                range.insert("end".into(), (*end as i64).into());
+                Dynamic::from(range)
+            })
+            .collect::<rhai::Array>()
+            .into(),
+    );
+    result.insert(
+        "build_id".into(),
+        module
+            .build_id(target)
+            .map(|id| id.iter().map(|b| format!("{:02x}", b)).collect::<String>().into())
+            .unwrap_or(Dynamic::UNIT),
+    );
+    Dynamic::from(result)
+}
+
+pub fn register_functions(engine: &mut Engine, context: Context) {
+    let ctx = context.clone();
+    engine.register_fn("modules", move || -> rhai::Array {
+        Module::from_maps(&ctx.maps())
+            .iter()
+            .map(|module| module_to_dynamic(module, &*ctx.target))
+            .collect()
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("module_base", move |name: &str| -> Dynamic {
+        Module::from_maps(&ctx.maps())
+            .iter()
+            .find(|module| module.matches(name))
+            .map(|module| Dynamic::from(module.base as i64))
+            .unwrap_or(Dynamic::UNIT)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("module_at", move |address: i64| -> Dynamic {
+        Module::from_maps(&ctx.maps())
+            .iter()
+            .find(|module| module.contains(address as u64))
+            .map(|module| module_to_dynamic(module, &*ctx.target))
+            .unwrap_or(Dynamic::UNIT)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("symbolize", move |address: i64| -> Dynamic {
+        ctx.symbols()
+            .symbolize(address as u64)
+            .map(|symbolized| Dynamic::from(symbolized.to_string()))
+            .unwrap_or(Dynamic::UNIT)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("symbolize_map", move |address: i64| -> Dynamic {
+        let Some(symbolized) = ctx.symbols().symbolize(address as u64) else {
+            return Dynamic::UNIT;
+        };
+        let mut map = rhai::Map::new();
+        map.insert("module_name".into(), symbolized.module.into());
+        map.insert("module_offset".into(), (symbolized.module_offset as i64).into());
+        let (symbol, offset) = match symbolized.symbol {
+            Some((name, offset)) => (Dynamic::from(name), Dynamic::from(offset as i64)),
+            None => (Dynamic::UNIT, Dynamic::UNIT),
+        };
+        map.insert("symbol".into(), symbol);
+        map.insert("offset".into(), offset);
+        Dynamic::from(map)
+    });
+}
@@ -0,0 +1,56 @@
+//! Journaled memory patches, reverted automatically on reload and exit
+
+use log::error;
+use rhai::{Dynamic, Engine};
+
+use super::Context;
+
+pub fn register_functions(engine: &mut Engine, context: Context) {
+    let ctx = context.clone();
+    engine.register_fn("patch", move |address: Dynamic, bytes: rhai::Blob| -> Dynamic {
+        let address = address.as_int().unwrap_or(-1);
+        if address < 0 {
+            return Dynamic::UNIT;
+        }
+        match ctx.patches().apply(address as _, &bytes) {
+            Ok(id) => Dynamic::from(id as i64),
+            Err(e) => {
+                error!("Failed to patch {:#x}: {}", address, e);
+                Dynamic::UNIT
+            }
+        }
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("patches", move || -> rhai::Array {
+        ctx.patches()
+            .patches
+            .iter()
+            .map(|patch| {
+                let mut map = rhai::Map::new();
+                map.insert("id".into(), (patch.id as i64).into());
+                map.insert("address".into(), (patch.address as i64).into());
+                map.insert("length".into(), (patch.patched.len() as i64).into());
+                map.insert("original".into(), Dynamic::from_blob(patch.original.clone()));
+                map.insert("patched".into(), Dynamic::from_blob(patch.patched.clone()));
+                Dynamic::from(map)
+            })
+            .collect()
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("revert_patch", move |id: i64| -> bool {
+        match ctx.patches().revert(id as _) {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Failed to revert patch: {}", e);
+                false
+            }
+        }
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("revert_patches", move || {
+        ctx.patches().revert_all();
+    });
+}
@@ -1,3 +1,4 @@
+use log::error;
 use rhai::{CustomType, FuncArgs, Map, TypeBuilder};
 
 use crate::{
@@ -312,15 +313,13 @@ impl TryFrom<rhai::Map> for RhaiFpRegisters {
 }
 
 pub fn register_functions(engine: &mut rhai::Engine, context: Context) {
-    let ctx = context.clone();
-    let thread_leader = ctx.debugger().threads[0].pid;
-
     // engine.register_fn("set_fp_registers", move |task: RhaiThread, fp_registers: RhaiFpRegisters| {
     //     util::ptrace::set_fp_regs(task.pid as _, &(<FpRegisters as From<&RhaiFpRegisters>>::from(&fp_registers)).into()).ok();
     // });
 
+    let ctx = context.clone();
     engine.register_fn("get_xmm", move |task: RhaiThread, reg: i64, idx: i64| {
-        match util::ptrace::get_fp_regs(task.pid as _) {
+        match ctx.target.fp_regs(task.pid as _) {
             Ok(fp_regs) => {
                 let value = unsafe {
                     std::mem::transmute::<u32, f32>(fp_regs.xmm_space[reg as usize * 4 + idx as usize])
@@ -359,4 +358,16 @@ pub fn register_functions(engine: &mut rhai::Engine, context: Context) {
             .ok();
         },
     );
+
+    // Registers of a stopped thread, outside of a breakpoint callback (or of any thread in a core file)
+    let ctx = context;
+    engine.register_fn("get_registers", move |task: RhaiThread| -> rhai::Dynamic {
+        match ctx.target.regs(task.pid as _) {
+            Ok(regs) => rhai::Dynamic::from(RhaiRegisters::from(&Registers::from(regs))),
+            Err(e) => {
+                error!("Failed to get registers of {}: {}", task.pid, e);
+                rhai::Dynamic::UNIT
+            }
+        }
+    });
 }
@@ -0,0 +1,531 @@
+//! Memory scanning functions
+
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use log::{error, info};
+use rhai::{CustomType, Dynamic, Engine, TypeBuilder};
+
+use crate::{
+    module::Module,
+    target::Target,
+    util::{pattern::Pattern, procfs::MemoryMap},
+};
+
+use super::{value::ValueType, Context};
+
+const SCAN_CHUNK_SIZE: usize = 1 << 20;
+
+const DEFAULT_POINTER_LEVELS: usize = 3;
+const DEFAULT_POINTER_OFFSET: u64 = 0x1000;
+const DEFAULT_POINTER_PATHS: usize = 100;
+
+/// Pointers followed per level of a pointer scan, which bounds time and memory in large processes
+const MAX_POINTER_FRONTIER: usize = 100_000;
+
+/// Filters for picking which memory maps to scan, parsed from a script's options map.
+#[derive(Default)]
+pub struct ScanOptions {
+    pub module: Option<String>,
+    pub permissions: Option<String>,
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl ScanOptions {
+    pub fn from_map(opts: &rhai::Map) -> Self {
+        let int = |key: &str| opts.get(key).and_then(|v| v.as_int().ok());
+        let string = |key: &str| opts.get(key).and_then(|v| v.clone().into_string().ok());
+        Self {
+            module: string("module_name"),
+            permissions: string("permissions"),
+            start: int("start").map(|v| v as u64),
+            end: int("end").map(|v| v as u64),
+            limit: int("limit").map(|v| v.max(0) as usize),
+        }
+    }
+
+    pub fn matches(&self, map: &MemoryMap) -> bool {
+        if let Some(module) = &self.module {
+            let Some(pathname) = &map.pathname else {
+                return false;
+            };
+            let name = pathname.rsplit('/').next().unwrap_or(pathname);
+            if name != module && pathname != module {
+                return false;
+            }
+        }
+        if let Some(permissions) = &self.permissions {
+            if !map.permissions.starts_with(permissions.as_str()) {
+                return false;
+            }
+        }
+        // Never scan maps that can't be read, or the vsyscall page (process_vm_readv refuses it)
+        map.permissions.starts_with('r') && map.pathname.as_deref() != Some("[vsyscall]")
+    }
+
+    /// The part of `map` that falls within the configured address range, if any.
+    pub fn clamp(&self, map: &MemoryMap) -> Option<(u64, u64)> {
+        let start = map.start.max(self.start.unwrap_or(0));
+        let end = map.end.min(self.end.unwrap_or(u64::MAX));
+        (start < end).then_some((start, end))
+    }
+}
+
+/// Search `[start, end)` for `pattern`, reading memory in large chunks that overlap by the pattern length.
+pub fn scan_region(target: &dyn Target, start: u64, end: u64, pattern: &Pattern, limit: usize, results: &mut Vec<u64>) {
+    let overlap = pattern.len() - 1;
+    let mut address = start;
+    while address < end && results.len() < limit {
+        let len = (SCAN_CHUNK_SIZE as u64).min(end - address) as usize;
+        let read_len = (len + overlap).min((end - address) as usize);
+        if let Ok(chunk) = target.read_bytes(address, read_len) {
+            for offset in pattern.find_all(&chunk) {
+                // Matches starting in the overlap are picked up by the next chunk
+                if offset >= len || results.len() >= limit {
+                    break;
+                }
+                results.push(address + offset as u64);
+            }
+        }
+        address += len as u64;
+    }
+}
+
+/// Addresses found by a value scan, along with the value each held when last (re)scanned.
+/// Kept on the native side so that scripts only ever see counts and samples.
+pub struct ValueScan {
+    pub kind: ValueType,
+    pub addresses: Vec<u64>,
+    pub values: Vec<u8>,
+}
+
+pub enum ScanPredicate {
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+    Equals(Vec<u8>),
+}
+
+impl ScanPredicate {
+    pub fn parse(name: &str, kind: ValueType, value: Option<&Dynamic>) -> Result<Self> {
+        let predicate = match name {
+            "changed" => ScanPredicate::Changed,
+            "unchanged" => ScanPredicate::Unchanged,
+            "increased" => ScanPredicate::Increased,
+            "decreased" => ScanPredicate::Decreased,
+            "equals" => {
+                let value = value.ok_or(anyhow::anyhow!("\"equals\" needs a value to compare against"))?;
+                ScanPredicate::Equals(kind.encode(value)?)
+            }
+            _ => return Err(anyhow::anyhow!("Unknown scan predicate: {}", name)),
+        };
+        if matches!(predicate, ScanPredicate::Increased | ScanPredicate::Decreased) && !kind.is_numeric() {
+            return Err(anyhow::anyhow!("\"{}\" only applies to numeric types", name));
+        }
+        Ok(predicate)
+    }
+
+    pub fn test(&self, kind: ValueType, old: &[u8], new: &[u8]) -> bool {
+        match self {
+            ScanPredicate::Changed => old != new,
+            ScanPredicate::Unchanged => old == new,
+            ScanPredicate::Increased => kind.compare(new, old) == Some(Ordering::Greater),
+            ScanPredicate::Decreased => kind.compare(new, old) == Some(Ordering::Less),
+            ScanPredicate::Equals(value) => new == value.as_slice(),
+        }
+    }
+}
+
+impl ValueScan {
+    /// Find every (aligned) occurrence of `value` in the maps selected by `opts`.
+    pub fn first_scan(target: &dyn Target, maps: &[MemoryMap], kind: ValueType, value: &[u8], opts: &ScanOptions) -> Self {
+        let size = kind.size();
+        let alignment = kind.alignment();
+        let limit = opts.limit.unwrap_or(usize::MAX);
+        let mut addresses = Vec::new();
+        let mut values = Vec::new();
+
+        'maps: for map in maps.iter().filter(|map| opts.matches(map)) {
+            let Some((start, end)) = opts.clamp(map) else {
+                continue;
+            };
+            let mut address = start.next_multiple_of(alignment as u64);
+            while address < end {
+                let len = (SCAN_CHUNK_SIZE as u64).min(end - address) as usize;
+                let read_len = (len + size - 1).min((end - address) as usize);
+                if let Ok(chunk) = target.read_bytes(address, read_len) {
+                    let mut offset = 0;
+                    while offset < len && offset + size <= chunk.len() {
+                        if chunk[offset..offset + size] == *value {
+                            if addresses.len() >= limit {
+                                break 'maps;
+                            }
+                            addresses.push(address + offset as u64);
+                            values.extend_from_slice(value);
+                        }
+                        offset += alignment;
+                    }
+                }
+                address += len as u64;
+            }
+        }
+
+        Self { kind, addresses, values }
+    }
+
+    /// Re-read every result, keeping only those for which `predicate` holds.
+    /// Results that can no longer be read are discarded.
+    pub fn rescan(&mut self, target: &dyn Target, maps: &[MemoryMap], predicate: &ScanPredicate) {
+        let size = self.kind.size();
+        let current = read_values(target, maps, &self.addresses, size);
+        let mut addresses = Vec::new();
+        let mut values = Vec::new();
+        for (i, new) in current.iter().enumerate() {
+            let Some(new) = new else {
+                continue;
+            };
+            let old = &self.values[i * size..(i + 1) * size];
+            if predicate.test(self.kind, old, new) {
+                addresses.push(self.addresses[i]);
+                values.extend_from_slice(new);
+            }
+        }
+        self.addresses = addresses;
+        self.values = values;
+    }
+
+    pub fn value(&self, index: usize) -> Dynamic {
+        let size = self.kind.size();
+        self.kind.decode(&self.values[index * size..(index + 1) * size])
+    }
+}
+
+/// Read `size` bytes at each of the (sorted) `addresses`, batching nearby addresses into a single read.
+fn read_values(target: &dyn Target, maps: &[MemoryMap], addresses: &[u64], size: usize) -> Vec<Option<Vec<u8>>> {
+    let mut chunk: Option<(u64, Vec<u8>)> = None;
+    addresses
+        .iter()
+        .map(|&address| {
+            let in_chunk = |(start, data): &(u64, Vec<u8>)| {
+                address >= *start && address + size as u64 <= start + data.len() as u64
+            };
+            if !chunk.as_ref().is_some_and(in_chunk) {
+                let map = maps.iter().find(|map| address >= map.start && address < map.end)?;
+                let len = (SCAN_CHUNK_SIZE as u64).min(map.end - address) as usize;
+                chunk = target.read_bytes(address, len).ok().map(|data| (address, data));
+            }
+            let (start, data) = chunk.as_ref().filter(|chunk| in_chunk(chunk))?;
+            let offset = (address - start) as usize;
+            Some(data[offset..offset + size].to_vec())
+        })
+        .collect()
+}
+
+/// Options of a pointer scan, parsed from a script's options map.
+pub struct PointerScanOptions {
+    /// Longest path, in pointers
+    pub levels: usize,
+    /// Largest offset from a pointer to the address the path continues at
+    pub max_offset: u64,
+    pub limit: usize,
+    /// Only accept paths starting in this module
+    pub module: Option<String>,
+}
+
+impl PointerScanOptions {
+    pub fn from_map(opts: &rhai::Map) -> Self {
+        let int = |key: &str| opts.get(key).and_then(|v| v.as_int().ok()).map(|v| v.max(0));
+        Self {
+            levels: int("levels").map_or(DEFAULT_POINTER_LEVELS, |v| v as usize),
+            max_offset: int("max_offset").map_or(DEFAULT_POINTER_OFFSET, |v| v as u64),
+            limit: int("limit").map_or(DEFAULT_POINTER_PATHS, |v| v as usize),
+            module: opts.get("module_name").and_then(|v| v.clone().into_string().ok()),
+        }
+    }
+}
+
+/// A way to reach an address from a module's static data, that still works after the process
+/// restarts: read the pointer at `module base + module_offset`, then add each offset, reading a
+/// pointer again between offsets.
+pub struct PointerPath {
+    pub module: String,
+    pub module_offset: u64,
+    pub offsets: Vec<u64>,
+}
+
+impl PointerPath {
+    /// Follow the path in the current process, wherever the module is loaded now.
+    pub fn resolve(&self, target: &dyn Target, modules: &[Module]) -> Option<u64> {
+        let module = modules.iter().find(|module| module.matches(&self.module))?;
+        let mut address = module.base + self.module_offset;
+        for offset in &self.offsets {
+            address = target.read::<u64>(address).ok()?.wrapping_add(*offset);
+        }
+        Some(address)
+    }
+}
+
+impl fmt::Display for PointerPath {
+    /// In the usual `[[module+0x1234]+0x10]+0x8` notation
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut text = format!("{}+{:#x}", self.module, self.module_offset);
+        for offset in &self.offsets {
+            text = format!("[{}]+{:#x}", text, offset);
+        }
+        f.write_str(&text)
+    }
+}
+
+/// Every aligned qword in writable memory that points into mapped memory, as (value, location)
+/// sorted by value, so that pointers into a range can be looked up by binary search.
+fn pointer_map(target: &dyn Target, maps: &[MemoryMap]) -> Vec<(u64, u64)> {
+    let mapped = maps
+        .iter()
+        .filter(|map| map.permissions.starts_with('r'))
+        .map(|map| (map.start, map.end))
+        .collect::<Vec<_>>();
+    let is_mapped = |value: u64| {
+        let index = mapped.partition_point(|(start, _)| *start <= value);
+        index > 0 && value < mapped[index - 1].1
+    };
+    let mut pointers = Vec::new();
+    for map in maps.iter().filter(|map| map.permissions.starts_with("rw")) {
+        if map.pathname.as_deref().is_some_and(|path| path == "[vsyscall]" || path == "[vvar]") {
+            continue;
+        }
+        let mut address = map.start;
+        while address < map.end {
+            let len = (SCAN_CHUNK_SIZE as u64).min(map.end - address) as usize;
+            if let Ok(chunk) = target.read_bytes(address, len) {
+                for (i, qword) in chunk.chunks_exact(8).enumerate() {
+                    let value = u64::from_le_bytes(qword.try_into().unwrap());
+                    if is_mapped(value) {
+                        pointers.push((value, address + i as u64 * 8));
+                    }
+                }
+            }
+            address += len as u64;
+        }
+    }
+    pointers.sort_unstable();
+    pointers
+}
+
+/// Find pointer paths from module data to `destination`, shortest first, by walking backwards from
+/// `destination` through every pointer that points at most `max_offset` below it.
+pub fn find_pointer_paths(target: &dyn Target, maps: &[MemoryMap], destination: u64, opts: &PointerScanOptions) -> Vec<PointerPath> {
+    let modules = Module::from_maps(maps);
+    // A module's data is its writable maps, plus the .bss right after them
+    let statics = maps
+        .iter()
+        .filter(|map| map.permissions.starts_with("rw"))
+        .filter_map(|map| {
+            let module = modules.iter().find(|module| {
+                module.contains(map.start) || (map.pathname.is_none() && module.end == map.start)
+            })?;
+            let wanted = opts.module.as_ref().is_none_or(|name| module.matches(name));
+            wanted.then_some((map.start, map.end, module))
+        })
+        .collect::<Vec<_>>();
+    let pointers = pointer_map(target, maps);
+
+    let mut paths = Vec::new();
+    let mut visited = HashSet::from([destination]);
+    let mut frontier = vec![(destination, Vec::new())];
+    for _ in 0..opts.levels {
+        let mut next = Vec::new();
+        for (address, offsets) in &frontier {
+            let first = pointers.partition_point(|(value, _)| *value < address.saturating_sub(opts.max_offset));
+            for &(value, location) in pointers[first..].iter().take_while(|(value, _)| value <= address) {
+                if !visited.insert(location) {
+                    continue;
+                }
+                let mut path = vec![address - value];
+                path.extend_from_slice(offsets);
+                match statics.iter().find(|(start, end, _)| (*start..*end).contains(&location)) {
+                    Some((_, _, module)) => {
+                        paths.push(PointerPath {
+                            module: module.name.clone(),
+                            module_offset: location - module.base,
+                            offsets: path,
+                        });
+                        if paths.len() >= opts.limit {
+                            return paths;
+                        }
+                    }
+                    None => next.push((location, path)),
+                }
+            }
+        }
+        if next.len() > MAX_POINTER_FRONTIER {
+            info!("Pointer scan: only following {} of {} pointers", MAX_POINTER_FRONTIER, next.len());
+            next.truncate(MAX_POINTER_FRONTIER);
+        }
+        frontier = next;
+    }
+    paths
+}
+
+fn pointer_path_to_dynamic(path: &PointerPath) -> Dynamic {
+    let mut map = rhai::Map::new();
+    map.insert("module_name".into(), path.module.clone().into());
+    map.insert("module_offset".into(), (path.module_offset as i64).into());
+    map.insert(
+        "offsets".into(),
+        path.offsets.iter().map(|offset| Dynamic::from(*offset as i64)).collect::<rhai::Array>().into(),
+    );
+    map.insert("text".into(), path.to_string().into());
+    Dynamic::from(map)
+}
+
+fn pointer_path_from_dynamic(path: &rhai::Map) -> Option<PointerPath> {
+    Some(PointerPath {
+        module: path.get("module_name")?.clone().into_string().ok()?,
+        module_offset: path.get("module_offset")?.as_int().ok()? as u64,
+        offsets: path
+            .get("offsets")?
+            .clone()
+            .try_cast::<rhai::Array>()?
+            .iter()
+            .map(|offset| offset.as_int().ok().map(|offset| offset as u64))
+            .collect::<Option<_>>()?,
+    })
+}
+
+/// Script handle to a native [`ValueScan`].
+#[derive(Clone)]
+pub struct ScanResults(Arc<Mutex<ValueScan>>);
+
+impl ScanResults {
+    fn sample(&mut self, count: i64) -> rhai::Array {
+        let scan = self.0.lock().unwrap();
+        (0..scan.addresses.len().min(count.max(0) as usize))
+            .map(|i| {
+                let mut map = rhai::Map::new();
+                map.insert("address".into(), (scan.addresses[i] as i64).into());
+                map.insert("value".into(), scan.value(i));
+                Dynamic::from(map)
+            })
+            .collect()
+    }
+}
+
+impl CustomType for ScanResults {
+    fn build(mut builder: TypeBuilder<Self>) {
+        builder
+            .with_name("ScanResults")
+            .with_get("count", |results: &mut Self| results.0.lock().unwrap().addresses.len() as i64)
+            .with_fn("sample", Self::sample)
+            .with_fn("to_string", |results: &mut Self| {
+                format!("ScanResults({} results)", results.0.lock().unwrap().addresses.len())
+            });
+    }
+}
+
+pub fn register_functions(engine: &mut Engine, context: Context) {
+    let ctx = context.clone();
+    let scan = move |pattern: &str, opts: rhai::Map| -> Dynamic {
+        let pattern = match Pattern::parse(pattern) {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                error!("Invalid scan pattern: {}", e);
+                return Dynamic::UNIT;
+            }
+        };
+        let opts = ScanOptions::from_map(&opts);
+        let limit = opts.limit.unwrap_or(usize::MAX);
+        let mut results = Vec::new();
+        for map in ctx.maps().iter().filter(|map| opts.matches(map)) {
+            if let Some((start, end)) = opts.clamp(map) {
+                scan_region(&*ctx.target, start, end, &pattern, limit, &mut results);
+            }
+        }
+        results.into_iter().map(|address| Dynamic::from(address as i64)).collect::<rhai::Array>().into()
+    };
+
+    let scan_all = scan.clone();
+    engine.register_fn("scan", move |pattern: &str| -> Dynamic { scan_all(pattern, rhai::Map::new()) });
+    engine.register_fn("scan", scan);
+
+    engine.build_type::<ScanResults>();
+
+    let ctx = context.clone();
+    let scan_value = move |kind: &str, value: Dynamic, opts: rhai::Map| -> Dynamic {
+        let encoded = ValueType::parse(kind, &value).and_then(|kind| Ok((kind, kind.encode(&value)?)));
+        let (kind, value) = match encoded {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                error!("Invalid value scan: {}", e);
+                return Dynamic::UNIT;
+            }
+        };
+        let mut opts = ScanOptions::from_map(&opts);
+        opts.permissions.get_or_insert("rw".into());
+        let scan = ValueScan::first_scan(&*ctx.target, &ctx.maps(), kind, &value, &opts);
+        info!("Value scan found {} results", scan.addresses.len());
+        Dynamic::from(ScanResults(Arc::new(Mutex::new(scan))))
+    };
+
+    let scan_value_all = scan_value.clone();
+    engine.register_fn("scan_value", move |kind: &str, value: Dynamic| -> Dynamic {
+        scan_value_all(kind, value, rhai::Map::new())
+    });
+    engine.register_fn("scan_value", scan_value);
+
+    let ctx = context.clone();
+    let rescan = move |results: ScanResults, predicate: &str, value: Option<Dynamic>| -> Dynamic {
+        let mut scan = results.0.lock().unwrap();
+        let predicate = match ScanPredicate::parse(predicate, scan.kind, value.as_ref()) {
+            Ok(predicate) => predicate,
+            Err(e) => {
+                error!("Invalid rescan: {}", e);
+                return Dynamic::UNIT;
+            }
+        };
+        scan.rescan(&*ctx.target, &ctx.maps(), &predicate);
+        Dynamic::from(scan.addresses.len() as i64)
+    };
+
+    let rescan_compare = rescan.clone();
+    engine.register_fn("rescan", move |results: ScanResults, predicate: &str| -> Dynamic {
+        rescan_compare(results, predicate, None)
+    });
+    engine.register_fn("rescan", move |results: ScanResults, predicate: &str, value: Dynamic| -> Dynamic {
+        rescan(results, predicate, Some(value))
+    });
+
+    let ctx = context.clone();
+    let pointer_scan = move |target: i64, opts: rhai::Map| -> rhai::Array {
+        let opts = PointerScanOptions::from_map(&opts);
+        let maps = ctx.maps().clone();
+        let paths = find_pointer_paths(&*ctx.target, &maps, target as u64, &opts);
+        info!("Pointer scan found {} paths to {:#x}", paths.len(), target);
+        paths.iter().map(pointer_path_to_dynamic).collect()
+    };
+
+    let pointer_scan_default = pointer_scan.clone();
+    engine.register_fn("pointer_scan", move |target: i64| -> rhai::Array {
+        pointer_scan_default(target, rhai::Map::new())
+    });
+    engine.register_fn("pointer_scan", pointer_scan);
+
+    let ctx = context;
+    engine.register_fn("resolve_pointer_path", move |path: rhai::Map| -> Dynamic {
+        let Some(path) = pointer_path_from_dynamic(&path) else {
+            error!("Invalid pointer path, expected #{{ module_name, module_offset, offsets }}");
+            return Dynamic::UNIT;
+        };
+        let modules = Module::from_maps(&ctx.maps());
+        path.resolve(&*ctx.target, &modules)
+            .map(|address| Dynamic::from(address as i64))
+            .unwrap_or(Dynamic::UNIT)
+    });
+}
@@ -0,0 +1,359 @@
+//! Copies of the target's memory: snapshots, finding what changed between them, and core dumps
+
+use std::{fs, sync::Arc};
+
+use anyhow::{anyhow, bail, Result};
+use log::{error, info};
+use rhai::{CustomType, Dynamic, Engine, TypeBuilder};
+
+use crate::{elf::coredump, target::Target};
+
+use super::{scan::ScanOptions, Context};
+
+const SNAPSHOT_CHUNK_SIZE: u64 = 1 << 20;
+
+/// Start of a snapshot file, followed by the region count and each region as start, length, bytes
+const SNAPSHOT_MAGIC: &[u8; 8] = b"XSNAP\x00\x00\x01";
+
+/// A run of memory copied from the target.
+struct Region {
+    start: u64,
+    bytes: Vec<u8>,
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.start + self.bytes.len() as u64
+    }
+}
+
+/// Memory copied out of the target at one point in time. Parts that couldn't be read are left
+/// out, so a snapshot can have holes where its ranges asked for more.
+#[derive(Default)]
+pub struct Snapshot {
+    regions: Vec<Region>,
+}
+
+impl Snapshot {
+    /// Copy `[start, end)` ranges, a chunk at a time so an unreadable page only loses its chunk.
+    pub fn capture(target: &dyn Target, ranges: &[(u64, u64)]) -> Self {
+        let mut snapshot = Snapshot::default();
+        for &(start, end) in ranges {
+            let mut address = start;
+            while address < end {
+                let len = SNAPSHOT_CHUNK_SIZE.min(end - address);
+                if let Ok(bytes) = target.read_bytes(address, len as _) {
+                    snapshot.push(address, bytes);
+                }
+                address += len;
+            }
+        }
+        snapshot
+    }
+
+    /// Add bytes at `start`, extending the last region if they follow it
+    fn push(&mut self, start: u64, bytes: Vec<u8>) {
+        match self.regions.last_mut() {
+            Some(last) if last.end() == start => last.bytes.extend_from_slice(&bytes),
+            _ => self.regions.push(Region { start, bytes }),
+        }
+    }
+
+    fn ranges(&self) -> Vec<(u64, u64)> {
+        self.regions.iter().map(|region| (region.start, region.end())).collect()
+    }
+
+    pub fn size(&self) -> usize {
+        self.regions.iter().map(|region| region.bytes.len()).sum()
+    }
+
+    /// Every run of bytes that differs where both snapshots have memory, as (address, old, new).
+    pub fn diff(&self, other: &Snapshot) -> Vec<(u64, Vec<u8>, Vec<u8>)> {
+        let mut changes = Vec::new();
+        for old in &self.regions {
+            for new in other.regions.iter().filter(|new| new.start < old.end() && old.start < new.end()) {
+                let start = old.start.max(new.start);
+                let end = old.end().min(new.end());
+                let old_bytes = &old.bytes[(start - old.start) as usize..(end - old.start) as usize];
+                let new_bytes = &new.bytes[(start - new.start) as usize..(end - new.start) as usize];
+                let mut i = 0;
+                while i < old_bytes.len() {
+                    if old_bytes[i] == new_bytes[i] {
+                        i += 1;
+                        continue;
+                    }
+                    let run = old_bytes[i..].iter().zip(&new_bytes[i..]).take_while(|(a, b)| a != b).count();
+                    changes.push((start + i as u64, old_bytes[i..i + run].to_vec(), new_bytes[i..i + run].to_vec()));
+                    i += run;
+                }
+            }
+        }
+        changes
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        data.extend_from_slice(&(self.regions.len() as u64).to_le_bytes());
+        for region in &self.regions {
+            data.extend_from_slice(&region.start.to_le_bytes());
+            data.extend_from_slice(&(region.bytes.len() as u64).to_le_bytes());
+            data.extend_from_slice(&region.bytes);
+        }
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let data = fs::read(path)?;
+        let Some(mut rest) = data.strip_prefix(SNAPSHOT_MAGIC.as_slice()) else {
+            bail!("{} isn't a snapshot", path);
+        };
+        let truncated = || anyhow!("{} is truncated", path);
+        let take = |rest: &mut &'_ [u8], len: usize| -> Option<Vec<u8>> {
+            let taken = rest.get(..len)?.to_vec();
+            *rest = &rest[len..];
+            Some(taken)
+        };
+        let take_u64 = |rest: &mut &'_ [u8]| take(rest, 8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()));
+        let mut snapshot = Snapshot::default();
+        for _ in 0..take_u64(&mut rest).ok_or_else(truncated)? {
+            let start = take_u64(&mut rest).ok_or_else(truncated)?;
+            let len = take_u64(&mut rest).ok_or_else(truncated)? as usize;
+            let bytes = take(&mut rest, len).ok_or_else(truncated)?;
+            snapshot.regions.push(Region { start, bytes });
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Script handle to a native [`Snapshot`].
+#[derive(Clone)]
+pub struct RhaiSnapshot(Arc<Snapshot>);
+
+impl CustomType for RhaiSnapshot {
+    fn build(mut builder: TypeBuilder<Self>) {
+        builder
+            .with_name("Snapshot")
+            .with_get("size", |snapshot: &mut Self| snapshot.0.size() as i64)
+            .with_get("regions", |snapshot: &mut Self| {
+                snapshot
+                    .0
+                    .ranges()
+                    .into_iter()
+                    .map(|(start, end)| {
+                        let mut map = rhai::Map::new();
+                        map.insert("start".into(), (start as i64).into());
+                        map.insert("end".into(), (end as i64).into());
+                        Dynamic::from(map)
+                    })
+                    .collect::<rhai::Array>()
+            })
+            .with_fn("save", |snapshot: &mut Self, path: &str| match snapshot.0.save(path) {
+                Ok(()) => true,
+                Err(e) => {
+                    error!("Failed to save snapshot to {}: {}", path, e);
+                    false
+                }
+            })
+            .with_fn("to_string", |snapshot: &mut Self| {
+                format!("Snapshot({} regions, {} bytes)", snapshot.0.regions.len(), snapshot.0.size())
+            });
+    }
+}
+
+fn changes_to_dynamic(changes: Vec<(u64, Vec<u8>, Vec<u8>)>) -> rhai::Array {
+    changes
+        .into_iter()
+        .map(|(address, old, new)| {
+            let mut map = rhai::Map::new();
+            map.insert("address".into(), (address as i64).into());
+            map.insert("length".into(), (old.len() as i64).into());
+            map.insert("old".into(), Dynamic::from_blob(old));
+            map.insert("new".into(), Dynamic::from_blob(new));
+            Dynamic::from(map)
+        })
+        .collect()
+}
+
+pub fn register_functions(engine: &mut Engine, context: Context) {
+    let thread_leader = context.debugger().pid;
+
+    engine.build_type::<RhaiSnapshot>();
+
+    // A module name, or scan-style options (a map from maps() works too)
+    let ctx = context.clone();
+    engine.register_fn("snapshot", move |target: Dynamic| -> Dynamic {
+        let opts = if let Some(opts) = target.read_lock::<rhai::Map>() {
+            ScanOptions::from_map(&opts)
+        } else if let Ok(module) = target.clone().into_string() {
+            ScanOptions {
+                module: Some(module),
+                ..Default::default()
+            }
+        } else {
+            error!("snapshot expects a module name or a map with start and end");
+            return Dynamic::UNIT;
+        };
+        let ranges = ctx
+            .maps()
+            .iter()
+            .filter(|map| opts.matches(map))
+            .filter_map(|map| opts.clamp(map))
+            .collect::<Vec<_>>();
+        if ranges.is_empty() {
+            error!("Nothing to snapshot for {}", target);
+            return Dynamic::UNIT;
+        }
+        Dynamic::from(RhaiSnapshot(Arc::new(Snapshot::capture(&*ctx.target, &ranges))))
+    });
+
+    // Only the mapped parts of the range, so a huge length doesn't try every chunk of it
+    let ctx = context.clone();
+    engine.register_fn("snapshot", move |address: i64, len: i64| -> Dynamic {
+        if address < 0 || len < 0 {
+            return Dynamic::UNIT;
+        }
+        let opts = ScanOptions {
+            start: Some(address as u64),
+            end: Some((address as u64).saturating_add(len as u64)),
+            ..Default::default()
+        };
+        let ranges = ctx
+            .maps()
+            .iter()
+            .filter(|map| opts.matches(map))
+            .filter_map(|map| opts.clamp(map))
+            .collect::<Vec<_>>();
+        if ranges.is_empty() {
+            error!("Nothing mapped to snapshot at {:#x}", address);
+            return Dynamic::UNIT;
+        }
+        Dynamic::from(RhaiSnapshot(Arc::new(Snapshot::capture(&*ctx.target, &ranges))))
+    });
+
+    engine.register_fn("diff", |old: RhaiSnapshot, new: RhaiSnapshot| -> rhai::Array {
+        changes_to_dynamic(old.0.diff(&new.0))
+    });
+
+    // Against live memory, over the same ranges as the snapshot
+    let ctx = context.clone();
+    engine.register_fn("diff", move |old: RhaiSnapshot| -> rhai::Array {
+        let live = Snapshot::capture(&*ctx.target, &old.0.ranges());
+        changes_to_dynamic(old.0.diff(&live))
+    });
+
+    // Returns whether the core file was written
+    let ctx = context;
+    engine.register_fn("dump_core", move |path: &str| -> bool {
+        if ctx.unsupported_offline("Core dumps") {
+            return false;
+        }
+        // Fresh maps, as the cached ones can be a little behind
+        let maps = ctx.fresh_maps().clone();
+        match coredump::dump_core(thread_leader, &maps, path) {
+            Ok(summary) => {
+                info!(
+                    "Wrote {} ({} threads, {} segments, {} bytes)",
+                    path, summary.threads, summary.segments, summary.size
+                );
+                if summary.skipped_threads > 0 {
+                    info!("Left out {} running threads", summary.skipped_threads);
+                }
+                true
+            }
+            Err(e) => {
+                error!("Failed to dump core to {}: {}", path, e);
+                false
+            }
+        }
+    });
+
+    engine.register_fn("load_snapshot", |path: &str| -> Dynamic {
+        match Snapshot::load(path) {
+            Ok(snapshot) => Dynamic::from(RhaiSnapshot(Arc::new(snapshot))),
+            Err(e) => {
+                error!("Failed to load snapshot from {}: {}", path, e);
+                Dynamic::UNIT
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(regions: &[(u64, &[u8])]) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        for &(start, bytes) in regions {
+            snapshot.push(start, bytes.to_vec());
+        }
+        snapshot
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("xenon3-{}-{}", std::process::id(), name)).to_string_lossy().into()
+    }
+
+    #[test]
+    fn diff_same_ranges() {
+        let old = snapshot(&[(0x1000, &[1, 2, 3, 4, 5, 6])]);
+        let new = snapshot(&[(0x1000, &[1, 9, 9, 4, 5, 7])]);
+        assert_eq!(
+            old.diff(&new),
+            [(0x1001, vec![2, 3], vec![9, 9]), (0x1005, vec![6], vec![7])]
+        );
+        assert!(old.diff(&old).is_empty());
+    }
+
+    #[test]
+    fn diff_partial_overlap() {
+        // Only where both have memory: 0x1002..0x1004 in the first region, 0x2000..0x2002 in the second
+        let old = snapshot(&[(0x1000, &[0, 0, 1, 1]), (0x2000, &[5, 5, 5, 5])]);
+        let new = snapshot(&[(0x1002, &[2, 1, 3, 3]), (0x1ffe, &[8, 8, 6, 5])]);
+        assert_eq!(
+            old.diff(&new),
+            [(0x1002, vec![1], vec![2]), (0x2000, vec![5], vec![6])]
+        );
+        let disjoint = snapshot(&[(0x3000, &[1])]);
+        assert!(old.diff(&disjoint).is_empty());
+    }
+
+    #[test]
+    fn push_merges_adjacent() {
+        let merged = snapshot(&[(0x1000, &[1, 2]), (0x1002, &[3]), (0x2000, &[4])]);
+        assert_eq!(merged.ranges(), [(0x1000, 0x1003), (0x2000, 0x2001)]);
+        assert_eq!(merged.size(), 4);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = temp_path("roundtrip.snap");
+        let saved = snapshot(&[(0x1000, &[1, 2, 3]), (0x7fff_0000, &[]), (u64::MAX - 1, &[0xff])]);
+        saved.save(&path).unwrap();
+        let loaded = Snapshot::load(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.ranges(), saved.ranges());
+        assert!(saved.diff(&loaded).is_empty());
+        assert_eq!(loaded.regions[0].bytes, [1, 2, 3]);
+    }
+
+    #[test]
+    fn load_rejects_bad_files() {
+        let path = temp_path("truncated.snap");
+        snapshot(&[(0x1000, &[1, 2, 3, 4])]).save(&path).unwrap();
+        let full = fs::read(&path).unwrap();
+        for len in [0, 4, SNAPSHOT_MAGIC.len() + 4, full.len() - 1] {
+            fs::write(&path, &full[..len]).unwrap();
+            assert!(Snapshot::load(&path).is_err(), "loaded a snapshot cut to {} bytes", len);
+        }
+        // A length that runs past the end of the file
+        let mut data = full;
+        data[SNAPSHOT_MAGIC.len() + 16..SNAPSHOT_MAGIC.len() + 24].copy_from_slice(&u64::MAX.to_le_bytes());
+        fs::write(&path, &data).unwrap();
+        let huge = Snapshot::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(huge.is_err());
+    }
+}
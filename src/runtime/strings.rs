@@ -0,0 +1,272 @@
+//! Strings in other encodings than NUL-terminated UTF-8, and writing strings
+
+use anyhow::{anyhow, bail, Result};
+use log::error;
+use rhai::{Dynamic, Engine};
+
+use crate::target::Target;
+
+use super::{mem::read_varint, value::ValueType, Context};
+
+/// Longest string read, in bytes, so a garbage length prefix doesn't allocate gigabytes
+const MAX_STRING_LENGTH: usize = 1 << 20;
+
+/// Default limit of `read_wstring`, in UTF-16 code units
+const DEFAULT_WSTRING_LENGTH: usize = 32767;
+
+#[derive(Debug, Clone, Copy)]
+enum Encoding {
+    /// Invalid UTF-8 reads as `()`
+    Utf8,
+    /// Invalid UTF-8 is replaced with U+FFFD
+    Utf8Lossy,
+    Latin1,
+    Utf16,
+}
+
+impl Encoding {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name.to_lowercase().replace('-', "_").as_str() {
+            "utf8" | "utf_8" => Encoding::Utf8,
+            "utf8_lossy" | "utf_8_lossy" => Encoding::Utf8Lossy,
+            "latin1" | "latin_1" | "iso_8859_1" => Encoding::Latin1,
+            "utf16" | "utf_16" | "utf16le" | "utf_16le" => Encoding::Utf16,
+            _ => bail!("Unknown encoding: {}", name),
+        })
+    }
+
+    /// Size of a code unit, which is also the size of the NUL terminator
+    fn unit_size(self) -> usize {
+        match self {
+            Encoding::Utf16 => 2,
+            _ => 1,
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> Option<String> {
+        match self {
+            Encoding::Utf8 => String::from_utf8(bytes.to_vec()).ok(),
+            Encoding::Utf8Lossy => Some(String::from_utf8_lossy(bytes).to_string()),
+            Encoding::Latin1 => Some(bytes.iter().map(|&b| b as char).collect()),
+            Encoding::Utf16 => {
+                let units = bytes
+                    .chunks_exact(2)
+                    .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                    .collect::<Vec<_>>();
+                Some(String::from_utf16_lossy(&units))
+            }
+        }
+    }
+
+    fn encode(self, s: &str) -> Result<Vec<u8>> {
+        Ok(match self {
+            Encoding::Utf8 | Encoding::Utf8Lossy => s.as_bytes().to_vec(),
+            Encoding::Latin1 => s
+                .chars()
+                .map(|c| u8::try_from(c).map_err(|_| anyhow!("{} can't be encoded as latin-1", c)))
+                .collect::<Result<_>>()?,
+            Encoding::Utf16 => s.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+        })
+    }
+}
+
+/// Cut `bytes` at the first NUL code unit
+fn until_nul(bytes: &[u8], encoding: Encoding) -> &[u8] {
+    let unit_size = encoding.unit_size();
+    match bytes.chunks_exact(unit_size).position(|unit| unit.iter().all(|&b| b == 0)) {
+        Some(end) => &bytes[..end * unit_size],
+        None => &bytes[..bytes.len() - bytes.len() % unit_size],
+    }
+}
+
+/// Length of a string stored before it, as a number of code units
+fn read_length_prefix(target: &dyn Target, address: u64, prefix: &str) -> Result<(usize, usize)> {
+    if prefix == "varint" {
+        let (length, size) = read_varint(target, address).ok_or_else(|| anyhow!("Failed to read varint"))?;
+        return Ok((length as usize, size));
+    }
+    let (name, big_endian) = match prefix.strip_suffix("_be") {
+        Some(name) => (name, true),
+        None => (prefix, false),
+    };
+    let kind = match ValueType::parse(name, &Dynamic::UNIT)? {
+        kind @ (ValueType::U8 | ValueType::U16 | ValueType::U32 | ValueType::U64) => kind,
+        _ => bail!("Length prefix must be u8, u16, u32, u64 or varint"),
+    };
+    let mut bytes = target.read_bytes(address, kind.size())?;
+    if big_endian {
+        bytes.reverse();
+    }
+    bytes.resize(8, 0);
+    Ok((u64::from_le_bytes(bytes.try_into().unwrap()) as usize, kind.size()))
+}
+
+struct WriteOptions {
+    encoding: Encoding,
+    nul: bool,
+    /// Size of the buffer being written to, in bytes
+    capacity: Option<usize>,
+}
+
+impl WriteOptions {
+    fn parse(options: &rhai::Map) -> Result<Self> {
+        let encoding = match options.get("encoding") {
+            Some(encoding) => Encoding::parse(&encoding.to_string())?,
+            None => Encoding::Utf8,
+        };
+        let nul = match options.get("nul") {
+            Some(nul) => nul.as_bool().map_err(|_| anyhow!("nul must be a bool"))?,
+            None => true,
+        };
+        let capacity = match options.get("capacity") {
+            Some(capacity) => Some(
+                capacity
+                    .as_int()
+                    .ok()
+                    .filter(|capacity| *capacity >= 0)
+                    .ok_or_else(|| anyhow!("capacity must be a positive number"))? as usize,
+            ),
+            None => None,
+        };
+        Ok(Self { encoding, nul, capacity })
+    }
+}
+
+fn write_string(ctx: &Context, address: u64, s: &str, options: &WriteOptions) -> Result<usize> {
+    let mut bytes = options.encoding.encode(s)?;
+    if options.nul {
+        bytes.extend(std::iter::repeat_n(0, options.encoding.unit_size()));
+    }
+    if let Some(capacity) = options.capacity {
+        if bytes.len() > capacity {
+            bail!("{} bytes don't fit in a buffer of {}", bytes.len(), capacity);
+        }
+    }
+    let end = address + bytes.len() as u64;
+    // Fresh maps, as the mapping may have shrunk or moved since the cached ones were read
+    let map_end = ctx
+        .fresh_maps()
+        .iter()
+        .find(|map| map.contains(address))
+        .map(|map| map.end)
+        .ok_or_else(|| anyhow!("{:#x} isn't mapped", address))?;
+    if end > map_end {
+        bail!("{} bytes at {:#x} would run past the end of its mapping at {:#x}", bytes.len(), address, map_end);
+    }
+    ctx.target.write_memory(address, &bytes)?;
+    Ok(bytes.len())
+}
+
+fn read_wstring(target: &dyn Target, address: Dynamic, max_chars: usize) -> Dynamic {
+    let address = address.as_int().unwrap_or(-1);
+    if address < 0 {
+        return Dynamic::UNIT;
+    }
+    let max_len = (max_chars * 2).min(MAX_STRING_LENGTH);
+    match target.read_terminated(address as _, 2, max_len) {
+        Ok(bytes) => Encoding::Utf16.decode(&bytes).map(Dynamic::from).unwrap_or(Dynamic::UNIT),
+        Err(_) => Dynamic::UNIT,
+    }
+}
+
+pub fn register_functions(engine: &mut Engine, context: Context) {
+    let ctx = context.clone();
+    engine.register_fn("read_wstring", move |address: Dynamic| -> Dynamic {
+        read_wstring(&*ctx.target, address, DEFAULT_WSTRING_LENGTH)
+    });
+    let ctx = context.clone();
+    engine.register_fn("read_wstring", move |address: Dynamic, max_chars: i64| -> Dynamic {
+        read_wstring(&*ctx.target, address, max_chars.max(0) as usize)
+    });
+
+    // Up to `len` bytes, stopping at a NUL
+    let ctx = context.clone();
+    engine.register_fn("read_string_n", move |address: Dynamic, len: i64, encoding: &str| -> Dynamic {
+        let address = address.as_int().unwrap_or(-1);
+        if address < 0 {
+            return Dynamic::UNIT;
+        }
+        let encoding = match Encoding::parse(encoding) {
+            Ok(encoding) => encoding,
+            Err(e) => {
+                error!("{}", e);
+                return Dynamic::UNIT;
+            }
+        };
+        let len = (len.max(0) as usize).min(MAX_STRING_LENGTH);
+        match ctx.target.read_bytes(address as _, len) {
+            Ok(bytes) => encoding.decode(until_nul(&bytes, encoding)).map(Dynamic::from).unwrap_or(Dynamic::UNIT),
+            Err(_) => Dynamic::UNIT,
+        }
+    });
+
+    // Strings stored after their length, counted in code units (bytes, or 16-bit units for UTF-16)
+    let ctx = context.clone();
+    engine.register_fn(
+        "read_string_prefixed",
+        move |address: Dynamic, prefix: &str, encoding: &str| -> Dynamic {
+            let address = address.as_int().unwrap_or(-1);
+            if address < 0 {
+                return Dynamic::UNIT;
+            }
+            let read = || -> Result<Option<String>> {
+                let encoding = Encoding::parse(encoding)?;
+                let (length, prefix_size) = read_length_prefix(&*ctx.target, address as _, prefix)?;
+                let len = length.saturating_mul(encoding.unit_size());
+                if len > MAX_STRING_LENGTH {
+                    bail!("Length {} is implausibly large", length);
+                }
+                let bytes = ctx.target.read_bytes(address as u64 + prefix_size as u64, len)?;
+                Ok(encoding.decode(&bytes))
+            };
+            match read() {
+                Ok(s) => s.map(Dynamic::from).unwrap_or(Dynamic::UNIT),
+                Err(e) => {
+                    error!("Failed to read string at {:#x}: {}", address, e);
+                    Dynamic::UNIT
+                }
+            }
+        },
+    );
+
+    // Returns the number of bytes written, including the terminator
+    let ctx = context.clone();
+    engine.register_fn("write_string", move |address: Dynamic, s: &str| -> Dynamic {
+        let address = address.as_int().unwrap_or(-1);
+        if address < 0 {
+            return Dynamic::UNIT;
+        }
+        let options = WriteOptions {
+            encoding: Encoding::Utf8,
+            nul: true,
+            capacity: None,
+        };
+        match write_string(&ctx, address as u64, s, &options) {
+            Ok(written) => (written as i64).into(),
+            Err(e) => {
+                error!("Failed to write string at {:#x}: {}", address, e);
+                Dynamic::UNIT
+            }
+        }
+    });
+
+    let ctx = context;
+    engine.register_fn(
+        "write_string",
+        move |address: Dynamic, s: &str, options: rhai::Map| -> Dynamic {
+            let address = address.as_int().unwrap_or(-1);
+            if address < 0 {
+                return Dynamic::UNIT;
+            }
+            let written = WriteOptions::parse(&options)
+                .and_then(|options| write_string(&ctx, address as u64, s, &options));
+            match written {
+                Ok(written) => (written as i64).into(),
+                Err(e) => {
+                    error!("Failed to write string at {:#x}: {}", address, e);
+                    Dynamic::UNIT
+                }
+            }
+        },
+    );
+}
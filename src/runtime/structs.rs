@@ -0,0 +1,371 @@
+//! Struct layouts declared by scripts, so that whole structures can be read with a single call
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Result};
+use log::error;
+use rhai::{Dynamic, Engine};
+
+use crate::target::Target;
+
+use super::{value::ValueType, Context};
+
+/// How many pointers `ptr<T>` fields are followed through, so linked lists don't read forever
+const MAX_POINTER_DEPTH: usize = 4;
+
+/// Longest string read through a `ptr<cstr>` field
+const MAX_CSTRING_LENGTH: usize = 4096;
+
+/// Largest type read in one go, so a garbage array count doesn't allocate gigabytes
+const MAX_TYPE_SIZE: usize = 1 << 24;
+
+/// Nesting limit, which also catches structs that (inline) contain themselves
+const MAX_NESTING: usize = 32;
+
+#[derive(Debug, Clone)]
+enum FieldType {
+    Value(ValueType),
+    /// `ptr`, or `ptr<T>` to read what it points to
+    Pointer(Option<Box<FieldType>>),
+    /// A NUL-terminated string, only valid behind a pointer
+    CString,
+    /// `char[N]`, a NUL-terminated string stored inline
+    Text(usize),
+    /// `bytes[N]`
+    Bytes(usize),
+    Struct(String),
+    Array(Box<FieldType>, usize),
+}
+
+impl FieldType {
+    /// Parse a type such as `i32`, `u64`, `f32[3]`, `ptr<cstr>`, `char[16]` or `ptr<Player>[64]`
+    fn parse(name: &str) -> Result<Self> {
+        let name = name.trim();
+        if let Some((element, count)) = name.strip_suffix(']').and_then(|name| name.rsplit_once('[')) {
+            let count = count
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| anyhow!("Invalid array length in {}", name))?;
+            return Ok(match element.trim() {
+                "char" => FieldType::Text(count),
+                "bytes" => FieldType::Bytes(count),
+                element => FieldType::Array(Box::new(FieldType::parse(element)?), count),
+            });
+        }
+        if let Some(target) = name.strip_prefix("ptr<").and_then(|name| name.strip_suffix('>')) {
+            return Ok(FieldType::Pointer(Some(Box::new(FieldType::parse(target)?))));
+        }
+        if let Ok(value) = ValueType::parse(name, &Dynamic::UNIT) {
+            return Ok(FieldType::Value(value));
+        }
+        Ok(match name {
+            "ptr" => FieldType::Pointer(None),
+            "cstr" => FieldType::CString,
+            name if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') => {
+                FieldType::Struct(name.to_string())
+            }
+            _ => bail!("Unknown type: {}", name),
+        })
+    }
+}
+
+struct StructLayout {
+    /// Explicit size, for structs with padding or fields that aren't declared
+    size: Option<usize>,
+    fields: Vec<(String, usize, FieldType)>,
+}
+
+#[derive(Default)]
+struct Structs {
+    layouts: HashMap<String, StructLayout>,
+}
+
+impl Structs {
+    fn layout(&self, name: &str) -> Result<&StructLayout> {
+        self.layouts.get(name).ok_or_else(|| anyhow!("Unknown struct: {}", name))
+    }
+
+    /// Size of a type as stored inline
+    fn size(&self, ty: &FieldType, nesting: usize) -> Result<usize> {
+        if nesting > MAX_NESTING {
+            bail!("Struct contains itself");
+        }
+        let too_large = || anyhow!("Type is larger than {} bytes", MAX_TYPE_SIZE);
+        let size = match ty {
+            FieldType::Value(value) => value.size(),
+            FieldType::Pointer(_) => 8,
+            FieldType::CString => bail!("cstr can only be used behind a pointer, as ptr<cstr>"),
+            FieldType::Text(len) | FieldType::Bytes(len) => *len,
+            FieldType::Array(element, count) => {
+                self.size(element, nesting + 1)?.checked_mul(*count).ok_or_else(too_large)?
+            }
+            FieldType::Struct(name) => {
+                let layout = self.layout(name)?;
+                match layout.size {
+                    Some(size) => size,
+                    None => layout.fields.iter().try_fold(0, |size, (_, offset, ty)| {
+                        let end = offset.checked_add(self.size(ty, nesting + 1)?).ok_or_else(too_large)?;
+                        Ok::<_, anyhow::Error>(size.max(end))
+                    })?,
+                }
+            }
+        };
+        if size > MAX_TYPE_SIZE {
+            return Err(too_large());
+        }
+        Ok(size)
+    }
+
+    /// Decode a value from bytes that were already read, following pointers if the type says so
+    fn decode(&self, target: &dyn Target, ty: &FieldType, bytes: &[u8], depth: usize) -> Result<Dynamic> {
+        Ok(match ty {
+            FieldType::Value(value) => value.decode(bytes),
+            FieldType::Pointer(pointee) => {
+                let pointer = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+                match pointee {
+                    Some(_) if pointer == 0 => Dynamic::UNIT,
+                    Some(pointee) if depth < MAX_POINTER_DEPTH => self.read(target, pointee, pointer, depth + 1)?,
+                    _ => (pointer as i64).into(),
+                }
+            }
+            FieldType::CString => unreachable!("cstr is read through its pointer"),
+            FieldType::Text(_) => {
+                let nul = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+                String::from_utf8_lossy(&bytes[..nul]).to_string().into()
+            }
+            FieldType::Bytes(_) => Dynamic::from_blob(bytes.to_vec()),
+            FieldType::Array(element, count) => {
+                let size = self.size(element, 0)?;
+                (0..*count)
+                    .map(|i| self.decode(target, element, &bytes[i * size..(i + 1) * size], depth))
+                    .collect::<Result<rhai::Array>>()?
+                    .into()
+            }
+            FieldType::Struct(name) => {
+                let mut map = rhai::Map::new();
+                for (field, offset, ty) in &self.layout(name)?.fields {
+                    let size = self.size(ty, 0)?;
+                    if offset + size > bytes.len() {
+                        bail!("{}.{} is past the end of the struct", name, field);
+                    }
+                    map.insert(field.into(), self.decode(target, ty, &bytes[*offset..offset + size], depth)?);
+                }
+                map.into()
+            }
+        })
+    }
+
+    /// Read a value with one read for everything stored inline. Unreadable memory reads as `()`.
+    fn read(&self, target: &dyn Target, ty: &FieldType, address: u64, depth: usize) -> Result<Dynamic> {
+        if let FieldType::CString = ty {
+            return Ok(match target.read_terminated(address, 1, MAX_CSTRING_LENGTH) {
+                Ok(bytes) => String::from_utf8_lossy(&bytes).to_string().into(),
+                Err(_) => Dynamic::UNIT,
+            });
+        }
+        match target.read_bytes(address, self.size(ty, 0)?) {
+            Ok(bytes) => self.decode(target, ty, &bytes, depth),
+            Err(_) => Ok(Dynamic::UNIT),
+        }
+    }
+
+    /// Offset and type of a field, given a path like `pos[1]` or `stats.health`. Paths don't go
+    /// through pointers, since that would need a read.
+    fn locate(&self, name: &str, path: &str) -> Result<(usize, FieldType)> {
+        let mut offset = 0;
+        let mut ty = FieldType::Struct(name.to_string());
+        for segment in path.split('.') {
+            let (field, indices) = match segment.split_once('[') {
+                Some((field, indices)) => (field, Some(indices)),
+                None => (segment, None),
+            };
+            let FieldType::Struct(name) = &ty else {
+                bail!("{} is not a struct", field);
+            };
+            let (_, field_offset, field_ty) = self
+                .layout(name)?
+                .fields
+                .iter()
+                .find(|(candidate, _, _)| candidate == field.trim())
+                .ok_or_else(|| anyhow!("{} has no field {}", name, field))?;
+            offset += field_offset;
+            ty = field_ty.clone();
+            for index in indices.into_iter().flat_map(|indices| indices.split('[')) {
+                let index = index
+                    .trim()
+                    .strip_suffix(']')
+                    .and_then(|index| index.parse::<usize>().ok())
+                    .ok_or_else(|| anyhow!("Invalid index in {}", segment))?;
+                let FieldType::Array(element, count) = ty else {
+                    bail!("{} is not an array", field);
+                };
+                if index >= count {
+                    bail!("Index {} out of bounds for {}", index, field);
+                }
+                offset += index * self.size(&element, 0)?;
+                ty = *element;
+            }
+        }
+        Ok((offset, ty))
+    }
+}
+
+/// Encode a script value for a single field
+fn encode(ty: &FieldType, value: &Dynamic) -> Result<Vec<u8>> {
+    Ok(match ty {
+        FieldType::Value(value_type) => value_type.encode(value)?,
+        FieldType::Pointer(_) => value
+            .as_int()
+            .map_err(|_| anyhow!("Expected an address"))?
+            .to_le_bytes()
+            .to_vec(),
+        FieldType::Text(len) => {
+            let s = value
+                .read_lock::<rhai::ImmutableString>()
+                .ok_or_else(|| anyhow!("Expected a string"))?;
+            // Leave room for the NUL terminator
+            if s.len() >= *len {
+                bail!("String is too long for char[{}]", len);
+            }
+            let mut bytes = s.as_bytes().to_vec();
+            bytes.push(0);
+            bytes
+        }
+        FieldType::Bytes(len) => {
+            let bytes = value.clone().try_cast::<rhai::Blob>().ok_or_else(|| anyhow!("Expected a blob"))?;
+            if bytes.len() > *len {
+                bail!("{} bytes don't fit in bytes[{}]", bytes.len(), len);
+            }
+            bytes
+        }
+        _ => bail!("Only single fields can be written"),
+    })
+}
+
+fn define_struct(structs: &Mutex<Structs>, name: &str, fields: rhai::Map, size: Option<usize>) {
+    let parsed = fields
+        .into_iter()
+        .map(|(field, definition)| {
+            let definition = definition
+                .try_cast::<rhai::Array>()
+                .filter(|definition| definition.len() == 2)
+                .ok_or_else(|| anyhow!("Expected [offset, type] for {}", field))?;
+            let offset = definition[0]
+                .as_int()
+                .ok()
+                .filter(|offset| *offset >= 0)
+                .ok_or_else(|| anyhow!("Invalid offset for {}", field))?;
+            let ty = definition[1]
+                .read_lock::<rhai::ImmutableString>()
+                .ok_or_else(|| anyhow!("Invalid type for {}", field))
+                .and_then(|ty| FieldType::parse(&ty))?;
+            Ok((field.to_string(), offset as usize, ty))
+        })
+        .collect::<Result<Vec<_>>>();
+    match parsed {
+        Ok(fields) => {
+            structs.lock().unwrap().layouts.insert(name.to_string(), StructLayout { size, fields });
+        }
+        Err(e) => error!("Invalid struct {}: {}", name, e),
+    }
+}
+
+pub fn register_functions(engine: &mut Engine, context: Context) {
+    let structs = Arc::new(Mutex::new(Structs::default()));
+
+    let s = structs.clone();
+    engine.register_fn("define_struct", move |name: &str, fields: rhai::Map| {
+        define_struct(&s, name, fields, None);
+    });
+
+    let s = structs.clone();
+    engine.register_fn("define_struct", move |name: &str, fields: rhai::Map, size: i64| {
+        define_struct(&s, name, fields, Some(size.max(0) as usize));
+    });
+
+    let s = structs.clone();
+    engine.register_fn("struct_size", move |name: &str| -> Dynamic {
+        match s.lock().unwrap().size(&FieldType::Struct(name.to_string()), 0) {
+            Ok(size) => (size as i64).into(),
+            Err(e) => {
+                error!("{}", e);
+                Dynamic::UNIT
+            }
+        }
+    });
+
+    let s = structs.clone();
+    let ctx = context.clone();
+    engine.register_fn("read_struct", move |name: &str, address: Dynamic| -> Dynamic {
+        let address = address.as_int().unwrap_or(-1);
+        if address < 0 {
+            return Dynamic::UNIT;
+        }
+        let ty = FieldType::Struct(name.to_string());
+        s.lock().unwrap().read(&*ctx.target, &ty, address as u64, 0).unwrap_or_else(|e| {
+            error!("Failed to read {}: {}", name, e);
+            Dynamic::UNIT
+        })
+    });
+
+    // A contiguous array of structs, read all at once
+    let s = structs.clone();
+    let ctx = context.clone();
+    engine.register_fn("read_struct_array", move |name: &str, address: Dynamic, count: i64| -> Dynamic {
+        let address = address.as_int().unwrap_or(-1);
+        if address < 0 {
+            return Dynamic::UNIT;
+        }
+        let ty = FieldType::Array(Box::new(FieldType::Struct(name.to_string())), count.max(0) as usize);
+        s.lock().unwrap().read(&*ctx.target, &ty, address as u64, 0).unwrap_or_else(|e| {
+            error!("Failed to read {}: {}", name, e);
+            Dynamic::UNIT
+        })
+    });
+
+    let s = structs.clone();
+    let ctx = context.clone();
+    engine.register_fn("read_field", move |name: &str, address: Dynamic, path: &str| -> Dynamic {
+        let address = address.as_int().unwrap_or(-1);
+        if address < 0 {
+            return Dynamic::UNIT;
+        }
+        let structs = s.lock().unwrap();
+        let read = structs
+            .locate(name, path)
+            .and_then(|(offset, ty)| structs.read(&*ctx.target, &ty, address as u64 + offset as u64, 0));
+        read.unwrap_or_else(|e| {
+            error!("Failed to read {}.{}: {}", name, path, e);
+            Dynamic::UNIT
+        })
+    });
+
+    let s = structs;
+    let ctx = context;
+    engine.register_fn(
+        "write_field",
+        move |name: &str, address: Dynamic, path: &str, value: Dynamic| -> bool {
+            let address = address.as_int().unwrap_or(-1);
+            if address < 0 {
+                return false;
+            }
+            let encoded = s
+                .lock()
+                .unwrap()
+                .locate(name, path)
+                .and_then(|(offset, ty)| Ok((offset, encode(&ty, &value)?)));
+            match encoded {
+                Ok((offset, bytes)) => {
+                    ctx.target.write_memory(address as u64 + offset as u64, &bytes).is_ok()
+                }
+                Err(e) => {
+                    error!("Failed to write {}.{}: {}", name, path, e);
+                    false
+                }
+            }
+        },
+    );
+}
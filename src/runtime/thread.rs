@@ -1,5 +1,9 @@
+use std::collections::HashMap;
+
 use crate::thread::Thread;
-use rhai::{CustomType, FuncArgs, Map, TypeBuilder};
+use rhai::{CustomType, Engine, FuncArgs, Map, TypeBuilder};
+
+use super::Context;
 
 // Rhai-friendly wrapper around a thread
 #[derive(Debug, Clone, CustomType)]
@@ -33,4 +37,28 @@ impl FuncArgs for RhaiThread {
         map.insert("name".into(), self.name.into());
         args.extend(Some(map.into()));
     }
-}
\ No newline at end of file
+}
+
+pub fn register_functions(engine: &mut Engine, context: Context) {
+    let (thread_leader, names) = {
+        let debugger = context.debugger();
+        let names = debugger.threads.values().map(|thread| (thread.pid, thread.name.clone())).collect::<HashMap<_, _>>();
+        (debugger.pid, names)
+    };
+    let target = context.target;
+
+    // The debugger is busy while callbacks run, so threads are listed from the target itself
+    engine.register_fn("threads", move || -> rhai::Array {
+        let tasks = target.threads().unwrap_or_default();
+        tasks
+            .into_iter()
+            .map(|tid| {
+                let name = names.get(&tid).cloned().unwrap_or_else(|| {
+                    let comm = std::fs::read_to_string(format!("/proc/{}/task/{}/comm", thread_leader, tid));
+                    comm.unwrap_or_default().trim().to_string()
+                });
+                rhai::Dynamic::from(RhaiThread::new(tid as i64, name))
+            })
+            .collect()
+    });
+}
@@ -0,0 +1,72 @@
+//! Stack walking functions
+
+use log::error;
+use rhai::{Dynamic, Engine};
+
+use crate::{
+    module::Module,
+    registers::Registers,
+    unwind::{self, StackFrame, UnwindMethod},
+};
+
+use super::{Context, RhaiThread};
+
+fn frame_to_dynamic(ctx: &Context, modules: &[Module], frame: &StackFrame) -> Dynamic {
+    // Callers are looked up by the call instruction rather than the return address, which may be past the end of the function
+    let lookup = match frame.method {
+        UnwindMethod::Registers => frame.pc,
+        _ => frame.pc - 1,
+    };
+    let mut map = rhai::Map::new();
+    map.insert("pc".into(), (frame.pc as i64).into());
+    map.insert("sp".into(), (frame.sp as i64).into());
+    let method = match frame.method {
+        UnwindMethod::Registers => "registers",
+        UnwindMethod::CallFrameInfo => "cfi",
+        UnwindMethod::FramePointer => "frame pointer",
+    };
+    map.insert("method".into(), method.into());
+    let symbolized = ctx.symbols().symbolize(lookup);
+    map.insert(
+        "module_name".into(),
+        symbolized.as_ref().map(|symbolized| Dynamic::from(symbolized.module.clone())).unwrap_or(Dynamic::UNIT),
+    );
+    map.insert(
+        "symbol".into(),
+        symbolized
+            .and_then(|symbolized| symbolized.symbol)
+            .map(|(name, offset)| Dynamic::from(format!("{}+{:#x}", name, offset + frame.pc - lookup)))
+            .unwrap_or(Dynamic::UNIT),
+    );
+    let Some(module) = modules.iter().find(|module| module.contains(lookup)) else {
+        return Dynamic::from(map);
+    };
+    let Ok(elf) = ctx.elves().get(&*ctx.target, module) else {
+        return Dynamic::from(map);
+    };
+    let vaddr = lookup.wrapping_sub(elf.bias);
+    if let Some(line) = elf.debug_info().and_then(|debug| debug.line(vaddr)) {
+        map.insert("line".into(), format!("{}:{}", line.file, line.line).into());
+    }
+    Dynamic::from(map)
+}
+
+pub fn register_functions(engine: &mut Engine, context: Context) {
+    let ctx = context;
+    engine.register_fn("backtrace", move |task: RhaiThread| -> Dynamic {
+        let regs = match ctx.target.regs(task.pid as _) {
+            Ok(regs) => Registers::from(regs),
+            Err(e) => {
+                error!("Failed to get registers of {}: {}", task.pid, e);
+                return Dynamic::UNIT;
+            }
+        };
+        let modules = Module::from_maps(&ctx.maps());
+        let frames = unwind::backtrace(&*ctx.target, regs, &modules, &mut ctx.elves());
+        frames
+            .iter()
+            .map(|frame| frame_to_dynamic(&ctx, &modules, frame))
+            .collect::<Vec<_>>()
+            .into()
+    });
+}
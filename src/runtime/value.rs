@@ -0,0 +1,181 @@
+//! Typed values that scripts refer to by name, such as "i32" or "f64"
+
+use std::cmp::Ordering;
+
+use anyhow::Result;
+use rhai::Dynamic;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ValueType {
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    /// Values above `i64::MAX` are exchanged with scripts as decimal strings
+    U64,
+    F32,
+    F64,
+    /// A fixed-length UTF-8 string, without a NUL terminator
+    String(usize),
+}
+
+impl ValueType {
+    /// Parse a type name. Strings take their length from the value they will be compared against.
+    pub fn parse(name: &str, value: &Dynamic) -> Result<Self> {
+        Ok(match name {
+            "i8" => ValueType::I8,
+            "i16" => ValueType::I16,
+            "i32" => ValueType::I32,
+            "i64" => ValueType::I64,
+            "u8" => ValueType::U8,
+            "u16" => ValueType::U16,
+            "u32" => ValueType::U32,
+            "u64" => ValueType::U64,
+            "f32" => ValueType::F32,
+            "f64" => ValueType::F64,
+            "string" | "str" => {
+                let len = value
+                    .read_lock::<rhai::ImmutableString>()
+                    .map(|s| s.len())
+                    .ok_or(anyhow::anyhow!("Expected a string value"))?;
+                if len == 0 {
+                    return Err(anyhow::anyhow!("String value is empty"));
+                }
+                ValueType::String(len)
+            }
+            _ => return Err(anyhow::anyhow!("Unknown type: {}", name)),
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            ValueType::I8 | ValueType::U8 => 1,
+            ValueType::I16 | ValueType::U16 => 2,
+            ValueType::I32 | ValueType::U32 | ValueType::F32 => 4,
+            ValueType::I64 | ValueType::U64 | ValueType::F64 => 8,
+            ValueType::String(len) => *len,
+        }
+    }
+
+    /// Natural alignment of the type, used to skip unaligned candidates while scanning.
+    pub fn alignment(&self) -> usize {
+        match self {
+            ValueType::String(_) => 1,
+            _ => self.size(),
+        }
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        !matches!(self, ValueType::String(_))
+    }
+
+    /// Encode a script value into the target's (little-endian) representation.
+    pub fn encode(&self, value: &Dynamic) -> Result<Vec<u8>> {
+        let int = || {
+            value
+                .as_int()
+                .or_else(|_| value.as_float().map(|f| f as i64))
+                .map_err(|_| anyhow::anyhow!("Expected a number"))
+        };
+        let float = || {
+            value
+                .as_float()
+                .or_else(|_| value.as_int().map(|i| i as f64))
+                .map_err(|_| anyhow::anyhow!("Expected a number"))
+        };
+        Ok(match self {
+            ValueType::I8 => (int()? as i8).to_le_bytes().to_vec(),
+            ValueType::I16 => (int()? as i16).to_le_bytes().to_vec(),
+            ValueType::I32 => (int()? as i32).to_le_bytes().to_vec(),
+            ValueType::I64 => int()?.to_le_bytes().to_vec(),
+            ValueType::U8 => (int()? as u8).to_le_bytes().to_vec(),
+            ValueType::U16 => (int()? as u16).to_le_bytes().to_vec(),
+            ValueType::U32 => (int()? as u32).to_le_bytes().to_vec(),
+            ValueType::U64 => match value.read_lock::<rhai::ImmutableString>() {
+                Some(s) => parse_u64(&s)?.to_le_bytes().to_vec(),
+                None => int()?.to_le_bytes().to_vec(),
+            },
+            ValueType::F32 => (float()? as f32).to_le_bytes().to_vec(),
+            ValueType::F64 => float()?.to_le_bytes().to_vec(),
+            ValueType::String(len) => {
+                let s = value
+                    .read_lock::<rhai::ImmutableString>()
+                    .ok_or(anyhow::anyhow!("Expected a string"))?;
+                if s.len() != *len {
+                    return Err(anyhow::anyhow!("Expected a string of length {}", len));
+                }
+                s.as_bytes().to_vec()
+            }
+        })
+    }
+
+    /// Decode big-endian `bytes` into a script value.
+    pub fn decode_be(&self, bytes: &[u8]) -> Dynamic {
+        self.decode(&bytes.iter().rev().copied().collect::<Vec<_>>())
+    }
+
+    /// Encode a script value into big-endian (network order) bytes.
+    pub fn encode_be(&self, value: &Dynamic) -> Result<Vec<u8>> {
+        let mut bytes = self.encode(value)?;
+        bytes.reverse();
+        Ok(bytes)
+    }
+
+    /// Decode `bytes` (which must be `self.size()` long) into a script value.
+    pub fn decode(&self, bytes: &[u8]) -> Dynamic {
+        match self {
+            ValueType::I8 => (bytes[0] as i8 as i64).into(),
+            ValueType::I16 => (i16::from_le_bytes(bytes[..2].try_into().unwrap()) as i64).into(),
+            ValueType::I32 => (i32::from_le_bytes(bytes[..4].try_into().unwrap()) as i64).into(),
+            ValueType::I64 => i64::from_le_bytes(bytes[..8].try_into().unwrap()).into(),
+            ValueType::U8 => (bytes[0] as i64).into(),
+            ValueType::U16 => (u16::from_le_bytes(bytes[..2].try_into().unwrap()) as i64).into(),
+            ValueType::U32 => (u32::from_le_bytes(bytes[..4].try_into().unwrap()) as i64).into(),
+            ValueType::U64 => u64_to_dynamic(u64::from_le_bytes(bytes[..8].try_into().unwrap())),
+            ValueType::F32 => (f32::from_le_bytes(bytes[..4].try_into().unwrap()) as f64).into(),
+            ValueType::F64 => f64::from_le_bytes(bytes[..8].try_into().unwrap()).into(),
+            ValueType::String(_) => String::from_utf8_lossy(bytes).to_string().into(),
+        }
+    }
+
+    /// Numerically compare two encoded values. Returns `None` for strings and NaNs.
+    pub fn compare(&self, a: &[u8], b: &[u8]) -> Option<Ordering> {
+        match self {
+            ValueType::F32 | ValueType::F64 => {
+                let a = self.decode(a).as_float().ok()?;
+                let b = self.decode(b).as_float().ok()?;
+                a.partial_cmp(&b)
+            }
+            ValueType::String(_) => None,
+            ValueType::U64 => {
+                let a = u64::from_le_bytes(a[..8].try_into().ok()?);
+                let b = u64::from_le_bytes(b[..8].try_into().ok()?);
+                Some(a.cmp(&b))
+            }
+            _ => {
+                let a = self.decode(a).as_int().ok()?;
+                let b = self.decode(b).as_int().ok()?;
+                Some(a.cmp(&b))
+            }
+        }
+    }
+}
+
+/// Scripts only have signed integers, so u64 values are always decimal strings. Returning small
+/// ones as integers would make their type, and how `+` or `<` treat them, depend on the value.
+pub fn u64_to_dynamic(value: u64) -> Dynamic {
+    value.to_string().into()
+}
+
+/// Parse a u64 given as a decimal or 0x-prefixed hex string.
+pub fn parse_u64(s: &str) -> Result<u64> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| anyhow::anyhow!("Invalid u64: {}", s))
+}
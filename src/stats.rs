@@ -0,0 +1,113 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use crate::{hwbp::HardwareBreakpointType, symbolize::Symbolizer};
+
+/// What a breakpoint or watchpoint has cost the target so far.
+pub struct BreakpointStats {
+    pub address: u64,
+    pub kind: HardwareBreakpointType,
+    pub hits: u64,
+    /// Time spent in script callbacks
+    pub callback_time: Duration,
+    /// Time the thread was stopped for it, from the debugger noticing the hit to resuming the thread
+    pub stopped_time: Duration,
+    pub max_stopped_time: Duration,
+}
+
+impl BreakpointStats {
+    fn new(address: u64, kind: HardwareBreakpointType) -> Self {
+        Self {
+            address,
+            kind,
+            hits: 0,
+            callback_time: Duration::ZERO,
+            stopped_time: Duration::ZERO,
+            max_stopped_time: Duration::ZERO,
+        }
+    }
+
+    pub fn average_stopped_time(&self) -> Duration {
+        Duration::from_nanos((self.stopped_time.as_nanos() / self.hits.max(1) as u128) as u64)
+    }
+}
+
+/// Hit counts and overhead of every breakpoint and watchpoint, by debug register.
+pub struct Stats {
+    pub breakpoints: BTreeMap<usize, BreakpointStats>,
+    /// Start of the measurements, reset along with them when the script is reloaded
+    pub since: Instant,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeMap::new(),
+            since: Instant::now(),
+        }
+    }
+
+    /// Count a hit of the breakpoint in debug register `dr`, which kept the thread stopped for
+    /// `stopped` and spent `callback` of that in script callbacks.
+    pub fn record_hit(
+        &mut self,
+        dr: usize,
+        address: u64,
+        kind: HardwareBreakpointType,
+        callback: Duration,
+        stopped: Duration,
+    ) {
+        let stats = self.breakpoints.entry(dr).or_insert_with(|| BreakpointStats::new(address, kind));
+        // Debug registers are reused past the fourth breakpoint
+        if stats.address != address || stats.kind != kind {
+            *stats = BreakpointStats::new(address, kind);
+        }
+        stats.hits += 1;
+        stats.callback_time += callback;
+        stats.stopped_time += stopped;
+        stats.max_stopped_time = stats.max_stopped_time.max(stopped);
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.since = Instant::now();
+    }
+
+    /// Seconds since the measurements started
+    pub fn elapsed(&self) -> f64 {
+        self.since.elapsed().as_secs_f64()
+    }
+
+    /// Every breakpoint along with its debug register, most expensive (longest stopped) first
+    pub fn sorted(&self) -> Vec<(usize, &BreakpointStats)> {
+        let mut breakpoints = self.breakpoints.iter().map(|(dr, stats)| (*dr, stats)).collect::<Vec<_>>();
+        breakpoints.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.stopped_time));
+        breakpoints
+    }
+
+    /// One line per breakpoint, most expensive first
+    pub fn summary(&self, symbols: &mut Symbolizer) -> String {
+        let elapsed = self.elapsed();
+        let mut summary = format!("Breakpoint statistics over {:.1}s:", elapsed);
+        for (_, stats) in self.sorted() {
+            write!(
+                summary,
+                "\n  {} ({:?}): {} hits ({:.1}/s), callbacks {:.2?}, stopped {:.2?} (average {:.2?}, max {:.2?}, {:.2}% of the time)",
+                symbols.describe(stats.address),
+                stats.kind,
+                stats.hits,
+                stats.hits as f64 / elapsed,
+                stats.callback_time,
+                stats.stopped_time,
+                stats.average_stopped_time(),
+                stats.max_stopped_time,
+                stats.stopped_time.as_secs_f64() / elapsed * 100.0
+            )
+            .unwrap();
+        }
+        summary
+    }
+}
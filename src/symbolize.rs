@@ -0,0 +1,147 @@
+//! Turning addresses into `module!symbol+offset`.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    elf::{self, ElfCache, LoadedElf, SymbolSource, SHN_ABS},
+    maps::MemoryMaps,
+    module::Module,
+    target::Target,
+};
+
+/// Function and object symbols of a module, sorted by (unrelocated) address.
+struct SymbolTable {
+    symbols: Vec<(u64, u64, String)>,
+}
+
+impl SymbolTable {
+    fn new(elf: &LoadedElf) -> Self {
+        let mut symbols = elf
+            .elf
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.is_defined() && symbol.shndx != SHN_ABS && matches!(symbol.kind, 1 | 2 | 10))
+            .collect::<Vec<_>>();
+        // Of several aliases for the same address, keep the most descriptive one:
+        // from .symtab, with a size, and with the fewest leading underscores (malloc over __libc_malloc)
+        symbols.sort_by_key(|symbol| {
+            (
+                symbol.value,
+                symbol.source == SymbolSource::Dynsym,
+                symbol.size == 0,
+                symbol.name.len() - symbol.name.trim_start_matches('_').len(),
+            )
+        });
+        symbols.dedup_by_key(|symbol| symbol.value);
+        Self {
+            symbols: symbols
+                .into_iter()
+                .map(|symbol| (symbol.value, symbol.size, symbol.name.clone()))
+                .collect(),
+        }
+    }
+
+    /// The symbol containing `vaddr` and the offset into it. Symbols without a size (as hand-written
+    /// assembly often has) extend up to the next symbol.
+    fn lookup(&self, vaddr: u64) -> Option<(&str, u64)> {
+        let index = self.symbols.partition_point(|(value, _, _)| *value <= vaddr).checked_sub(1)?;
+        let (value, size, name) = &self.symbols[index];
+        (*size == 0 || vaddr < value + size).then_some((name.as_str(), vaddr - value))
+    }
+}
+
+/// Where an address is, in terms of modules and symbols.
+#[derive(Debug, Clone)]
+pub struct Symbolized {
+    pub module: String,
+    /// Offset from the module base
+    pub module_offset: u64,
+    /// Symbol name, and offset into it
+    pub symbol: Option<(String, u64)>,
+}
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.symbol {
+            Some((name, 0)) => write!(f, "{}!{}", self.module, name),
+            Some((name, offset)) => write!(f, "{}!{}+{:#x}", self.module, name, offset),
+            None => write!(f, "{}+{:#x}", self.module, self.module_offset),
+        }
+    }
+}
+
+/// Symbol tables of loaded modules, cached by build ID (or path, for modules without one) so that
+/// they survive unloading and reloading the same library.
+pub struct Symbolizer {
+    pub target: Arc<dyn Target>,
+    maps: Arc<Mutex<MemoryMaps>>,
+    elves: Arc<Mutex<ElfCache>>,
+    tables: HashMap<Vec<u8>, Arc<SymbolTable>>,
+    /// Cache keys and load biases of loaded modules, by path and base address
+    keys: HashMap<(String, u64), (Vec<u8>, u64)>,
+}
+
+impl Symbolizer {
+    pub fn new(target: Arc<dyn Target>, maps: Arc<Mutex<MemoryMaps>>, elves: Arc<Mutex<ElfCache>>) -> Self {
+        Self {
+            target,
+            maps,
+            elves,
+            tables: HashMap::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Symbol table of a module, along with its load bias
+    fn table(&mut self, module: &Module) -> Option<(Arc<SymbolTable>, u64)> {
+        let loaded = (module.path.clone(), module.base);
+        let (key, bias) = match self.keys.get(&loaded) {
+            Some(entry) => entry.clone(),
+            None => {
+                let (_, _, bias) = elf::loaded::loaded_segments(&*self.target, module).ok()?;
+                let key = module.build_id(&*self.target).unwrap_or_else(|| module.path.clone().into_bytes());
+                self.keys.insert(loaded, (key.clone(), bias));
+                (key, bias)
+            }
+        };
+        // Only parse the module when no copy of it was seen before
+        if let Some(table) = self.tables.get(&key) {
+            return Some((table.clone(), bias));
+        }
+        let elf = self.elves.lock().unwrap().get(&*self.target, module).ok()?;
+        let table = Arc::new(SymbolTable::new(&elf));
+        self.tables.insert(key, table.clone());
+        Some((table, bias))
+    }
+
+    /// Find the module (and if possible, the symbol) containing an address.
+    pub fn symbolize(&mut self, address: u64) -> Option<Symbolized> {
+        let modules = {
+            let mut maps = self.maps.lock().unwrap();
+            maps.refresh_if_stale();
+            Module::from_maps(&maps)
+        };
+        let module = modules.into_iter().find(|module| module.contains(address))?;
+        let symbol = self.table(&module).and_then(|(table, bias)| {
+            let (name, offset) = table.lookup(address.wrapping_sub(bias))?;
+            Some((name.to_string(), offset))
+        });
+        Some(Symbolized {
+            module: module.name,
+            module_offset: address - module.base,
+            symbol,
+        })
+    }
+
+    /// An address as `0x7f0012345678 (libc.so.6!malloc+0x12)`, for logging
+    pub fn describe(&mut self, address: u64) -> String {
+        match self.symbolize(address) {
+            Some(symbolized) => format!("{:#x} ({})", address, symbolized),
+            None => format!("{:#x}", address),
+        }
+    }
+}
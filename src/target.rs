@@ -0,0 +1,154 @@
+//! The process being debugged, behind a trait so that memory, registers and maps can also come
+//! from a core file. The debugger and the script runtime share one [`Target`] and read through it;
+//! only what needs a live process (ptrace, breakpoints, injected syscalls) goes to `util` directly.
+
+use std::mem::{self, MaybeUninit};
+
+use anyhow::{bail, Result};
+use libc::{user_fpregs_struct, user_regs_struct};
+
+use crate::{
+    elf::coredump::CoreFile,
+    util::{self, procfs::MemoryMap},
+};
+
+pub trait Target: Send + Sync {
+    fn read_memory(&self, address: u64, buf: &mut [u8]) -> Result<()>;
+    fn write_memory(&self, address: u64, bytes: &[u8]) -> Result<()>;
+    /// Read many (address, length) ranges. Ranges that can't be read come back as `None`.
+    fn read_batch(&self, ranges: &[(u64, usize)]) -> Vec<Option<Vec<u8>>> {
+        ranges
+            .iter()
+            .map(|&(address, len)| {
+                let mut buf = vec![0; len];
+                self.read_memory(address, &mut buf).ok().map(|_| buf)
+            })
+            .collect()
+    }
+    fn maps(&self) -> Result<Vec<MemoryMap>>;
+    fn threads(&self) -> Result<Vec<u32>>;
+    fn regs(&self, tid: u32) -> Result<user_regs_struct>;
+    fn fp_regs(&self, tid: u32) -> Result<user_fpregs_struct>;
+    /// Whether the target runs, so that breakpoints and anything else that resumes it can work
+    fn is_live(&self) -> bool;
+}
+
+/// A running process, traced with ptrace.
+pub struct LiveProcess {
+    pub pid: u32,
+}
+
+impl Target for LiveProcess {
+    fn read_memory(&self, address: u64, buf: &mut [u8]) -> Result<()> {
+        util::mem::read_into(self.pid, address as _, buf)
+    }
+
+    fn write_memory(&self, address: u64, bytes: &[u8]) -> Result<()> {
+        util::mem::write_bytes(self.pid, address as _, bytes)
+    }
+
+    fn read_batch(&self, ranges: &[(u64, usize)]) -> Vec<Option<Vec<u8>>> {
+        let ranges = ranges.iter().map(|&(address, len)| (address as usize, len)).collect::<Vec<_>>();
+        util::mem::read_batch(self.pid, &ranges)
+    }
+
+    fn maps(&self) -> Result<Vec<MemoryMap>> {
+        MemoryMap::parse_maps(self.pid)
+    }
+
+    fn threads(&self) -> Result<Vec<u32>> {
+        util::procfs::get_tasks(self.pid)
+    }
+
+    fn regs(&self, tid: u32) -> Result<user_regs_struct> {
+        util::ptrace::get_regs(tid)
+    }
+
+    fn fp_regs(&self, tid: u32) -> Result<user_fpregs_struct> {
+        util::ptrace::get_fp_regs(tid)
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+}
+
+impl Target for CoreFile {
+    fn read_memory(&self, address: u64, buf: &mut [u8]) -> Result<()> {
+        CoreFile::read_memory(self, address, buf)
+    }
+
+    fn write_memory(&self, address: u64, _bytes: &[u8]) -> Result<()> {
+        bail!("Can't write to {:#x}: core files are read-only", address)
+    }
+
+    fn maps(&self) -> Result<Vec<MemoryMap>> {
+        Ok(self.maps.clone())
+    }
+
+    fn threads(&self) -> Result<Vec<u32>> {
+        Ok(self.threads.iter().map(|thread| thread.pid).collect())
+    }
+
+    fn regs(&self, tid: u32) -> Result<user_regs_struct> {
+        match self.threads.iter().find(|thread| thread.pid == tid) {
+            Some(thread) => Ok(thread.regs),
+            None => bail!("Thread {} isn't in the core file", tid),
+        }
+    }
+
+    fn fp_regs(&self, tid: u32) -> Result<user_fpregs_struct> {
+        match self.threads.iter().find(|thread| thread.pid == tid).and_then(|thread| thread.fp_regs) {
+            Some(fp_regs) => Ok(fp_regs),
+            None => bail!("The core file has no floating point registers for thread {}", tid),
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        false
+    }
+}
+
+impl dyn Target + '_ {
+    pub fn read<T: Sized>(&self, address: u64) -> Result<T> {
+        let mut buf = MaybeUninit::<T>::zeroed();
+        let bytes = unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, mem::size_of::<T>()) };
+        self.read_memory(address, bytes)?;
+        Ok(unsafe { buf.assume_init() })
+    }
+
+    pub fn write<T: Sized>(&self, address: u64, value: &T) -> Result<()> {
+        let bytes = unsafe { std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) };
+        self.write_memory(address, bytes)
+    }
+
+    pub fn read_bytes(&self, address: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        self.read_memory(address, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Read a string terminated by `unit_size` zero bytes (1 for C strings, 2 for UTF-16), returning
+    /// it without the terminator. Reads are split on `chunk` boundaries so that a string ending right
+    /// before an unmapped page can still be read. Strings longer than `max_len` are cut off there.
+    pub fn read_terminated(&self, address: u64, unit_size: usize, max_len: usize) -> Result<Vec<u8>> {
+        const CHUNK: u64 = 256;
+        let mut bytes = Vec::new();
+        let mut next = address;
+        while bytes.len() < max_len {
+            let len = CHUNK - next % CHUNK;
+            match self.read_bytes(next, len as usize) {
+                Ok(chunk) => bytes.extend_from_slice(&chunk),
+                Err(e) if bytes.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+            next += len;
+            if let Some(end) = bytes.chunks_exact(unit_size).position(|unit| unit.iter().all(|&b| b == 0)) {
+                bytes.truncate(end * unit_size);
+                break;
+            }
+        }
+        bytes.truncate(max_len - max_len % unit_size);
+        Ok(bytes)
+    }
+}
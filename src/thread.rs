@@ -48,24 +48,17 @@ impl Thread {
 
     pub fn wait(&mut self) -> Result<WaitStatus> {
         let status = util::signal::wait(self.pid)?;
-        self.state = match status {
-            WaitStatus::Stopped(_) => ThreadState::Tracing,
-            WaitStatus::Exited(_) => ThreadState::Exited,
-            WaitStatus::Signaled(_) => ThreadState::Exited,
-            WaitStatus::Running => ThreadState::Running,
-        };
+        self.update_state(&status);
         Ok(status)
     }
 
-    pub fn wait_nonblocking(&mut self) -> Result<WaitStatus> {
-        let status = util::signal::wait_nonblock(self.pid)?;
+    /// Track a status change reported by waitpid
+    pub fn update_state(&mut self, status: &WaitStatus) {
         self.state = match status {
             WaitStatus::Stopped(_) => ThreadState::Tracing,
             WaitStatus::Exited(_) => ThreadState::Exited,
             WaitStatus::Signaled(_) => ThreadState::Exited,
-            WaitStatus::Running => ThreadState::Running,
         };
-        Ok(status)
     }
 
     pub fn interrupt(&mut self) -> Result<()> {
@@ -0,0 +1,83 @@
+//! Stack unwinding, from call frame information where there is some and frame pointers otherwise.
+
+use log::debug;
+
+use crate::{elf::ElfCache, module::Module, registers::Registers, target::Target};
+
+/// Deep enough for any sane stack, shallow enough to stop quickly on a corrupted one
+const MAX_FRAMES: usize = 256;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UnwindMethod {
+    /// The innermost frame, straight from the thread's registers
+    Registers,
+    CallFrameInfo,
+    FramePointer,
+}
+
+pub struct StackFrame {
+    pub pc: u64,
+    pub sp: u64,
+    /// How this frame was recovered from the one below it
+    pub method: UnwindMethod,
+}
+
+/// The caller of a frame according to the frame pointer chain, assuming `rbp` points at the saved `rbp`,
+/// right below the return address.
+fn unwind_frame_pointer(target: &dyn Target, regs: &Registers) -> Option<Registers> {
+    if regs.rbp == 0 || regs.rbp < regs.rsp {
+        return None;
+    }
+    // A garbage rbp near the top of the address space ends the chain rather than wrapping around
+    let rsp = regs.rbp.checked_add(16)?;
+    let mut caller = regs.clone();
+    caller.rbp = target.read::<u64>(regs.rbp).ok()?;
+    caller.rip = target.read::<u64>(regs.rbp + 8).ok()?;
+    caller.rsp = rsp;
+    Some(caller)
+}
+
+/// Walk the stack of a stopped thread, innermost frame first.
+pub fn backtrace(target: &dyn Target, regs: Registers, modules: &[Module], elves: &mut ElfCache) -> Vec<StackFrame> {
+    let mut frames = vec![StackFrame {
+        pc: regs.rip,
+        sp: regs.rsp,
+        method: UnwindMethod::Registers,
+    }];
+    let mut regs = regs;
+    while frames.len() < MAX_FRAMES {
+        // Return addresses point past the call, which may already be the next function
+        let lookup = if frames.len() == 1 { regs.rip } else { regs.rip - 1 };
+        let unwound = modules
+            .iter()
+            .find(|module| module.contains(lookup))
+            .and_then(|module| elves.get(target, module).ok())
+            .map(|elf| elf.call_frames().unwind(lookup.wrapping_sub(elf.bias), &regs, target));
+        let (caller, method) = match unwound {
+            Some(Ok(unwound)) => match unwound.caller {
+                Some(caller) => (caller, UnwindMethod::CallFrameInfo),
+                None => break,
+            },
+            other => {
+                if let Some(Err(e)) = other {
+                    debug!("No CFI for {:#x}, following frame pointers: {}", regs.rip, e);
+                }
+                match unwind_frame_pointer(target, &regs) {
+                    Some(caller) => (caller, UnwindMethod::FramePointer),
+                    None => break,
+                }
+            }
+        };
+        // The stack only grows down, anything else means we went off the rails
+        if caller.rip == 0 || caller.rsp <= regs.rsp {
+            break;
+        }
+        frames.push(StackFrame {
+            pc: caller.rip,
+            sp: caller.rsp,
+            method,
+        });
+        regs = caller;
+    }
+    frames
+}
@@ -1,22 +1,39 @@
-use std::path::PathBuf;
-use std::sync::mpsc::Sender;
+use std::io::ErrorKind;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::Path;
 use inotify::{Inotify, WatchMask, EventMask};
 use anyhow::Result;
 
-use crate::Event;
+/// Notices modifications of a file. Never blocks: poll its file descriptor to wait for changes.
+pub struct FileWatcher {
+    inotify: Inotify,
+}
 
-pub fn watch_file_for_changes(tx: Sender<Event>, file_path: PathBuf) -> Result<()> {
-    let mut inotify = Inotify::init()?;
-    inotify.watches().add(file_path, WatchMask::MODIFY)?;
+impl FileWatcher {
+    pub fn new(file_path: &Path) -> Result<Self> {
+        let inotify = Inotify::init()?;
+        inotify.watches().add(file_path, WatchMask::MODIFY)?;
+        Ok(Self { inotify })
+    }
 
-    let mut buffer = [0; 1024];
-    loop {
-        let events = inotify.read_events_blocking(&mut buffer)?;
-        for event in events {
-            if event.mask.contains(EventMask::MODIFY) {
-                tx.send(Event::FileModified).unwrap();
+    /// Whether the file was modified since the last call
+    pub fn modified(&mut self) -> Result<bool> {
+        let mut buffer = [0; 1024];
+        let mut modified = false;
+        loop {
+            match self.inotify.read_events(&mut buffer) {
+                Ok(events) => {
+                    modified |= events.into_iter().any(|event| event.mask.contains(EventMask::MODIFY));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(modified),
+                Err(e) => return Err(e.into()),
             }
         }
     }
 }
 
+impl AsRawFd for FileWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
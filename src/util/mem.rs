@@ -1,74 +1,210 @@
-use std::{ffi::c_void, mem::{self, MaybeUninit}};
+//! Reading and writing the target's memory. Each access tries `process_vm_readv/writev` first,
+//! then `/proc/pid/mem`, which can also write through read-only mappings such as `.text`, and
+//! finally `PTRACE_PEEKDATA/POKEDATA`, which only works while the thread is stopped. Only for live
+//! processes: anything that can also run against a core file reads through its
+//! [`Target`](crate::target::Target) instead.
 
-use anyhow::Result;
-use libc::{process_vm_readv, process_vm_writev, iovec};
+use std::{
+    fs::File,
+    io,
+    mem::{self, MaybeUninit},
+    os::unix::fs::FileExt,
+};
+
+use anyhow::{bail, Result};
+use libc::{iovec, process_vm_readv, process_vm_writev, ptrace, PTRACE_PEEKDATA, PTRACE_POKEDATA};
+
+const WORD_SIZE: usize = mem::size_of::<usize>();
+
+/// Most iovecs a single `process_vm_readv` accepts
+const IOV_MAX: usize = 1024;
 
 pub fn read<T: Sized>(pid: u32, addr: usize) -> Result<T> {
-    let mut buf = MaybeUninit::<T>::uninit();
-    let local = vec![iovec {
-        iov_base: buf.as_mut_ptr() as _,
-        iov_len: mem::size_of::<T>(),
-    }];
-    let remote = vec![iovec {
-        iov_base: addr as _,
-        iov_len: mem::size_of::<T>(),
-    }];
-    unsafe {
-        if process_vm_readv(pid as _, local.as_ptr(), 1, remote.as_ptr(), 1, 0) == -1 {
-            return Err(anyhow::anyhow!("process_vm_readv failed"));
-        }
-    }
+    let mut buf = MaybeUninit::<T>::zeroed();
+    let bytes = unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, mem::size_of::<T>()) };
+    read_into(pid, addr, bytes)?;
     Ok(unsafe { buf.assume_init() })
 }
 
-pub fn write<T: Sized>(pid: u32, addr: usize, value: &T) -> Result<()> {
-    let local = vec![iovec {
-        iov_base: unsafe { value as *const T as *const c_void as *mut c_void },
-        iov_len: mem::size_of::<T>(),
-    }];
-    let remote = vec![iovec {
-        iov_base: addr as _,
-        iov_len: mem::size_of::<T>(),
-    }];
-    unsafe {
-        if process_vm_writev(pid as _, local.as_ptr(), 1, remote.as_ptr(), 1, 0) == -1 {
-            return Err(anyhow::anyhow!("process_vm_writev failed"));
+pub fn read_bytes(pid: u32, addr: usize, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    read_into(pid, addr, &mut buf)?;
+    Ok(buf)
+}
+
+/// Fill `buf` from the target's memory at `addr`, trying each access method in turn.
+pub fn read_into(pid: u32, addr: usize, buf: &mut [u8]) -> Result<()> {
+    let errors = match vm_read(pid, addr, buf) {
+        Ok(()) => return Ok(()),
+        Err(vm) => match proc_mem_read(pid, addr, buf) {
+            Ok(()) => return Ok(()),
+            Err(proc_mem) => match peek_read(pid, addr, buf) {
+                Ok(()) => return Ok(()),
+                Err(peek) => [("process_vm_readv", vm), ("/proc/pid/mem", proc_mem), ("PTRACE_PEEKDATA", peek)],
+            },
+        },
+    };
+    bail!("Failed to read {} bytes at {:#x}: {}", buf.len(), addr, describe(&errors))
+}
+
+pub fn write_bytes(pid: u32, addr: usize, bytes: &[u8]) -> Result<()> {
+    let errors = match vm_write(pid, addr, bytes) {
+        Ok(()) => return Ok(()),
+        Err(vm) => match proc_mem_write(pid, addr, bytes) {
+            Ok(()) => return Ok(()),
+            Err(proc_mem) => match poke_write(pid, addr, bytes) {
+                Ok(()) => return Ok(()),
+                Err(poke) => [("process_vm_writev", vm), ("/proc/pid/mem", proc_mem), ("PTRACE_POKEDATA", poke)],
+            },
+        },
+    };
+    bail!("Failed to write {} bytes at {:#x}: {}", bytes.len(), addr, describe(&errors))
+}
+
+/// Read many ranges with as few syscalls as possible, as (address, length) pairs. Ranges that
+/// can't be read come back as `None` without affecting the others.
+pub fn read_batch(pid: u32, ranges: &[(usize, usize)]) -> Vec<Option<Vec<u8>>> {
+    let mut buffers = ranges.iter().map(|&(_, len)| vec![0u8; len]).collect::<Vec<_>>();
+    let mut results = vec![false; ranges.len()];
+    let mut next = 0;
+    while next < ranges.len() {
+        let end = (next + IOV_MAX).min(ranges.len());
+        let local = buffers[next..end]
+            .iter_mut()
+            .map(|buf| iovec {
+                iov_base: buf.as_mut_ptr() as _,
+                iov_len: buf.len(),
+            })
+            .collect::<Vec<_>>();
+        let remote = ranges[next..end]
+            .iter()
+            .map(|&(addr, len)| iovec {
+                iov_base: addr as _,
+                iov_len: len,
+            })
+            .collect::<Vec<_>>();
+        let transferred =
+            unsafe { process_vm_readv(pid as _, local.as_ptr(), local.len() as _, remote.as_ptr(), remote.len() as _, 0) };
+        // The kernel stops at the first range it can't read completely, so everything before it
+        // was read and the one it stopped at gets a slower retry on its own
+        let mut remaining = transferred.max(0) as usize;
+        while next < end && remaining >= ranges[next].1 {
+            remaining -= ranges[next].1;
+            results[next] = true;
+            next += 1;
+        }
+        if next < end {
+            let (addr, _) = ranges[next];
+            results[next] = read_into(pid, addr, &mut buffers[next]).is_ok();
+            next += 1;
         }
     }
-    Ok(())
+    buffers
+        .into_iter()
+        .zip(results)
+        .map(|(buf, ok)| ok.then_some(buf))
+        .collect()
 }
 
-pub fn read_bytes(pid: u32, addr: usize, len: usize) -> Result<Vec<u8>> {
-    let mut buf = vec![0u8; len];
-    let local = vec![iovec {
+fn describe(errors: &[(&str, io::Error)]) -> String {
+    errors
+        .iter()
+        .map(|(method, e)| format!("{}: {}", method, e))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// A short transfer means part of the range isn't mapped
+fn check_transferred(transferred: isize, len: usize) -> io::Result<()> {
+    match transferred {
+        -1 => Err(io::Error::last_os_error()),
+        n if (n as usize) < len => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("only {} of {} bytes transferred", n, len),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn vm_read(pid: u32, addr: usize, buf: &mut [u8]) -> io::Result<()> {
+    let local = iovec {
         iov_base: buf.as_mut_ptr() as _,
-        iov_len: len,
-    }];
-    let remote = vec![iovec {
+        iov_len: buf.len(),
+    };
+    let remote = iovec {
         iov_base: addr as _,
-        iov_len: len,
-    }];
-    unsafe {
-        if process_vm_readv(pid as _, local.as_ptr(), 1, remote.as_ptr(), 1, 0) == -1 {
-            return Err(anyhow::anyhow!("process_vm_readv failed"));
-        }
-    }
-    Ok(buf)
+        iov_len: buf.len(),
+    };
+    check_transferred(unsafe { process_vm_readv(pid as _, &local, 1, &remote, 1, 0) }, buf.len())
 }
 
-pub fn write_bytes(pid: u32, addr: usize, bytes: &[u8]) -> Result<()> {
-    let local = vec![iovec {
+fn vm_write(pid: u32, addr: usize, bytes: &[u8]) -> io::Result<()> {
+    let local = iovec {
         iov_base: bytes.as_ptr() as _,
         iov_len: bytes.len(),
-    }];
-    let remote = vec![iovec {
+    };
+    let remote = iovec {
         iov_base: addr as _,
         iov_len: bytes.len(),
-    }];
+    };
+    check_transferred(unsafe { process_vm_writev(pid as _, &local, 1, &remote, 1, 0) }, bytes.len())
+}
+
+fn proc_mem_read(pid: u32, addr: usize, buf: &mut [u8]) -> io::Result<()> {
+    File::open(format!("/proc/{}/mem", pid))?.read_exact_at(buf, addr as u64)
+}
+
+fn proc_mem_write(pid: u32, addr: usize, bytes: &[u8]) -> io::Result<()> {
+    File::options()
+        .write(true)
+        .open(format!("/proc/{}/mem", pid))?
+        .write_all_at(bytes, addr as u64)
+}
+
+/// PEEKDATA returns the word itself, so -1 is only an error if errno says so
+fn peek_word(pid: u32, addr: usize) -> io::Result<usize> {
     unsafe {
-        if process_vm_writev(pid as _, local.as_ptr(), 1, remote.as_ptr(), 1, 0) == -1 {
-            return Err(anyhow::anyhow!("process_vm_writev failed"));
+        *libc::__errno_location() = 0;
+        let word = ptrace(PTRACE_PEEKDATA, pid, addr, 0);
+        if word == -1 && *libc::__errno_location() != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(word as usize)
+    }
+}
+
+fn poke_word(pid: u32, addr: usize, word: usize) -> io::Result<()> {
+    if unsafe { ptrace(PTRACE_POKEDATA, pid, addr, word) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn peek_read(pid: u32, addr: usize, buf: &mut [u8]) -> io::Result<()> {
+    let start = addr - addr % WORD_SIZE;
+    for word_addr in (start..addr + buf.len()).step_by(WORD_SIZE) {
+        let word = peek_word(pid, word_addr)?.to_ne_bytes();
+        for (i, byte) in word.iter().enumerate() {
+            if let Some(offset) = (word_addr + i).checked_sub(addr).filter(|offset| *offset < buf.len()) {
+                buf[offset] = *byte;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Words only partly covered by `bytes` are read first so their other bytes are kept
+fn poke_write(pid: u32, addr: usize, bytes: &[u8]) -> io::Result<()> {
+    let start = addr - addr % WORD_SIZE;
+    for word_addr in (start..addr + bytes.len()).step_by(WORD_SIZE) {
+        let partial = word_addr < addr || word_addr + WORD_SIZE > addr + bytes.len();
+        let mut word = if partial { peek_word(pid, word_addr)? } else { 0 }.to_ne_bytes();
+        for (i, byte) in word.iter_mut().enumerate() {
+            if let Some(offset) = (word_addr + i).checked_sub(addr).filter(|offset| *offset < bytes.len()) {
+                *byte = bytes[offset];
+            }
         }
+        poke_word(pid, word_addr, usize::from_ne_bytes(word))?;
     }
     Ok(())
 }
@@ -4,6 +4,7 @@ pub mod signal;
 pub mod syscall;
 pub mod inotify;
 pub mod mem;
+pub mod pattern;
 
 pub mod dbg {
     use anyhow::Result;
@@ -0,0 +1,62 @@
+use anyhow::Result;
+
+/// An IDA-style byte signature, e.g. `48 8B ?? ?? E8`. Wildcards match any byte.
+pub struct Pattern {
+    bytes: Vec<Option<u8>>,
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let bytes = pattern
+            .split_whitespace()
+            .map(|token| match token {
+                "?" | "??" => Ok(None),
+                _ => u8::from_str_radix(token, 16)
+                    .map(Some)
+                    .map_err(|_| anyhow::anyhow!("Invalid pattern byte: {}", token)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if bytes.is_empty() {
+            return Err(anyhow::anyhow!("Pattern is empty"));
+        }
+        if bytes.iter().all(Option::is_none) {
+            return Err(anyhow::anyhow!("Pattern consists only of wildcards"));
+        }
+        Ok(Self { bytes })
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn matches_at(&self, haystack: &[u8], offset: usize) -> bool {
+        haystack.len() >= offset + self.bytes.len()
+            && self
+                .bytes
+                .iter()
+                .zip(&haystack[offset..])
+                .all(|(expected, actual)| expected.is_none_or(|b| b == *actual))
+    }
+
+    /// Offsets of every match within `haystack`.
+    pub fn find_all(&self, haystack: &[u8]) -> Vec<usize> {
+        // Anchor on the first concrete byte so most positions are rejected by a single comparison
+        let (anchor_offset, anchor) = self
+            .bytes
+            .iter()
+            .enumerate()
+            .find_map(|(i, b)| b.map(|b| (i, b)))
+            .unwrap();
+        if haystack.len() < self.bytes.len() {
+            return Vec::new();
+        }
+        let last = haystack.len() - self.bytes.len();
+        haystack[anchor_offset..=last + anchor_offset]
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| **b == anchor)
+            .map(|(offset, _)| offset)
+            .filter(|offset| self.matches_at(haystack, *offset))
+            .collect()
+    }
+}
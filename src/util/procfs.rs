@@ -1,14 +1,41 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{self, BufRead};
 use std::path::Path;
 
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct MemoryMap {
     pub start: u64,
     pub end: u64,
     pub permissions: String,
     pub offset: u64,
     pub device: String,
+    pub inode: u64,
+    pub pathname: Option<String>,
+}
+
+/// Differences between two snapshots of a process' memory maps.
+/// Regions are identified by their start address.
+#[derive(Default, Clone)]
+pub struct MapChanges {
+    pub added: Vec<MemoryMap>,
+    pub removed: Vec<MemoryMap>,
+    /// (old, new) pairs of regions whose size, permissions or backing changed
+    pub changed: Vec<(MemoryMap, MemoryMap)>,
+}
+
+impl MapChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Append the changes that happened after these.
+    pub fn extend(&mut self, later: MapChanges) {
+        self.added.extend(later.added);
+        self.removed.extend(later.removed);
+        self.changed.extend(later.changed);
+    }
 }
 
 impl MemoryMap {
@@ -20,8 +47,8 @@ impl MemoryMap {
 
         for line in reader.lines() {
             let line = line?;
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() < 6 {
+            let parts: Vec<&str> = line.splitn(6, char::is_whitespace).collect();
+            if parts.len() < 5 {
                 continue; // Skip lines that don't have enough parts
             }
 
@@ -35,6 +62,12 @@ impl MemoryMap {
             let permissions = parts[1].to_string();
             let offset = u64::from_str_radix(parts[2], 16).unwrap_or(0);
             let device = parts[3].to_string();
+            let inode = parts[4].trim().parse().unwrap_or(0);
+            // Anonymous mappings have no pathname; the column is padded with spaces otherwise
+            let pathname = parts
+                .get(5)
+                .map(|pathname| pathname.trim_start().to_string())
+                .filter(|pathname| !pathname.is_empty());
 
             memory_maps.push(MemoryMap {
                 start,
@@ -42,11 +75,36 @@ impl MemoryMap {
                 permissions,
                 offset,
                 device,
+                inode,
+                pathname,
             });
         }
 
         Ok(memory_maps)
     }
+
+    pub fn diff(old: &[MemoryMap], new: &[MemoryMap]) -> MapChanges {
+        let old_by_start: HashMap<u64, &MemoryMap> = old.iter().map(|map| (map.start, map)).collect();
+        let new_by_start: HashMap<u64, &MemoryMap> = new.iter().map(|map| (map.start, map)).collect();
+        let mut changes = MapChanges::default();
+        for map in new {
+            match old_by_start.get(&map.start) {
+                Some(old) if *old != map => changes.changed.push(((*old).clone(), map.clone())),
+                Some(_) => {}
+                None => changes.added.push(map.clone()),
+            }
+        }
+        for map in old {
+            if !new_by_start.contains_key(&map.start) {
+                changes.removed.push(map.clone());
+            }
+        }
+        changes
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.start && address < self.end
+    }
 }
 
 pub fn get_tasks(pid: u32) -> Result<Vec<u32>> {
@@ -64,3 +122,25 @@ pub fn get_tasks(pid: u32) -> Result<Vec<u32>> {
 pub fn process_exists(pid: u32) -> bool {
     std::fs::exists(format!("/proc/{}", pid)).unwrap_or(false)
 }
+
+/// Signal masks of a thread, with bit `n - 1` standing for signal `n`.
+pub struct SignalMasks {
+    pub blocked: u64,
+    /// Signals with a handler installed
+    pub caught: u64,
+}
+
+pub fn signal_masks(pid: u32, tid: u32) -> Result<SignalMasks> {
+    let status = std::fs::read_to_string(format!("/proc/{}/task/{}/status", pid, tid))?;
+    let mask = |field: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(field))
+            .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+            .ok_or_else(|| anyhow!("No {} in the status of thread {}", field, tid))
+    };
+    Ok(SignalMasks {
+        blocked: mask("SigBlk:")?,
+        caught: mask("SigCgt:")?,
+    })
+}
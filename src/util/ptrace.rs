@@ -1,7 +1,7 @@
 use anyhow::Result;
 
 use libc::{
-    ptrace, ptrace_syscall_info, user_fpregs_struct, user_regs_struct, PTRACE_ATTACH, PTRACE_CONT, PTRACE_DETACH, PTRACE_GETEVENTMSG, PTRACE_GETFPREGS, PTRACE_GETREGS, PTRACE_GET_SYSCALL_INFO, PTRACE_INTERRUPT, PTRACE_PEEKUSER, PTRACE_POKEUSER, PTRACE_SEIZE, PTRACE_SETFPREGS, PTRACE_SETOPTIONS, PTRACE_SETREGS, PTRACE_SYSCALL
+    ptrace, ptrace_syscall_info, siginfo_t, user_fpregs_struct, user_regs_struct, PTRACE_ATTACH, PTRACE_CONT, PTRACE_DETACH, PTRACE_GETEVENTMSG, PTRACE_GETFPREGS, PTRACE_GETREGS, PTRACE_GETSIGINFO, PTRACE_GET_SYSCALL_INFO, PTRACE_INTERRUPT, PTRACE_PEEKUSER, PTRACE_POKEUSER, PTRACE_SEIZE, PTRACE_SETFPREGS, PTRACE_SETOPTIONS, PTRACE_SETREGS, PTRACE_SINGLESTEP, PTRACE_SYSCALL
 };
 use std::{ffi::c_void, mem::MaybeUninit, ptr};
 
@@ -103,6 +103,16 @@ pub fn get_event_message(pid: u32) -> Result<u64> {
     unsafe { Ok(val.assume_init()) }
 }
 
+/// The signal a thread is stopped with, including the faulting address for SIGSEGV and the like.
+pub fn get_siginfo(pid: u32) -> Result<siginfo_t> {
+    let mut info = MaybeUninit::<siginfo_t>::uninit();
+    let res = unsafe { ptrace(PTRACE_GETSIGINFO, pid, 0, info.as_mut_ptr() as *mut c_void) };
+    if res == -1 {
+        return Err(anyhow::anyhow!("Failed to get signal info"));
+    }
+    unsafe { Ok(info.assume_init()) }
+}
+
 pub fn run_until_syscall(pid: u32, signal: Option<i32>) -> Result<()> {
     let res = unsafe { ptrace(PTRACE_SYSCALL, pid, 0, signal.unwrap_or(0)) };
     if res == -1 {
@@ -111,6 +121,15 @@ pub fn run_until_syscall(pid: u32, signal: Option<i32>) -> Result<()> {
     Ok(())
 }
 
+/// Execute a single instruction in the stopped thread with the given PID.
+pub fn single_step(pid: u32) -> Result<()> {
+    let res = unsafe { ptrace(PTRACE_SINGLESTEP, pid, 0, 0) };
+    if res == -1 {
+        return Err(anyhow::anyhow!("Failed to single-step thread"));
+    }
+    Ok(())
+}
+
 pub(crate) fn get_fp_regs(pid: u32) -> Result<user_fpregs_struct> {
     let mut regs = MaybeUninit::<user_fpregs_struct>::uninit();
     let res = unsafe { ptrace(PTRACE_GETFPREGS, pid, 0, regs.as_mut_ptr() as *mut c_void) };
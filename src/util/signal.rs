@@ -1,4 +1,8 @@
-use std::mem::MaybeUninit;
+use std::{
+    mem::MaybeUninit,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    time::Duration,
+};
 
 use anyhow::Result;
 
@@ -6,17 +10,9 @@ pub enum WaitStatus {
     Stopped(i32),
     Exited(i32),
     Signaled(i32),
-    Running,
 }
 
-pub fn wait(pid: u32) -> Result<WaitStatus> {
-    let mut status = MaybeUninit::<libc::c_int>::uninit();
-    let res = unsafe { libc::waitpid(pid as _, status.as_mut_ptr(), 0) };
-    if res == -1 {
-        return Err(anyhow::anyhow!("Failed to wait for process"));
-    }
-    let status = unsafe { status.assume_init() };
-
+fn decode_status(status: libc::c_int) -> Result<WaitStatus> {
     if libc::WIFSTOPPED(status) {
         Ok(WaitStatus::Stopped(status))
     } else if libc::WIFEXITED(status) {
@@ -28,25 +24,106 @@ pub fn wait(pid: u32) -> Result<WaitStatus> {
     }
 }
 
-pub fn wait_nonblock(pid: u32) -> Result<WaitStatus> {
+pub fn wait(pid: u32) -> Result<WaitStatus> {
     let mut status = MaybeUninit::<libc::c_int>::uninit();
-    let res = unsafe { libc::waitpid(pid as _, status.as_mut_ptr(), libc::WNOHANG) };
+    let res = unsafe { libc::waitpid(pid as _, status.as_mut_ptr(), 0) };
     if res == -1 {
         return Err(anyhow::anyhow!("Failed to wait for process"));
     }
+    decode_status(unsafe { status.assume_init() })
+}
+
+/// Block until thread `pid` is stopped or gone, without reaping the status, which is left for
+/// `waitpid` as usual. Returns whether it is stopped.
+pub fn wait_stopped(pid: u32) -> Result<bool> {
+    let mut info = MaybeUninit::<libc::siginfo_t>::zeroed();
+    let flags = libc::WSTOPPED | libc::WEXITED | libc::WNOWAIT | libc::__WALL;
+    let res = unsafe { libc::waitid(libc::P_PID, pid, info.as_mut_ptr(), flags) };
+    if res == -1 {
+        return Err(anyhow::anyhow!("Failed to wait for thread: {}", std::io::Error::last_os_error()));
+    }
+    Ok(unsafe { info.assume_init() }.si_code == libc::CLD_TRAPPED)
+}
+
+/// The next pending status change of any traced thread, without blocking. `None` once there are
+/// no more, or nothing is traced anymore.
+pub fn wait_any() -> Result<Option<(u32, WaitStatus)>> {
+    let mut status = MaybeUninit::<libc::c_int>::uninit();
+    let res = unsafe { libc::waitpid(-1, status.as_mut_ptr(), libc::WNOHANG | libc::__WALL) };
+    if res == -1 {
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() == Some(libc::ECHILD) {
+            return Ok(None);
+        }
+        return Err(anyhow::anyhow!("Failed to wait for threads: {}", error));
+    }
     if res == 0 {
-        return Ok(WaitStatus::Running);
+        return Ok(None);
+    }
+    Ok(Some((res as u32, decode_status(unsafe { status.assume_init() })?)))
+}
+
+/// Signals delivered through a file descriptor instead of handlers. The signals are blocked, so
+/// this has to be created before spawning any threads, which inherit the signal mask.
+pub struct SignalFd {
+    fd: OwnedFd,
+}
+
+impl SignalFd {
+    pub fn new(signals: &[i32]) -> Result<Self> {
+        let fd = unsafe {
+            let mut mask = MaybeUninit::<libc::sigset_t>::uninit();
+            libc::sigemptyset(mask.as_mut_ptr());
+            for &signal in signals {
+                libc::sigaddset(mask.as_mut_ptr(), signal);
+            }
+            let mask = mask.assume_init();
+            if libc::pthread_sigmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut()) != 0 {
+                return Err(anyhow::anyhow!("Failed to block signals"));
+            }
+            libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC)
+        };
+        if fd == -1 {
+            return Err(anyhow::anyhow!("Failed to create signalfd: {}", std::io::Error::last_os_error()));
+        }
+        Ok(Self { fd: unsafe { OwnedFd::from_raw_fd(fd) } })
     }
 
-    let status = unsafe { status.assume_init() };
+    /// Every signal received since the last call
+    pub fn read(&self) -> Vec<i32> {
+        let mut signals = Vec::new();
+        loop {
+            let mut info = MaybeUninit::<libc::signalfd_siginfo>::uninit();
+            let size = std::mem::size_of::<libc::signalfd_siginfo>();
+            let res = unsafe { libc::read(self.fd.as_raw_fd(), info.as_mut_ptr() as *mut libc::c_void, size) };
+            if res != size as isize {
+                return signals;
+            }
+            signals.push(unsafe { info.assume_init() }.ssi_signo as i32);
+        }
+    }
+}
 
-    if libc::WIFSTOPPED(status) {
-        Ok(WaitStatus::Stopped(status))
-    } else if libc::WIFEXITED(status) {
-        Ok(WaitStatus::Exited(libc::WEXITSTATUS(status)))
-    } else if libc::WIFSIGNALED(status) {
-        Ok(WaitStatus::Signaled(libc::WTERMSIG(status)))
-    } else {
-        Err(anyhow::anyhow!("Unknown wait status"))
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
     }
-}
\ No newline at end of file
+}
+
+/// Block until one of the file descriptors is readable or the timeout, if any, runs out.
+pub fn poll(fds: &[RawFd], timeout: Option<Duration>) -> Result<()> {
+    let mut fds = fds
+        .iter()
+        .map(|&fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 })
+        .collect::<Vec<_>>();
+    // Rounded up, so a deadline less than a millisecond away doesn't turn into a busy loop
+    let timeout = timeout.map_or(-1, |timeout| timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32);
+    let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, timeout) };
+    if res == -1 {
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::Interrupted {
+            return Err(anyhow::anyhow!("Failed to poll: {}", error));
+        }
+    }
+    Ok(())
+}
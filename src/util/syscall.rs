@@ -0,0 +1,50 @@
+use anyhow::Result;
+use log::debug;
+
+use super::{procfs::MemoryMap, signal::WaitStatus};
+
+const SYSCALL_INSTRUCTION: [u8; 2] = [0x0f, 0x05];
+
+/// Find a `syscall` instruction in the executable maps of a process, preferring the vDSO.
+pub fn find_syscall_instruction(pid: u32, maps: &[MemoryMap]) -> Option<u64> {
+    let executable = maps.iter().filter(|map| map.permissions.starts_with("r-x"));
+    let (vdso, rest): (Vec<_>, Vec<_>) = executable.partition(|map| map.pathname.as_deref() == Some("[vdso]"));
+    vdso.into_iter().chain(rest).find_map(|map| {
+        let len = (map.end - map.start).min(1 << 20) as usize;
+        let code = super::mem::read_bytes(pid, map.start as _, len).ok()?;
+        code.windows(2)
+            .position(|window| window == SYSCALL_INSTRUCTION)
+            .map(|offset| map.start + offset as u64)
+    })
+}
+
+/// Make the stopped thread `pid` perform a system call, by single-stepping it over the `syscall` instruction
+/// at `instruction`. The thread's registers are restored afterwards. Returns the raw result (negative errno on failure).
+pub fn inject(pid: u32, instruction: u64, number: u64, args: &[u64]) -> Result<i64> {
+    if args.len() > 6 {
+        return Err(anyhow::anyhow!("Too many syscall arguments"));
+    }
+    let saved = super::ptrace::get_regs(pid)?;
+    let mut regs = saved;
+    regs.rip = instruction;
+    regs.rax = number;
+    // Keep the kernel from restarting a syscall the thread was interrupted in, instead of ours
+    regs.orig_rax = u64::MAX;
+    let registers = [&mut regs.rdi, &mut regs.rsi, &mut regs.rdx, &mut regs.r10, &mut regs.r8, &mut regs.r9];
+    for (register, arg) in registers.into_iter().zip(args) {
+        *register = *arg;
+    }
+    super::ptrace::set_regs(pid, &regs)?;
+
+    let result = super::ptrace::single_step(pid)
+        .and_then(|_| match super::signal::wait(pid)? {
+            WaitStatus::Stopped(_) => Ok(()),
+            _ => Err(anyhow::anyhow!("Thread {} exited while injecting a syscall", pid)),
+        })
+        .and_then(|_| super::ptrace::get_regs(pid));
+    super::ptrace::set_regs(pid, &saved)?;
+
+    let result = result?.rax as i64;
+    debug!("Injected syscall {} into thread {}: {}", number, pid, result);
+    Ok(result)
+}